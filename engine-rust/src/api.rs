@@ -1,44 +1,233 @@
-/// Klikschaak Engine - HTTP API (stdlib, threaded)
+//! Klikschaak Engine - HTTP API (stdlib, threaded)
 
 use std::io::{Read, Write, BufRead, BufReader};
-use std::net::TcpListener;
+use std::net::{Shutdown, TcpListener};
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread;
+use std::time::Instant;
 
-use crate::board::Board;
-use crate::movegen::generate_moves;
-use crate::search::{SearchEngine, compute_zobrist, MAX_DEPTH};
-use crate::evaluate::CHECKMATE_SCORE;
-use crate::types::move_type_name;
+use crate::board::{Board, BoardView};
+use crate::movegen::{
+    generate_moves, checking_moves, perft_divide, is_in_check, is_checkmate, is_stalemate,
+};
+use crate::search::{LmrConfig, SearchEngine, SearchInfo, TranspositionTable, compute_zobrist};
+use crate::evaluate::{evaluate_trace, game_phase, EvalParams};
+use crate::types::{Move, move_type_name, parse_square, square_name, SQ_NONE};
 
-const PORT: u16 = 5005;
+pub const DEFAULT_ADDR: &str = "127.0.0.1:5005";
+const MAX_PERFT_DEPTH: u64 = 6;
+const SHUTDOWN_POLL_MS: u64 = 20;
+// Idle keep-alive connections are closed after this long
+const KEEP_ALIVE_TIMEOUT_MS: u64 = 5000;
+// Once started, a request must arrive in full within this long
+const REQUEST_TIMEOUT_MS: u64 = 10_000;
+const WRITE_TIMEOUT_MS: u64 = 10_000;
+const MAX_HEADER_BYTES: usize = 8 * 1024;
+const MAX_BODY_BYTES: usize = 1024 * 1024;
+const DEFAULT_HASH_MB: u64 = 16;
+const MAX_HASH_MB: u64 = 1024;
+const TT_ENTRY_BYTES: u64 = 16;
+const MAX_CONTEMPT: i64 = 500;
+const MAX_EVAL_WEIGHT: i64 = 1000;
 
-fn parse_request(stream: &mut std::net::TcpStream) -> Option<(String, String, String)> {
-    let mut reader = BufReader::new(stream.try_clone().ok()?);
+// Scalar evaluation weights settable through /options
+const EVAL_WEIGHTS: [&str; 13] = [
+    "passed_pawn_stack_bonus", "passed_pawn_king_distance", "rook_open_file",
+    "rook_half_open_file", "bishop_pair", "doubled_pawn", "isolated_pawn", "castled_king",
+    "uncastled_king", "pawn_shield", "stacked_king", "check_bonus", "tempo",
+];
 
-    let mut request_line = String::new();
-    reader.read_line(&mut request_line).ok()?;
-    let parts: Vec<&str> = request_line.trim().split_whitespace().collect();
+fn eval_weight<'a>(params: &'a mut EvalParams, name: &str) -> Option<&'a mut i32> {
+    Some(match name {
+        "passed_pawn_stack_bonus" => &mut params.passed_pawn_stack_bonus,
+        "passed_pawn_king_distance" => &mut params.passed_pawn_king_distance,
+        "rook_open_file" => &mut params.rook_open_file,
+        "rook_half_open_file" => &mut params.rook_half_open_file,
+        "bishop_pair" => &mut params.bishop_pair,
+        "doubled_pawn" => &mut params.doubled_pawn,
+        "isolated_pawn" => &mut params.isolated_pawn,
+        "castled_king" => &mut params.castled_king,
+        "uncastled_king" => &mut params.uncastled_king,
+        "pawn_shield" => &mut params.pawn_shield,
+        "stacked_king" => &mut params.stacked_king,
+        "check_bonus" => &mut params.check_bonus,
+        "tempo" => &mut params.tempo,
+        _ => return None,
+    })
+}
+
+/// Engine settings, changed at runtime through POST /options.
+#[derive(Clone)]
+struct EngineOptions {
+    hash_mb: u64,
+    contempt: i32,
+    lmr: bool,
+    iid: bool,
+    eval_params: EvalParams,
+}
+
+impl Default for EngineOptions {
+    fn default() -> Self {
+        EngineOptions {
+            hash_mb: DEFAULT_HASH_MB,
+            contempt: 0,
+            lmr: true,
+            iid: true,
+            eval_params: EvalParams::default(),
+        }
+    }
+}
+
+impl EngineOptions {
+    fn to_json(&self) -> serde_json::Value {
+        let mut json = serde_json::json!({
+            "hash_mb": self.hash_mb,
+            "contempt": self.contempt,
+            "lmr": self.lmr,
+            "iid": self.iid,
+        });
+        let mut params = self.eval_params.clone();
+        for name in EVAL_WEIGHTS {
+            json[name] = serde_json::json!(*eval_weight(&mut params, name).unwrap());
+        }
+        json
+    }
+
+    /// Set one option, or say why the value is refused.
+    fn set(&mut self, name: &str, value: &serde_json::Value) -> Result<(), String> {
+        let int_in = |lo: i64, hi: i64| value.as_i64().filter(|v| (lo..=hi).contains(v))
+            .ok_or_else(|| format!("{} must be an integer from {} to {}", name, lo, hi));
+        let flag = || value.as_bool().ok_or_else(|| format!("{} must be true or false", name));
+        match name {
+            "hash_mb" => self.hash_mb = int_in(1, MAX_HASH_MB as i64)? as u64,
+            "contempt" => self.contempt = int_in(-MAX_CONTEMPT, MAX_CONTEMPT)? as i32,
+            "lmr" => self.lmr = flag()?,
+            "iid" => self.iid = flag()?,
+            _ => {
+                let weight = int_in(-MAX_EVAL_WEIGHT, MAX_EVAL_WEIGHT);
+                match eval_weight(&mut self.eval_params, name) {
+                    Some(w) => *w = weight? as i32,
+                    None => return Err(format!("Unknown option: {}", name)),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn tt_entries(&self) -> usize {
+        (self.hash_mb * (1 << 20) / TT_ENTRY_BYTES) as usize
+    }
+}
+
+/// Shared by all connections. The transposition table outlives single
+/// requests, so analysing a game move by move reuses earlier work; entries
+/// from unrelated positions fail the key check and get overwritten.
+struct ServerState {
+    options: RwLock<EngineOptions>,
+    tt: RwLock<Arc<TranspositionTable>>,
+}
+
+impl ServerState {
+    /// A fresh engine on the shared table with the server's settings
+    fn engine(&self) -> SearchEngine {
+        let options = self.options.read().unwrap();
+        let mut engine = SearchEngine::with_tt(Arc::clone(&self.tt.read().unwrap()));
+        engine.set_eval_params(options.eval_params.clone());
+        engine.set_contempt(options.contempt);
+        engine.set_lmr(LmrConfig { enabled: options.lmr, ..LmrConfig::default() });
+        engine.set_iid(options.iid);
+        engine.set_verbosity(0);
+        engine
+    }
+}
+
+/// A parsed HTTP request; `keep_alive` is whether the client wants the
+/// connection kept open for another one afterwards.
+struct Request {
+    method: String,
+    path: String,
+    body: String,
+    keep_alive: bool,
+}
+
+/// Read up to and including the next newline, giving up at `deadline` or
+/// once the line would use up the rest of `budget` bytes. Every read waits
+/// only for the time left, so a client trickling bytes can't stretch it.
+fn read_line_within(reader: &mut BufReader<std::net::TcpStream>, deadline: Instant, budget: &mut usize) -> Option<String> {
+    let mut line = Vec::new();
+    loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() { return None; }
+        reader.get_ref().set_read_timeout(Some(left)).ok()?;
+        let buf = reader.fill_buf().ok()?;
+        if buf.is_empty() { return None; }
+        let (chunk, done) = match buf.iter().position(|&b| b == b'\n') {
+            Some(i) => (&buf[..=i], true),
+            None => (buf, false),
+        };
+        if chunk.len() > *budget { return None; }
+        *budget -= chunk.len();
+        line.extend_from_slice(chunk);
+        let n = chunk.len();
+        reader.consume(n);
+        if done { return Some(String::from_utf8_lossy(&line).into_owned()); }
+    }
+}
+
+/// Read the next request from a connection. None on EOF, a timeout or a
+/// malformed request, all of which end the connection. A client gets
+/// KEEP_ALIVE_TIMEOUT_MS to start a request and REQUEST_TIMEOUT_MS to
+/// finish it; a body over MAX_BODY_BYTES is answered with 413.
+fn parse_request(reader: &mut BufReader<std::net::TcpStream>) -> Option<Request> {
+    let idle = std::time::Duration::from_millis(KEEP_ALIVE_TIMEOUT_MS);
+    reader.get_ref().set_read_timeout(Some(idle)).ok()?;
+    if reader.fill_buf().ok()?.is_empty() { return None; }
+    let deadline = Instant::now() + std::time::Duration::from_millis(REQUEST_TIMEOUT_MS);
+    let mut budget = MAX_HEADER_BYTES;
+
+    let request_line = read_line_within(reader, deadline, &mut budget)?;
+    let parts: Vec<&str> = request_line.split_whitespace().collect();
     if parts.len() < 2 { return None; }
     let method = parts[0].to_string();
     let path = parts[1].to_string();
+    // HTTP/1.1 keeps connections open unless told otherwise, 1.0 closes them
+    let mut keep_alive = parts.get(2) != Some(&"HTTP/1.0");
 
     let mut content_length: usize = 0;
     loop {
-        let mut line = String::new();
-        reader.read_line(&mut line).ok()?;
+        let line = read_line_within(reader, deadline, &mut budget)?;
         if line.trim().is_empty() { break; }
         let lower = line.to_lowercase();
         if lower.starts_with("content-length:") {
             content_length = lower.split(':').nth(1)?.trim().parse().unwrap_or(0);
+        } else if let Some(value) = lower.strip_prefix("connection:") {
+            match value.trim() {
+                "close" => keep_alive = false,
+                "keep-alive" => keep_alive = true,
+                _ => {}
+            }
         }
     }
 
+    if content_length > MAX_BODY_BYTES {
+        let err = serde_json::json!({"error": format!("Request body over {} bytes", MAX_BODY_BYTES)});
+        send_response(reader.get_mut(), 413, &err.to_string());
+        return None;
+    }
     let mut body = vec![0u8; content_length];
-    if content_length > 0 {
-        reader.read_exact(&mut body).ok()?;
+    let mut filled = 0;
+    while filled < content_length {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() { return None; }
+        reader.get_ref().set_read_timeout(Some(left)).ok()?;
+        match reader.read(&mut body[filled..]) {
+            Ok(0) | Err(_) => return None,
+            Ok(n) => filled += n,
+        }
     }
 
-    Some((method, path, String::from_utf8_lossy(&body).to_string()))
+    Some(Request { method, path, body: String::from_utf8_lossy(&body).to_string(), keep_alive })
 }
 
 fn send_response(stream: &mut std::net::TcpStream, status: u16, body: &str) {
@@ -46,6 +235,7 @@ fn send_response(stream: &mut std::net::TcpStream, status: u16, body: &str) {
         200 => "OK",
         400 => "Bad Request",
         404 => "Not Found",
+        413 => "Payload Too Large",
         500 => "Internal Server Error",
         _ => "OK",
     };
@@ -57,7 +247,6 @@ fn send_response(stream: &mut std::net::TcpStream, status: u16, body: &str) {
          Access-Control-Allow-Origin: *\r\n\
          Access-Control-Allow-Methods: GET, POST, OPTIONS\r\n\
          Access-Control-Allow-Headers: Content-Type\r\n\
-         Connection: close\r\n\
          \r\n\
          {}",
         status, status_text, body.len(), body
@@ -67,16 +256,172 @@ fn send_response(stream: &mut std::net::TcpStream, status: u16, body: &str) {
     let _ = stream.flush();
 }
 
+/// Decode `%XX` escapes and `+` (a space) in a query string component.
+/// Malformed escapes are kept as they are.
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes.get(i + 1..i + 3)
+            .and_then(|h| std::str::from_utf8(h).ok())
+            .and_then(|h| u8::from_str_radix(h, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(b)) => { out.push(b); i += 3; }
+            (b'+', _) => { out.push(b' '); i += 1; }
+            (b, _) => { out.push(b); i += 1; }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// A query string as the JSON body the POST handlers take, so GET requests
+/// can share them. Values that parse as integers become numbers.
+fn query_json(query: &str) -> String {
+    let fields: serde_json::Map<String, serde_json::Value> = query.split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            let value = percent_decode(value);
+            let value = match value.parse::<i64>() {
+                Ok(n) => serde_json::json!(n),
+                Err(_) => serde_json::json!(value),
+            };
+            (percent_decode(name), value)
+        })
+        .collect();
+    serde_json::Value::Object(fields).to_string()
+}
+
+/// A request FEN as a board, or why it is malformed or describes an
+/// impossible position.
+fn board_from_fen(fen: &str) -> Result<Board, String> {
+    let board = Board::try_from_fen(fen).map_err(|e| format!("Invalid FEN: {}", e))?;
+    board.validate().map_err(|e| format!("Illegal position: {}", e))?;
+    Ok(board)
+}
+
+/// Parse a request FEN, answering 400 with the reason if it is malformed
+/// or describes an impossible position.
+fn parse_fen(stream: &mut std::net::TcpStream, fen: &str) -> Option<Board> {
+    match board_from_fen(fen) {
+        Ok(board) => Some(board),
+        Err(error) => {
+            let err = serde_json::json!({"error": error});
+            send_response(stream, 400, &err.to_string());
+            None
+        }
+    }
+}
+
+/// Shared shape of the POST endpoints on one position: parse the body and
+/// its `fen` (400 if either is bad), then answer with `respond`'s JSON.
+/// `respond` returns `Err` for a 400 about its own fields, and a panic in
+/// it answers 500. Error bodies are `empty` (the fields clients read, at
+/// their empty values) plus `error`.
+fn with_fen_request<F>(stream: &mut std::net::TcpStream, body: &str, empty: serde_json::Value, respond: F)
+where
+    F: FnOnce(&serde_json::Value, Board) -> Result<serde_json::Value, String>,
+{
+    let error = |message: &str| {
+        let mut err = empty.clone();
+        err["error"] = serde_json::json!(message);
+        err.to_string()
+    };
+
+    let data: serde_json::Value = match serde_json::from_str(body) {
+        Ok(v) => v,
+        Err(e) => {
+            send_response(stream, 400, &error(&e.to_string()));
+            return;
+        }
+    };
+
+    let fen = data.get("fen").and_then(|v| v.as_str()).unwrap_or("");
+    if fen.is_empty() {
+        send_response(stream, 400, &error("Missing fen field"));
+        return;
+    }
+    let board = match board_from_fen(fen) {
+        Ok(board) => board,
+        Err(e) => {
+            send_response(stream, 400, &error(&e));
+            return;
+        }
+    };
+
+    match std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| respond(&data, board))) {
+        Ok(Ok(resp)) => send_response(stream, 200, &resp.to_string()),
+        Ok(Err(e)) => send_response(stream, 400, &error(&e)),
+        Err(_) => send_response(stream, 500, &error("Internal error")),
+    }
+}
+
+fn move_list_json(board: &Board, moves: &[Move]) -> Vec<serde_json::Value> {
+    moves.iter().map(|m| {
+        serde_json::json!({
+            "uci": m.to_uci(),
+            "san": m.to_san(board),
+            "type": move_type_name(m.move_type),
+        })
+    }).collect()
+}
+
+fn info_json(info: &SearchInfo, granularity: u32) -> serde_json::Value {
+    let (score, score_type) = info.score_report_rounded(granularity);
+    serde_json::json!({
+        "score": score,
+        "scoreType": score_type,
+        "pv": info.pv.iter().map(|m| m.to_uci()).collect::<Vec<_>>(),
+        "depth": info.depth,
+        "seldepth": info.seldepth,
+        "nodes": info.nodes,
+        "nps": info.nps,
+        "time_ms": info.time_ms,
+        "timeUsedMs": info.time_ms,
+        "timeBudgetMs": info.time_budget_ms,
+        "gameOver": info.terminal.map(|end| end.as_str()),
+    })
+}
+
 fn handle_health(stream: &mut std::net::TcpStream) {
     send_response(stream, 200, r#"{"status":"ok"}"#);
 }
 
 fn handle_moves(stream: &mut std::net::TcpStream, body: &str) {
+    with_fen_request(stream, body, serde_json::json!({"count": 0, "moves": []}), |_, mut board| {
+        compute_zobrist(&mut board);
+        let moves = generate_moves(&mut board, true, false);
+        let move_list = move_list_json(&board, &moves);
+
+        Ok(serde_json::json!({
+            "count": move_list.len(),
+            "moves": move_list,
+            "error": null,
+        }))
+    });
+}
+
+fn handle_checks(stream: &mut std::net::TcpStream, body: &str) {
+    with_fen_request(stream, body, serde_json::json!({"count": 0, "moves": []}), |_, mut board| {
+        compute_zobrist(&mut board);
+        let moves = checking_moves(&mut board);
+        let move_list = move_list_json(&board, &moves);
+
+        Ok(serde_json::json!({
+            "count": move_list.len(),
+            "moves": move_list,
+            "error": null,
+        }))
+    });
+}
+
+fn handle_eval(stream: &mut std::net::TcpStream, body: &str, state: &ServerState) {
     let parsed: Result<serde_json::Value, _> = serde_json::from_str(body);
     let data = match parsed {
         Ok(v) => v,
         Err(e) => {
-            let err = serde_json::json!({"error": e.to_string(), "count": 0, "moves": []});
+            let err = serde_json::json!({"error": e.to_string()});
             send_response(stream, 400, &err.to_string());
             return;
         }
@@ -88,35 +433,114 @@ fn handle_moves(stream: &mut std::net::TcpStream, body: &str) {
         return;
     }
 
-    let result = std::panic::catch_unwind(|| {
-        let mut board = Board::from_fen(fen);
-        compute_zobrist(&mut board);
-        let moves = generate_moves(&mut board, true, false);
+    let board = match parse_fen(stream, fen) {
+        Some(board) => board,
+        None => return,
+    };
 
-        let move_list: Vec<serde_json::Value> = moves.iter().map(|m| {
-            serde_json::json!({
-                "uci": m.to_uci(),
-                "type": move_type_name(m.move_type),
-            })
-        }).collect();
+    // Optional time budget; with only a time budget, search as deep as it allows
+    let movetime_ms = data.get("movetime_ms").and_then(|v| v.as_u64());
+    let default_depth = if movetime_ms.is_some() { 20 } else { 4 };
+    let depth = data.get("depth").and_then(|v| v.as_u64()).unwrap_or(default_depth) as u32;
+    let depth = depth.clamp(1, 20);
+    // Display rounding for centipawn scores (mate scores stay exact)
+    let granularity = data.get("granularity").and_then(|v| v.as_u64()).unwrap_or(1) as u32;
 
-        serde_json::json!({
-            "count": move_list.len(),
-            "moves": move_list,
-            "error": null,
-        })
-    });
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        let mut board = board;
+        let mut searcher = state.engine();
+        let (best_move, info) = searcher.search(&mut board, depth, movetime_ms);
+
+        let mut resp = info_json(&info, granularity);
+        resp["bestMove"] = serde_json::json!(best_move.map(|m| m.to_uci()));
+        resp["error"] = serde_json::Value::Null;
+        resp
+    }));
 
     match result {
         Ok(resp) => send_response(stream, 200, &resp.to_string()),
         Err(_) => {
-            let err = serde_json::json!({"error": "Internal error", "count": 0, "moves": []});
+            let err = serde_json::json!({"error": "Internal error during evaluation"});
             send_response(stream, 500, &err.to_string());
         }
     }
 }
 
-fn handle_eval(stream: &mut std::net::TcpStream, body: &str) {
+/// Just the move to play, for clients that don't want the analysis:
+/// `{"bestmove": "e2e4", "ponder": "e7e5"}`, where ponder is the reply
+/// the PV expects. Both are null when there is nothing to report.
+fn handle_bestmove(stream: &mut std::net::TcpStream, body: &str, state: &ServerState) {
+    with_fen_request(stream, body, serde_json::json!({}), |data, mut board| {
+        let movetime_ms = data.get("movetime_ms").and_then(|v| v.as_u64());
+        let default_depth = if movetime_ms.is_some() { 20 } else { 4 };
+        let depth = data.get("depth").and_then(|v| v.as_u64()).unwrap_or(default_depth) as u32;
+        let depth = depth.clamp(1, 20);
+
+        let mut searcher = state.engine();
+        let (best_move, info) = searcher.search(&mut board, depth, movetime_ms);
+        let ponder = match info.pv.as_slice() {
+            [first, reply, ..] if Some(*first) == best_move => Some(*reply),
+            _ => None,
+        };
+        Ok(serde_json::json!({
+            "bestmove": best_move.map(|m| m.to_uci()),
+            "ponder": ponder.map(|m| m.to_uci()),
+        }))
+    });
+}
+
+/// Static evaluation split into its terms (see `EvalTrace`), from White's
+/// point of view.
+fn handle_eval_trace(stream: &mut std::net::TcpStream, body: &str, state: &ServerState) {
+    let params = state.options.read().unwrap().eval_params.clone();
+    with_fen_request(stream, body, serde_json::json!({}), |_, board| {
+        let mut resp = serde_json::json!(evaluate_trace(&board, &params));
+        resp["phase"] = serde_json::json!(game_phase(&board));
+        resp["error"] = serde_json::Value::Null;
+        Ok(resp)
+    });
+}
+
+/// Legal moves starting on one square, for click-to-move UIs.
+fn handle_moves_from(stream: &mut std::net::TcpStream, body: &str) {
+    with_fen_request(stream, body, serde_json::json!({"count": 0, "moves": []}), |data, mut board| {
+        let square = data.get("square").and_then(|v| v.as_str()).unwrap_or("");
+        if square.is_empty() {
+            return Err("Missing square field".to_string());
+        }
+        let from = parse_square(square);
+        if from == SQ_NONE {
+            return Err(format!("Invalid square '{}'", square));
+        }
+
+        compute_zobrist(&mut board);
+        let moves: Vec<Move> = generate_moves(&mut board, true, false).into_iter()
+            .filter(|m| m.from_sq == from)
+            .collect();
+        let mut move_list = move_list_json(&board, &moves);
+        for (json, m) in move_list.iter_mut().zip(&moves) {
+            json["to"] = serde_json::json!(square_name(m.to_sq));
+        }
+
+        Ok(serde_json::json!({
+            "count": move_list.len(),
+            "moves": move_list,
+            "error": null,
+        }))
+    });
+}
+
+fn handle_board(stream: &mut std::net::TcpStream, body: &str) {
+    with_fen_request(stream, body, serde_json::json!({}), |_, board| {
+        let mut resp = serde_json::json!(BoardView::from(&board));
+        resp["error"] = serde_json::Value::Null;
+        Ok(resp)
+    });
+}
+
+/// Like /eval, but streams one Server-Sent Event per completed iteration
+/// and a final `bestmove` event. Closing the connection stops the search.
+fn handle_analyze(stream: &mut std::net::TcpStream, body: &str, state: &ServerState) {
     let parsed: Result<serde_json::Value, _> = serde_json::from_str(body);
     let data = match parsed {
         Ok(v) => v,
@@ -133,78 +557,294 @@ fn handle_eval(stream: &mut std::net::TcpStream, body: &str) {
         return;
     }
 
-    let depth = data.get("depth").and_then(|v| v.as_u64()).unwrap_or(4) as u32;
-    let depth = depth.max(1).min(20);
+    let board = match parse_fen(stream, fen) {
+        Some(board) => board,
+        None => return,
+    };
+
+    let movetime_ms = data.get("movetime_ms").and_then(|v| v.as_u64());
+    let depth = data.get("depth").and_then(|v| v.as_u64()).unwrap_or(20) as u32;
+    let depth = depth.clamp(1, 20);
+    let granularity = data.get("granularity").and_then(|v| v.as_u64()).unwrap_or(1) as u32;
+
+    let (mut writer, reader) = match (stream.try_clone(), stream.try_clone()) {
+        (Ok(w), Ok(r)) => (w, r),
+        _ => return,
+    };
+
+    let header = "HTTP/1.1 200 OK\r\n\
+         Content-Type: text/event-stream\r\n\
+         Cache-Control: no-cache\r\n\
+         Access-Control-Allow-Origin: *\r\n\
+         Connection: close\r\n\
+         \r\n";
+    if stream.write_all(header.as_bytes()).and_then(|_| stream.flush()).is_err() { return; }
+
+    let mut searcher = state.engine();
+    let stop = searcher.stop_handle();
+
+    // The client sends nothing after the request, so EOF or an error on
+    // read means it went away. The keep-alive idle timeout would read as
+    // an error too, so the watch waits without one. It starts with the
+    // first update: the search clears the stop flag when it begins, which
+    // would lose a disconnect seen before then.
+    if reader.set_read_timeout(None).is_err() { return; }
+    let mut watch = Some((reader, stop.clone()));
+
+    let writer_stop = stop.clone();
+    searcher.set_info_callback(Box::new(move |info| {
+        if let Some((mut reader, reader_stop)) = watch.take() {
+            thread::spawn(move || {
+                let mut buf = [0u8; 64];
+                while let Ok(n) = reader.read(&mut buf) {
+                    if n == 0 { break; }
+                }
+                reader_stop.store(true, Ordering::Relaxed);
+            });
+        }
+        let frame = format!("event: info\ndata: {}\n\n", info_json(info, granularity));
+        if writer.write_all(frame.as_bytes()).and_then(|_| writer.flush()).is_err() {
+            writer_stop.store(true, Ordering::Relaxed);
+        }
+    }));
 
     let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
-        let mut board = Board::from_fen(fen);
-        let mut searcher = SearchEngine::new();
-        let (best_move, info) = searcher.search(&mut board, depth, None);
-
-        let mut score = info.score;
-        let score_type = if score.abs() >= CHECKMATE_SCORE - MAX_DEPTH as i32 {
-            if score > 0 {
-                score = (CHECKMATE_SCORE - score + 1) / 2;
-            } else {
-                score = -(CHECKMATE_SCORE + score + 1) / 2;
+        let mut board = board;
+        let (best_move, info) = searcher.search(&mut board, depth, movetime_ms);
+
+        let mut resp = info_json(&info, granularity);
+        resp["bestMove"] = serde_json::json!(best_move.map(|m| m.to_uci()));
+        resp["error"] = serde_json::Value::Null;
+        resp
+    }));
+
+    let frame = match result {
+        Ok(resp) => format!("event: bestmove\ndata: {}\n\n", resp),
+        Err(_) => "event: error\ndata: {\"error\":\"Internal error during analysis\"}\n\n".to_string(),
+    };
+    let _ = stream.write_all(frame.as_bytes());
+    let _ = stream.flush();
+    let _ = stream.shutdown(Shutdown::Both);
+}
+
+fn handle_perft(stream: &mut std::net::TcpStream, body: &str) {
+    with_fen_request(stream, body, serde_json::json!({}), |data, mut board| {
+        let depth = data.get("depth").and_then(|v| v.as_u64()).unwrap_or(1);
+        if depth == 0 || depth > MAX_PERFT_DEPTH {
+            return Err(format!("depth must be between 1 and {}", MAX_PERFT_DEPTH));
+        }
+
+        compute_zobrist(&mut board);
+        let divide = perft_divide(&mut board, depth as u32);
+        let nodes: u64 = divide.iter().map(|&(_, n)| n).sum();
+
+        let divide_list: Vec<serde_json::Value> = divide.iter().map(|(m, n)| {
+            serde_json::json!({"uci": m.to_uci(), "nodes": n})
+        }).collect();
+
+        Ok(serde_json::json!({
+            "nodes": nodes,
+            "depth": depth,
+            "divide": divide_list,
+            "error": null,
+        }))
+    });
+}
+
+fn handle_makemove(stream: &mut std::net::TcpStream, body: &str) {
+    with_fen_request(stream, body, serde_json::json!({"legal": false}), |data, mut board| {
+        let uci = data.get("move").and_then(|v| v.as_str()).unwrap_or("");
+        if uci.is_empty() {
+            return Err("Missing move field".to_string());
+        }
+
+        // Optional FENs of the game's earlier positions, oldest first, for
+        // threefold repetition
+        let mut history = Vec::new();
+        if let Some(entries) = data.get("history") {
+            let entries = entries.as_array().ok_or("history must be a list of FENs")?;
+            for entry in entries {
+                let fen = entry.as_str().ok_or("history must be a list of FENs")?;
+                let mut earlier = board_from_fen(fen)?;
+                compute_zobrist(&mut earlier);
+                history.push(earlier.zobrist_hash);
             }
-            "mate"
+        }
+
+        compute_zobrist(&mut board);
+        let mv = match Move::from_uci(&mut board, uci) {
+            Some(mv) => mv,
+            None => return Ok(serde_json::json!({"legal": false, "error": null})),
+        };
+        history.push(board.zobrist_hash);
+        if let Err(e) = board.make_move_checked(mv) {
+            return Ok(serde_json::json!({"legal": false, "error": e.to_string()}));
+        }
+        let repetitions = history.iter().filter(|&&h| h == board.zobrist_hash).count() + 1;
+
+        let game_over = if is_checkmate(&mut board) {
+            Some("checkmate")
+        } else if is_stalemate(&mut board) {
+            Some("stalemate")
         } else {
-            "cp"
+            None
         };
 
-        serde_json::json!({
-            "score": score,
-            "scoreType": score_type,
-            "bestMove": best_move.map(|m| m.to_uci()),
-            "pv": info.pv.iter().map(|m| m.to_uci()).collect::<Vec<_>>(),
-            "depth": info.depth,
-            "nodes": info.nodes,
-            "nps": info.nps,
-            "time_ms": info.time_ms,
+        Ok(serde_json::json!({
+            "fen": board.get_fen(),
+            "legal": true,
+            "check": is_in_check(&board, board.turn),
+            "gameOver": game_over,
+            "threefold": repetitions >= 3,
             "error": null,
-        })
-    }));
+        }))
+    });
+}
 
-    match result {
-        Ok(resp) => send_response(stream, 200, &resp.to_string()),
-        Err(_) => {
-            let err = serde_json::json!({"error": "Internal error during evaluation"});
-            send_response(stream, 500, &err.to_string());
+/// Change engine options: a JSON object of option names to values, all
+/// checked before any is applied. Answers with every option's value, so
+/// an empty object reads them. A new hash size starts an empty table.
+fn handle_options(stream: &mut std::net::TcpStream, body: &str, state: &ServerState) {
+    let changes = match serde_json::from_str::<serde_json::Value>(body) {
+        Ok(serde_json::Value::Object(changes)) => changes,
+        Ok(_) => {
+            send_response(stream, 400, r#"{"error":"options must be a JSON object"}"#);
+            return;
+        }
+        Err(e) => {
+            let err = serde_json::json!({"error": e.to_string()});
+            send_response(stream, 400, &err.to_string());
+            return;
+        }
+    };
+
+    let mut options = state.options.write().unwrap();
+    let mut updated = options.clone();
+    for (name, value) in &changes {
+        if let Err(e) = updated.set(name, value) {
+            let err = serde_json::json!({"error": e});
+            send_response(stream, 400, &err.to_string());
+            return;
         }
     }
+    if updated.hash_mb != options.hash_mb {
+        *state.tt.write().unwrap() = Arc::new(TranspositionTable::new(updated.tt_entries()));
+    }
+    *options = updated;
+    send_response(stream, 200, &options.to_json().to_string());
 }
 
-fn handle_connection(mut stream: std::net::TcpStream) {
-    if let Some((method, path, body)) = parse_request(&mut stream) {
-        match (method.as_str(), path.as_str()) {
-            ("OPTIONS", _) => send_response(&mut stream, 200, ""),
-            ("GET", "/health") => handle_health(&mut stream),
-            ("POST", "/moves") => handle_moves(&mut stream, &body),
-            ("POST", "/eval") => handle_eval(&mut stream, &body),
-            _ => send_response(&mut stream, 404, r#"{"error":"Not found"}"#),
+/// Serve requests on one connection until the client closes it, asks to
+/// close, stays idle for KEEP_ALIVE_TIMEOUT_MS or stops reading responses
+/// for WRITE_TIMEOUT_MS.
+fn handle_connection(mut stream: std::net::TcpStream, state: &ServerState) {
+    let timeout = std::time::Duration::from_millis(WRITE_TIMEOUT_MS);
+    let mut reader = match stream.try_clone() {
+        Ok(clone) if stream.set_write_timeout(Some(timeout)).is_ok() => BufReader::new(clone),
+        _ => return,
+    };
+
+    while let Some(request) = parse_request(&mut reader) {
+        let (stream, body) = (&mut stream, request.body.as_str());
+        let (path, query) = request.path.split_once('?').unwrap_or((&request.path, ""));
+        match (request.method.as_str(), path) {
+            ("OPTIONS", _) => send_response(stream, 200, ""),
+            ("GET", "/health") => handle_health(stream),
+            ("POST", "/moves") => handle_moves(stream, body),
+            ("GET", "/moves") => handle_moves(stream, &query_json(query)),
+            ("POST", "/eval") => handle_eval(stream, body, state),
+            ("GET", "/eval") => handle_eval(stream, &query_json(query), state),
+            ("POST", "/bestmove") => handle_bestmove(stream, body, state),
+            // The event stream runs until the search ends and then closes
+            ("POST", "/analyze") => return handle_analyze(stream, body, state),
+            ("POST", "/eval_trace") => handle_eval_trace(stream, body, state),
+            ("POST", "/board") => handle_board(stream, body),
+            ("POST", "/moves_from") => handle_moves_from(stream, body),
+            ("POST", "/checks") => handle_checks(stream, body),
+            ("POST", "/perft") => handle_perft(stream, body),
+            ("POST", "/makemove") => handle_makemove(stream, body),
+            ("GET", "/options") => handle_options(stream, "{}", state),
+            ("POST", "/options") => handle_options(stream, body, state),
+            _ => send_response(stream, 404, r#"{"error":"Not found"}"#),
         }
+        if !request.keep_alive { return; }
     }
 }
 
-pub fn run_server() {
-    let listener = TcpListener::bind(format!("127.0.0.1:{}", PORT))
-        .expect(&format!("Failed to bind to port {}", PORT));
+/// Where the server listens: `arg`, else $KLIKSCHAAK_ADDR, else
+/// DEFAULT_ADDR. A bare port number means that port on 127.0.0.1.
+pub fn server_addr(arg: Option<&str>) -> String {
+    let addr = arg.map(str::to_string)
+        .or_else(|| std::env::var("KLIKSCHAAK_ADDR").ok())
+        .unwrap_or_else(|| DEFAULT_ADDR.to_string());
+    match addr.parse::<u16>() {
+        Ok(port) => format!("127.0.0.1:{}", port),
+        Err(_) => addr,
+    }
+}
+
+pub fn run_server(addr: &str) {
+    let listener = TcpListener::bind(addr)
+        .unwrap_or_else(|e| panic!("Failed to bind to {}: {}", addr, e));
+    let bound = listener.local_addr().map_or(addr.to_string(), |a| a.to_string());
 
-    println!("Klikschaak Engine API (Rust) running on http://localhost:{}", PORT);
+    println!("Klikschaak Engine API (Rust) running on http://{}", bound);
     println!("  GET  /health  - Health check");
     println!("  POST /moves   - Generate legal moves for a FEN position");
+    println!("  GET  /moves?fen=...  - Same, FEN in the query string");
+    println!("  POST /moves_from - Legal moves starting on one square");
     println!("  POST /eval    - Evaluate position (score, best move, PV)");
+    println!("  GET  /eval?fen=...&depth=...  - Same, fields in the query string");
+    println!("  POST /bestmove - Best move and expected reply only");
+    println!("  POST /analyze - Stream search progress as Server-Sent Events");
+    println!("  POST /eval_trace - Static evaluation broken down by term");
+    println!("  POST /board   - Position as JSON, stacks listed bottom to top");
+    println!("  POST /checks  - Legal moves that give check");
+    println!("  POST /perft   - Perft node count with per-move divide");
+    println!("  POST /makemove - Apply a move, return the new FEN and game state");
+    println!("  GET  /options - Current engine options");
+    println!("  POST /options - Change engine options for later searches");
     println!("Press Ctrl+C to stop.");
 
-    for stream in listener.incoming() {
-        match stream {
-            Ok(stream) => {
+    if let Err(e) = serve(listener, Arc::new(AtomicBool::new(false))) {
+        panic!("Server on {} failed: {}", bound, e);
+    }
+}
+
+/// Serve on `addr` until `shutdown` is set, then stop accepting and
+/// return; requests already being handled finish on their own threads.
+/// The flag is checked every SHUTDOWN_POLL_MS between connections.
+pub fn run_server_with_shutdown(addr: &str, shutdown: Arc<AtomicBool>) -> std::io::Result<()> {
+    serve(TcpListener::bind(addr)?, shutdown)
+}
+
+fn serve(listener: TcpListener, shutdown: Arc<AtomicBool>) -> std::io::Result<()> {
+    listener.set_nonblocking(true)?;
+
+    let options = EngineOptions::default();
+    let state = Arc::new(ServerState {
+        tt: RwLock::new(Arc::new(TranspositionTable::new(options.tt_entries()))),
+        options: RwLock::new(options),
+    });
+
+    while !shutdown.load(Ordering::Relaxed) {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                // Some platforms hand out accepted sockets non-blocking too
+                if let Err(e) = stream.set_nonblocking(false) {
+                    eprintln!("Connection error: {}", e);
+                    continue;
+                }
+                let state = Arc::clone(&state);
                 thread::spawn(move || {
-                    handle_connection(stream);
+                    handle_connection(stream, &state);
                 });
             }
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                thread::sleep(std::time::Duration::from_millis(SHUTDOWN_POLL_MS));
+            }
             Err(e) => eprintln!("Connection error: {}", e),
         }
     }
+    Ok(())
 }
@@ -1,6 +1,15 @@
-use crate::board::Board;
-use crate::search::{find_best_move, compute_zobrist};
-use crate::movegen::generate_moves;
+use crate::board::{Board, STARTING_FEN};
+use crate::search::{find_best_move, compute_zobrist, FutilityConfig, LmrConfig, SearchEngine, MAX_DEPTH};
+use crate::movegen::{generate_moves, perft};
+
+const BENCH_NODES: u64 = 1_000_000;
+
+const MIDDLEGAME_FEN: &str = "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1";
+
+const PERFT_POSITIONS: [(&str, &str, u32); 2] = [
+    ("startpos", STARTING_FEN, 4),
+    ("stacked", "r3k2r/pp1n1ppp/2p1b3/q2(PN)4/3P4/2N2(BQ)2/PPP2PPP/R3K2R w KQkq - 0 1", 4),
+];
 
 pub fn run_bench() {
     println!("=== Klikschaak Rust Engine Benchmark ===\n");
@@ -21,13 +30,101 @@ pub fn run_bench() {
         iters, elapsed.as_secs_f64() * 1000.0,
         iters as f64 / elapsed.as_secs_f64(), total);
 
-    // Search benchmark
+    // Legal move generation from a middlegame position, dominated by the
+    // is_attacked calls of the legality filter
+    let mut board = Board::from_fen(MIDDLEGAME_FEN);
+    compute_zobrist(&mut board);
+    let start = std::time::Instant::now();
+    let mut total = 0usize;
+    for _ in 0..iters {
+        total += generate_moves(&mut board, true, false).len();
+    }
+    let elapsed = start.elapsed();
+    println!("Middlegame move gen: {} iterations in {:.2}ms ({:.0} gen/sec), total moves: {}",
+        iters, elapsed.as_secs_f64() * 1000.0,
+        iters as f64 / elapsed.as_secs_f64(), total);
+
+    // Perft: make/unmake plus legal move generation at every node
+    println!("\nPerft:");
+    for (name, fen, depth) in PERFT_POSITIONS {
+        let mut board = Board::from_fen(fen);
+        compute_zobrist(&mut board);
+        let start = std::time::Instant::now();
+        let nodes = perft(&mut board, depth);
+        let elapsed = start.elapsed();
+        println!("  {} depth {}: {} nodes in {:.0}ms ({:.0} nps)",
+            name, depth, nodes, elapsed.as_secs_f64() * 1000.0,
+            nodes as f64 / elapsed.as_secs_f64());
+    }
+
+    // Search benchmark: time to depth, and the effective branching factor
+    // as the node ratio to the previous depth
     println!("\nSearch from startpos:");
+    println!("  {:>5} {:>9} {:>8} {:>9} {:>5} {:>7}  best", "depth", "nodes", "ms", "nps", "EBF", "re-srch");
+    let mut prev_nodes = None;
     for depth in [4, 5, 6, 7, 8] {
         let mut board = Board::startpos();
         let (best, info) = find_best_move(&mut board, depth, None);
-        println!("  depth {}: {} nodes in {}ms ({} nps), best: {}",
-            depth, info.nodes, info.time_ms, info.nps,
+        let ebf = prev_nodes.map_or("-".to_string(), |prev| format!("{:.2}", info.nodes as f64 / prev as f64));
+        println!("  {:>5} {:>9} {:>8} {:>9} {:>5} {:>7}  {}",
+            depth, info.nodes, info.time_ms, info.nps, ebf, info.aspiration_researches,
             best.map_or("-".to_string(), |m| m.to_uci()));
+        prev_nodes = Some(info.nodes);
+    }
+
+    // Internal iterative deepening on/off
+    on_off_nodes("IID", STARTING_FEN, [6, 7, 8], |engine, iid| engine.set_iid(iid));
+
+    // Transposition table probes and stores on/off
+    on_off_nodes("TT", STARTING_FEN, [6, 7, 8], |engine, use_tt| engine.set_use_tt(use_tt));
+
+    // Late move reductions: logarithmic schedule against none
+    on_off_nodes("LMR", STARTING_FEN, [6, 8, 9], |engine, enabled| {
+        engine.set_lmr(LmrConfig { enabled, ..LmrConfig::default() });
+    });
+
+    // Futility and reverse futility pruning against neither, from a
+    // quiet middlegame
+    on_off_nodes("Futility", MIDDLEGAME_FEN, [5, 6, 7], |engine, enabled| {
+        engine.set_futility(FutilityConfig { enabled, ..FutilityConfig::default() });
+    });
+
+    // Lazy SMP: node throughput and time to depth by thread count
+    println!("\nParallel search from startpos (depth 8):");
+    for threads in [1, 2, 4] {
+        let mut board = Board::startpos();
+        let mut engine = SearchEngine::new();
+        engine.set_info_callback(Box::new(|_| {}));
+        let (best, info) = engine.search_parallel(&mut board, 8, None, threads);
+        println!("  {} threads: {} nodes in {}ms ({} nps), best: {}",
+            threads, info.nodes, info.time_ms, info.nps,
+            best.map_or("-".to_string(), |m| m.to_uci()));
+    }
+
+    // Node-limited search: same work on every machine, so nps is comparable
+    println!("\nFixed-node search from startpos ({} nodes):", BENCH_NODES);
+    let mut board = Board::startpos();
+    let mut engine = SearchEngine::new();
+    let (best, info) = engine.search_with_limits(&mut board, MAX_DEPTH as u32, None, Some(BENCH_NODES));
+    println!("  reached depth {} in {}ms ({} nps), best: {}",
+        info.depth, info.time_ms, info.nps,
+        best.map_or("-".to_string(), |m| m.to_uci()));
+}
+
+/// Print node counts for `fen` at each depth with a search feature switched
+/// on and off by `configure`.
+fn on_off_nodes(label: &str, fen: &str, depths: [u32; 3], configure: impl Fn(&mut SearchEngine, bool)) {
+    println!("\n{} node counts (on / off):", label);
+    for depth in depths {
+        let mut counts = [0u64; 2];
+        for (i, enabled) in [true, false].into_iter().enumerate() {
+            let mut board = Board::from_fen(fen);
+            let mut engine = SearchEngine::new();
+            configure(&mut engine, enabled);
+            engine.set_info_callback(Box::new(|_| {}));
+            let (_, info) = engine.search(&mut board, depth, None);
+            counts[i] = info.nodes;
+        }
+        println!("  depth {}: {} / {} nodes", depth, counts[0], counts[1]);
     }
 }
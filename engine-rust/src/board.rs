@@ -1,9 +1,176 @@
-/// Klikschaak Engine - Board Representation
+//! Klikschaak Engine - Board Representation
 
+use serde::Serialize;
 use crate::types::*;
+use crate::search::{ZOBRIST, compute_zobrist, ep_hash, unmoved_pawns_hash};
+use crate::rng::Rng;
+use crate::movegen::{generate_moves, is_in_check, make_move, unmake_move, UndoInfo};
+use crate::evaluate::pst_value;
 
 pub const STARTING_FEN: &str = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1";
 
+/// Why a FEN was rejected by `Board::try_from_fen`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FenError {
+    TooFewFields(usize),
+    BadRankCount(usize),
+    /// Rank (1-8) that does not describe exactly 8 squares
+    BadRankLength(u8),
+    UnknownPiece(char),
+    /// Unterminated or empty stack, or more than MAX_STACK pieces in it
+    BadStack(String),
+    BadSideToMove(String),
+    BadCastling(String),
+    BadSquare(String),
+    BadCounter(String),
+}
+
+impl std::fmt::Display for FenError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FenError::TooFewFields(n) => write!(f, "FEN has {} fields, expected at least 4", n),
+            FenError::BadRankCount(n) => write!(f, "FEN has {} ranks, expected 8", n),
+            FenError::BadRankLength(r) => write!(f, "rank {} does not have 8 squares", r),
+            FenError::UnknownPiece(c) => write!(f, "unknown piece '{}'", c),
+            FenError::BadStack(s) => write!(f, "bad stack '{}'", s),
+            FenError::BadSideToMove(s) => write!(f, "bad side to move '{}'", s),
+            FenError::BadCastling(s) => write!(f, "bad castling rights '{}'", s),
+            FenError::BadSquare(s) => write!(f, "bad en passant square '{}'", s),
+            FenError::BadCounter(s) => write!(f, "bad move counter '{}'", s),
+        }
+    }
+}
+
+impl std::error::Error for FenError {}
+
+/// Pieces on the board per color and type, stacks included.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PieceCounts {
+    /// Indexed by color, then piece type (index 0 stays 0)
+    pub pieces: [[u8; 7]; 2],
+    /// Pieces of either color standing in a stack, two per stack
+    pub stacked_pieces: u8,
+}
+
+impl PieceCounts {
+    pub fn get(&self, color: u8, pt: u8) -> u8 {
+        self.pieces[color as usize][pt as usize]
+    }
+
+    /// All pieces of `color`, king and pawns included.
+    pub fn total(&self, color: u8) -> u32 {
+        self.pieces[color as usize].iter().map(|&n| n as u32).sum()
+    }
+}
+
+/// A position as plain data for UIs (`POST /board`, `wasm_board`), so they
+/// never have to parse stacks out of a FEN themselves.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BoardView {
+    /// a1, b1, ..., h8; each square lists its piece letters bottom to top
+    pub squares: Vec<Vec<char>>,
+    /// "w" or "b"
+    pub turn: &'static str,
+    pub castling: String,
+    pub ep: Option<String>,
+    /// [white, black], None for a missing king
+    pub king_sq: [Option<String>; 2],
+    pub halfmove: u32,
+    pub fullmove: u32,
+    pub fen: String,
+}
+
+impl From<&Board> for BoardView {
+    fn from(board: &Board) -> Self {
+        let name = |sq: u8| if sq == SQ_NONE { None } else { Some(square_name(sq)) };
+        BoardView {
+            squares: board.squares.iter()
+                .map(|stack| stack.pieces[..stack.count as usize].iter().map(|&p| piece_char(p)).collect())
+                .collect(),
+            turn: if board.turn == WHITE { "w" } else { "b" },
+            castling: board.castling_string(),
+            ep: if board.ep_capturable() { name(board.ep_square) } else { None },
+            king_sq: board.king_sq.map(name),
+            halfmove: board.halfmove_clock as u32,
+            fullmove: board.fullmove as u32,
+            fen: board.get_fen(),
+        }
+    }
+}
+
+/// Why `Board::make_move_checked` refused a move.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IllegalMove {
+    /// Not a move the side to move can make in this position
+    NotAMove(Move),
+    /// Would leave the mover's king attacked
+    LeavesKingInCheck(Move),
+}
+
+impl std::fmt::Display for IllegalMove {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IllegalMove::NotAMove(mv) => write!(f, "{} is not a move in this position", mv.to_uci()),
+            IllegalMove::LeavesKingInCheck(mv) => write!(f, "{} leaves the king in check", mv.to_uci()),
+        }
+    }
+}
+
+impl std::error::Error for IllegalMove {}
+
+fn validate_fen(fen: &str) -> Result<(), FenError> {
+    let parts: Vec<&str> = fen.split_whitespace().collect();
+    if parts.len() < 4 { return Err(FenError::TooFewFields(parts.len())); }
+
+    let ranks: Vec<&str> = parts[0].split('/').collect();
+    if ranks.len() != 8 { return Err(FenError::BadRankCount(ranks.len())); }
+    for (i, rank) in ranks.iter().enumerate() {
+        let mut squares = 0;
+        let mut chars = rank.chars();
+        while let Some(c) = chars.next() {
+            if c == '(' {
+                let mut inner = String::new();
+                let mut closed = false;
+                for c in chars.by_ref() {
+                    if c == ')' { closed = true; break; }
+                    inner.push(c);
+                }
+                if !closed || inner.is_empty() || inner.chars().count() > MAX_STACK {
+                    return Err(FenError::BadStack(inner));
+                }
+                if let Some(bad) = inner.chars().find(|&c| char_to_piece(c) == NO_PIECE) {
+                    return Err(FenError::UnknownPiece(bad));
+                }
+                squares += 1;
+            } else if let Some(n) = c.to_digit(10) {
+                squares += n;
+            } else if char_to_piece(c) != NO_PIECE {
+                squares += 1;
+            } else {
+                return Err(FenError::UnknownPiece(c));
+            }
+        }
+        if squares != 8 { return Err(FenError::BadRankLength(8 - i as u8)); }
+    }
+
+    if parts[1] != "w" && parts[1] != "b" {
+        return Err(FenError::BadSideToMove(parts[1].to_string()));
+    }
+    if parts[2] != "-" && (parts[2].is_empty() || !parts[2].chars().all(|c| "KQkq".contains(c))) {
+        return Err(FenError::BadCastling(parts[2].to_string()));
+    }
+    if parts[3] != "-" && parse_square(parts[3]) == SQ_NONE {
+        return Err(FenError::BadSquare(parts[3].to_string()));
+    }
+    for counter in parts.iter().skip(4).take(2) {
+        if counter.parse::<u16>().is_err() {
+            return Err(FenError::BadCounter(counter.to_string()));
+        }
+    }
+    Ok(())
+}
+
 #[derive(Clone)]
 pub struct Board {
     pub squares: [SquareStack; 64],
@@ -15,6 +182,11 @@ pub struct Board {
     pub king_sq: [u8; 2], // [WHITE, BLACK]
     pub unmoved_pawns: [u8; 2], // bitmask per color
     pub zobrist_hash: u64,
+    pub stacked_count: u8, // number of squares holding two pieces
+    /// Running material per color, at the default piece values
+    pub material: [i32; 2],
+    /// Running (middlegame, endgame) PST sums per color
+    pub pst: [(i32, i32); 2],
 }
 
 impl Board {
@@ -29,6 +201,9 @@ impl Board {
             king_sq: [SQ_E1, SQ_E8],
             unmoved_pawns: [0xFF, 0xFF],
             zobrist_hash: 0,
+            stacked_count: 0,
+            material: [0, 0],
+            pst: [(0, 0), (0, 0)],
         }
     }
 
@@ -38,10 +213,69 @@ impl Board {
         board
     }
 
+    /// Parse a FEN, rejecting anything `set_fen` would have to guess at.
+    pub fn try_from_fen(fen: &str) -> Result<Self, FenError> {
+        validate_fen(fen)?;
+        Ok(Board::from_fen(fen))
+    }
+
     pub fn startpos() -> Self {
         Board::from_fen(STARTING_FEN)
     }
 
+    /// The position after playing `moves` (in any form `Move::from_uci`
+    /// reads) from `start_fen`, which may also be "startpos". On failure,
+    /// the index of the first illegal move and why; a malformed FEN is
+    /// reported at index 0.
+    pub fn from_moves(start_fen: &str, moves: &[&str]) -> Result<Board, (usize, String)> {
+        let mut board = match start_fen {
+            "startpos" => Board::startpos(),
+            fen => Board::try_from_fen(fen).map_err(|e| (0, format!("Invalid FEN: {}", e)))?,
+        };
+        for (i, &uci) in moves.iter().enumerate() {
+            match Move::from_uci(&mut board, uci) {
+                Some(mv) => { make_move(&mut board, mv); }
+                None => return Err((i, format!("illegal move {} in {}", uci, board.get_fen()))),
+            }
+        }
+        Ok(board)
+    }
+
+    /// Shuffled back-rank start position: bishops on opposite colors, king
+    /// between the rooks, black mirroring white. Not Chess960: castling
+    /// only exists from e1/e8 with rooks on a/h, so most arrangements have
+    /// no castling at all, and rights are granted only for the sides where
+    /// that arrangement holds.
+    pub fn shuffle_start(seed: u64) -> Self {
+        let mut rng = Rng::new(seed);
+        let mut rank = [NONE; 8];
+
+        rank[(rng.below(4) * 2) as usize] = BISHOP;
+        rank[(rng.below(4) * 2 + 1) as usize] = BISHOP;
+        for piece in [QUEEN, KNIGHT, KNIGHT] {
+            let free: Vec<usize> = (0..8).filter(|&f| rank[f] == NONE).collect();
+            rank[free[rng.below(free.len() as u64) as usize]] = piece;
+        }
+        // The three remaining files take rook, king, rook in order
+        let mut rest = [ROOK, KING, ROOK].into_iter();
+        for slot in rank.iter_mut().filter(|t| **t == NONE) {
+            *slot = rest.next().unwrap();
+        }
+
+        let back: String = rank.iter().map(|&t| piece_char(make_piece(BLACK, t))).collect();
+        let mut castling = String::new();
+        if rank[4] == KING {
+            if rank[7] == ROOK { castling.push('K'); }
+            if rank[0] == ROOK { castling.push('Q'); }
+        }
+        let black_castling = castling.to_lowercase();
+        castling.push_str(&black_castling);
+        if castling.is_empty() { castling.push('-'); }
+
+        Board::from_fen(&format!("{}/pppppppp/8/8/8/8/PPPPPPPP/{} w {} - 0 1",
+            back, back.to_uppercase(), castling))
+    }
+
     pub fn clear(&mut self) {
         self.squares = [SquareStack::empty(); 64];
         self.turn = WHITE;
@@ -51,7 +285,10 @@ impl Board {
         self.fullmove = 1;
         self.king_sq = [SQ_NONE, SQ_NONE];
         self.unmoved_pawns = [0x00, 0x00];
-        self.zobrist_hash = 0;
+        self.stacked_count = 0;
+        self.material = [0, 0];
+        self.pst = [(0, 0), (0, 0)];
+        compute_zobrist(self);
     }
 
     // Piece access
@@ -76,9 +313,104 @@ impl Board {
     }
 
     pub fn put_piece(&mut self, sq: u8, piece: u8) {
-        self.squares[sq as usize] = SquareStack::single(piece);
-        if piece_type(piece) == KING {
-            self.king_sq[piece_color(piece) as usize] = sq;
+        self.set_square(sq, SquareStack::single(piece));
+    }
+
+    /// Replace the contents of a square, keeping `king_sq`, `unmoved_pawns`
+    /// and the Zobrist hash in sync (for position editors).
+    pub fn set_square(&mut self, sq: u8, stack: SquareStack) {
+        let zob = &*ZOBRIST;
+        let old = self.squares[sq as usize];
+        // The edit can make an en passant capture possible or impossible
+        self.zobrist_hash ^= ep_hash(self);
+        for i in 0..old.count {
+            let piece = old.pieces[i as usize];
+            self.zobrist_hash ^= zob.piece_keys[piece as usize][i as usize][sq as usize];
+            if piece_type(piece) == KING && self.king_sq[piece_color(piece) as usize] == sq {
+                self.king_sq[piece_color(piece) as usize] = SQ_NONE;
+            }
+        }
+
+        self.stacked_count = self.stacked_count + stack.has_stack() as u8 - old.has_stack() as u8;
+        self.update_scores(sq, &old, -1);
+        self.update_scores(sq, &stack, 1);
+        self.squares[sq as usize] = stack;
+        for i in 0..stack.count {
+            let piece = stack.pieces[i as usize];
+            self.zobrist_hash ^= zob.piece_keys[piece as usize][i as usize][sq as usize];
+            if piece_type(piece) == KING {
+                self.king_sq[piece_color(piece) as usize] = sq;
+            }
+        }
+
+        // A pawn standing on its home rank may double-push
+        let old_unmoved = self.unmoved_pawns;
+        let file = square_file(sq);
+        for (color, home_rank, pawn) in [(WHITE, 1, W_PAWN), (BLACK, 6, B_PAWN)] {
+            if square_rank(sq) != home_rank { continue; }
+            if stack.pieces[..stack.count as usize].contains(&pawn) {
+                self.unmoved_pawns[color as usize] |= 1 << file;
+            } else {
+                self.unmoved_pawns[color as usize] &= !(1 << file);
+            }
+        }
+        self.zobrist_hash ^= unmoved_pawns_hash([
+            old_unmoved[0] ^ self.unmoved_pawns[0],
+            old_unmoved[1] ^ self.unmoved_pawns[1],
+        ]);
+        self.zobrist_hash ^= ep_hash(self);
+    }
+
+    /// Add (`sign` 1) or take away (`sign` -1) the material and PST of the
+    /// pieces in `stack` standing on `sq`. The stack index doesn't matter,
+    /// so klik and unklik only change the totals through captures.
+    pub(crate) fn update_scores(&mut self, sq: u8, stack: &SquareStack, sign: i32) {
+        for i in 0..stack.count {
+            let piece = stack.pieces[i as usize];
+            let (pt, color) = (piece_type(piece), piece_color(piece) as usize);
+            let (mg, eg) = pst_value(pt, sq, color == WHITE as usize);
+            self.material[color] += sign * PIECE_VALUES[pt as usize];
+            self.pst[color].0 += sign * mg;
+            self.pst[color].1 += sign * eg;
+        }
+    }
+
+    /// Put `piece` on top of whatever stands on `sq`. Returns false, and
+    /// leaves the square alone, when it already holds two pieces.
+    pub fn add_piece(&mut self, sq: u8, piece: u8) -> bool {
+        let mut stack = self.squares[sq as usize];
+        if stack.has_stack() { return false; }
+        stack.add(piece);
+        self.set_square(sq, stack);
+        true
+    }
+
+    /// Remove and return the piece at `index` of a square (0 is the bottom);
+    /// the piece above it drops down. NO_PIECE if there is no such piece.
+    pub fn remove_piece(&mut self, sq: u8, index: u8) -> u8 {
+        let mut stack = self.squares[sq as usize];
+        let piece = stack.remove_at(index);
+        if piece != NO_PIECE {
+            self.set_square(sq, stack);
+        }
+        piece
+    }
+
+    pub fn clear_square(&mut self, sq: u8) {
+        self.set_square(sq, SquareStack::empty());
+    }
+
+    /// Put `stack` on an empty square while parsing a FEN. Like `set_square`
+    /// but without the hash and unmoved pawns, which `set_fen` sets once
+    /// the whole board is read.
+    fn fill_square(&mut self, sq: u8, stack: SquareStack) {
+        self.stacked_count += stack.has_stack() as u8;
+        self.update_scores(sq, &stack, 1);
+        self.squares[sq as usize] = stack;
+        for &piece in &stack.pieces[..stack.count as usize] {
+            if piece_type(piece) == KING {
+                self.king_sq[piece_color(piece) as usize] = sq;
+            }
         }
     }
 
@@ -102,7 +434,7 @@ impl Board {
                 rank -= 1;
                 file = 0;
             } else if c.is_ascii_digit() {
-                file += (c as u8) - b'0';
+                file = file.saturating_add((c as u8) - b'0');
             } else if c == '(' {
                 // Stack notation: (Np)
                 i += 1;
@@ -114,22 +446,22 @@ impl Board {
                     }
                     i += 1;
                 }
-                let sq = make_square(file, rank as u8);
-                let idx = sq as usize;
-                self.squares[idx] = SquareStack::empty();
-                for &p in &pieces {
-                    self.squares[idx].add(p);
-                    if piece_type(p) == KING {
-                        self.king_sq[piece_color(p) as usize] = sq;
+                if rank >= 0 && file < 8 {
+                    let mut stack = SquareStack::empty();
+                    for &p in &pieces {
+                        stack.add(p);
                     }
+                    self.fill_square(make_square(file, rank as u8), stack);
                 }
-                file += 1;
+                file = file.saturating_add(1);
             } else {
                 let piece = char_to_piece(c);
                 if piece != NO_PIECE {
-                    let sq = make_square(file, rank as u8);
-                    self.put_piece(sq, piece);
-                    file += 1;
+                    // Squares off the board (malformed FEN) are skipped
+                    if rank >= 0 && file < 8 {
+                        self.fill_square(make_square(file, rank as u8), SquareStack::single(piece));
+                    }
+                    file = file.saturating_add(1);
                 }
             }
 
@@ -181,9 +513,35 @@ impl Board {
                 }
             }
         }
+
+        compute_zobrist(self);
     }
 
+    /// Castling rights as in FEN: `KQkq`, a subset, or `-`.
+    pub fn castling_string(&self) -> String {
+        let mut castling = String::new();
+        if self.castling & CR_W_KINGSIDE != 0 { castling.push('K'); }
+        if self.castling & CR_W_QUEENSIDE != 0 { castling.push('Q'); }
+        if self.castling & CR_B_KINGSIDE != 0 { castling.push('k'); }
+        if self.castling & CR_B_QUEENSIDE != 0 { castling.push('q'); }
+        if castling.is_empty() { castling.push('-'); }
+        castling
+    }
+
+    /// FEN of the position. The en passant square is only written when a
+    /// pawn of the side to move stands ready to take, as strict FEN readers
+    /// expect; `get_fen_raw` always writes it.
     pub fn get_fen(&self) -> String {
+        self.fen(self.ep_capturable())
+    }
+
+    /// FEN with the en passant square written whenever it is set, so that
+    /// parsing it gives back exactly this board.
+    pub fn get_fen_raw(&self) -> String {
+        self.fen(self.ep_square != SQ_NONE)
+    }
+
+    fn fen(&self, with_ep: bool) -> String {
         let mut fen = String::new();
 
         // Board
@@ -225,17 +583,11 @@ impl Board {
 
         // Castling
         fen.push(' ');
-        let mut castling_str = String::new();
-        if self.castling & CR_W_KINGSIDE != 0 { castling_str.push('K'); }
-        if self.castling & CR_W_QUEENSIDE != 0 { castling_str.push('Q'); }
-        if self.castling & CR_B_KINGSIDE != 0 { castling_str.push('k'); }
-        if self.castling & CR_B_QUEENSIDE != 0 { castling_str.push('q'); }
-        if castling_str.is_empty() { castling_str.push('-'); }
-        fen.push_str(&castling_str);
+        fen.push_str(&self.castling_string());
 
         // En passant
         fen.push(' ');
-        if self.ep_square != SQ_NONE {
+        if with_ep {
             fen.push_str(&square_name(self.ep_square));
         } else {
             fen.push('-');
@@ -282,6 +634,165 @@ impl Board {
 
         lines.join("\n")
     }
+
+    /// Check that a parsed position could occur in a game: one king per
+    /// color and never in a stack, no pawns on the first or last rank,
+    /// stacks of one color, and the side that just moved not left in
+    /// check. Stack size needs no check: `SquareStack` holds at most
+    /// MAX_STACK pieces, and `try_from_fen` rejects larger ones.
+    pub fn validate(&self) -> Result<(), String> {
+        let mut kings = [0; 2];
+        for sq in 0..64u8 {
+            let stack = &self.squares[sq as usize];
+            let pieces = &stack.pieces[..stack.count as usize];
+            if pieces.iter().any(|&p| piece_color(p) != piece_color(pieces[0])) {
+                return Err(format!("stack on {} mixes colors", square_name(sq)));
+            }
+            for &piece in pieces {
+                match piece_type(piece) {
+                    KING => {
+                        kings[piece_color(piece) as usize] += 1;
+                        if stack.count > 1 {
+                            return Err(format!("king in a stack on {}", square_name(sq)));
+                        }
+                    }
+                    PAWN if square_rank(sq) == 0 || square_rank(sq) == 7 => {
+                        return Err(format!("pawn on back rank at {}", square_name(sq)));
+                    }
+                    _ => {}
+                }
+            }
+        }
+        for (color, name) in [(WHITE, "white"), (BLACK, "black")] {
+            if kings[color as usize] != 1 {
+                return Err(format!("{} has {} kings, expected 1", name, kings[color as usize]));
+            }
+        }
+        if is_in_check(self, opposite_color(self.turn)) {
+            return Err("side not to move is in check".to_string());
+        }
+        Ok(())
+    }
+
+    /// Whether the side to move has a pawn (in any stack slot) that could
+    /// capture on the en passant square. Pins are not considered.
+    pub fn ep_capturable(&self) -> bool {
+        if self.ep_square == SQ_NONE { return false; }
+        let file = square_file(self.ep_square) as i8;
+        let (rank, pawn) = if self.turn == WHITE {
+            (square_rank(self.ep_square) as i8 - 1, W_PAWN)
+        } else {
+            (square_rank(self.ep_square) as i8 + 1, B_PAWN)
+        };
+        if !(0..8).contains(&rank) { return false; }
+        [file - 1, file + 1].iter().any(|&f| {
+            (0..8).contains(&f) && {
+                let stack = &self.squares[make_square(f as u8, rank as u8) as usize];
+                stack.pieces[..stack.count as usize].contains(&pawn)
+            }
+        })
+    }
+
+    pub fn piece_counts(&self) -> PieceCounts {
+        let mut counts = PieceCounts::default();
+        for stack in &self.squares {
+            for &piece in &stack.pieces[..stack.count as usize] {
+                counts.pieces[piece_color(piece) as usize][piece_type(piece) as usize] += 1;
+            }
+            if stack.has_stack() { counts.stacked_pieces += stack.count; }
+        }
+        counts
+    }
+
+    /// The same position with the colors swapped: the board is mirrored
+    /// top to bottom, every piece changes color in place (a stack keeps its
+    /// bottom piece at the bottom), castling rights, unmoved pawns and the
+    /// en passant square go to the other side, and the other side moves.
+    /// Kings never stack, so a king flips on its own; a stack next to it
+    /// flips like any other.
+    pub fn flip(&self) -> Board {
+        let mut flipped = Board::new();
+        flipped.clear();
+        for sq in 0..64u8 {
+            let mut stack = self.squares[sq as usize];
+            for i in 0..stack.count as usize {
+                let piece = stack.pieces[i];
+                stack.pieces[i] = make_piece(opposite_color(piece_color(piece)), piece_type(piece));
+            }
+            flipped.set_square(sq ^ 56, stack);
+        }
+        flipped.turn = opposite_color(self.turn);
+        flipped.castling = ((self.castling & CR_WHITE) << 2) | ((self.castling & CR_BLACK) >> 2);
+        flipped.ep_square = if self.ep_square == SQ_NONE { SQ_NONE } else { self.ep_square ^ 56 };
+        flipped.unmoved_pawns = [self.unmoved_pawns[BLACK as usize], self.unmoved_pawns[WHITE as usize]];
+        flipped.halfmove_clock = self.halfmove_clock;
+        flipped.fullmove = self.fullmove;
+        compute_zobrist(&mut flipped);
+        flipped
+    }
+
+    /// Key for repetition detection: placement, side to move, castling,
+    /// unmoved pawns, and the en passant square only when a capture there
+    /// is possible; move counters are left out. This is the Zobrist hash,
+    /// so it is valid once `compute_zobrist` has run and `make_move` keeps
+    /// it up to date.
+    pub fn position_key(&self) -> u64 {
+        self.zobrist_hash
+    }
+
+    /// `make_move` for untrusted input: the move must be one the side to
+    /// move can generate, and is taken back again if it leaves the mover in
+    /// check. On success the move stays on the board.
+    pub fn make_move_checked(&mut self, mv: Move) -> Result<UndoInfo, IllegalMove> {
+        if !generate_moves(self, false, false).contains(&mv) {
+            return Err(IllegalMove::NotAMove(mv));
+        }
+        let undo = make_move(self, mv);
+        if is_in_check(self, opposite_color(self.turn)) {
+            unmake_move(self, mv, &undo);
+            return Err(IllegalMove::LeavesKingInCheck(mv));
+        }
+        Ok(undo)
+    }
+}
+
+/// Exact position equality: placement, side to move, castling, unmoved
+/// pawns and en passant (only when it can be captured). Move counters are
+/// ignored. Unlike comparing Zobrist hashes this cannot collide.
+pub fn positions_equal_ignoring_clocks(a: &Board, b: &Board) -> bool {
+    let ep = |board: &Board| if board.ep_capturable() { board.ep_square } else { SQ_NONE };
+    a.squares == b.squares
+        && a.turn == b.turn
+        && a.castling == b.castling
+        && a.unmoved_pawns == b.unmoved_pawns
+        && ep(a) == ep(b)
+}
+
+/// Equal when the positions match as for repetition and the move
+/// counters match too.
+impl PartialEq for Board {
+    fn eq(&self, other: &Self) -> bool {
+        positions_equal_ignoring_clocks(self, other)
+            && self.halfmove_clock == other.halfmove_clock
+            && self.fullmove == other.fullmove
+    }
+}
+
+impl Eq for Board {}
+
+/// Hashes `zobrist_hash`, so that has to be current. FEN parsing computes
+/// it and moves and square edits keep it up to date; after assigning
+/// fields directly, call `compute_zobrist`.
+impl std::hash::Hash for Board {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.zobrist_hash.hash(state);
+    }
+}
+
+impl Default for Board {
+    fn default() -> Self {
+        Board::new()
+    }
 }
 
 impl std::fmt::Display for Board {
@@ -0,0 +1,182 @@
+//! Klikschaak Engine - Opening book
+//!
+//! Polyglot-style: a file of 16-byte big-endian entries sorted by key,
+//! `key: u64, move: u32, weight: u16, reserved: u16`. Keys are this
+//! engine's Zobrist hashes and moves are `PackedMove`s, so klik, unklik
+//! and combined moves round-trip. Books are made with `book <file>`.
+
+use std::collections::HashMap;
+
+use crate::board::Board;
+use crate::movegen::{generate_moves, make_move};
+use crate::rng::Rng;
+use crate::search::{SearchEngine, compute_zobrist};
+use crate::types::*;
+
+const ENTRY_BYTES: usize = 16;
+
+const DEFAULT_GAMES: u32 = 100;
+const DEFAULT_DEPTH: u32 = 4;
+const DEFAULT_PLIES: u32 = 8;
+// Moves within this many centipawns of the best one are book candidates
+const BOOK_MARGIN: i32 = 30;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct BookEntry {
+    pub key: u64,
+    pub mv: Move,
+    pub weight: u16,
+}
+
+pub struct OpeningBook {
+    entries: Vec<BookEntry>, // sorted by key, then move
+    rng: Rng,
+}
+
+impl OpeningBook {
+    pub fn new(mut entries: Vec<BookEntry>) -> Self {
+        entries.sort_by_key(|e| (e.key, e.mv.key()));
+        OpeningBook { entries, rng: Rng::new(0) }
+    }
+
+    pub fn load(path: impl AsRef<std::path::Path>) -> std::io::Result<Self> {
+        Self::from_bytes(&std::fs::read(path)?)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> std::io::Result<Self> {
+        if !bytes.len().is_multiple_of(ENTRY_BYTES) {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData,
+                format!("book size {} is not a multiple of {}", bytes.len(), ENTRY_BYTES)));
+        }
+        let entries = bytes.chunks_exact(ENTRY_BYTES).map(|e| BookEntry {
+            key: u64::from_be_bytes(e[0..8].try_into().unwrap()),
+            mv: PackedMove(u32::from_be_bytes(e[8..12].try_into().unwrap())).unpack(),
+            weight: u16::from_be_bytes(e[12..14].try_into().unwrap()),
+        }).collect();
+        Ok(Self::new(entries))
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.entries.len() * ENTRY_BYTES);
+        for e in &self.entries {
+            bytes.extend_from_slice(&e.key.to_be_bytes());
+            bytes.extend_from_slice(&PackedMove::pack(e.mv).0.to_be_bytes());
+            bytes.extend_from_slice(&e.weight.to_be_bytes());
+            bytes.extend_from_slice(&[0, 0]);
+        }
+        bytes
+    }
+
+    pub fn save(&self, path: impl AsRef<std::path::Path>) -> std::io::Result<()> {
+        std::fs::write(path, self.to_bytes())
+    }
+
+    /// Reseed the weighted choice in `probe`.
+    pub fn set_seed(&mut self, seed: u64) {
+        self.rng = Rng::new(seed);
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Book entries for `board` that are legal there (a hash collision can
+    /// bring in moves from another position).
+    pub fn moves(&self, board: &Board) -> Vec<BookEntry> {
+        let mut board = board.clone();
+        compute_zobrist(&mut board);
+        let key = board.zobrist_hash;
+        let start = self.entries.partition_point(|e| e.key < key);
+        let end = start + self.entries[start..].partition_point(|e| e.key == key);
+        if start == end { return Vec::new(); }
+
+        let legal = generate_moves(&mut board, true, false);
+        self.entries[start..end].iter().copied()
+            .filter(|e| e.weight > 0 && legal.contains(&e.mv))
+            .collect()
+    }
+
+    /// A book move for `board`, picked at random in proportion to weight.
+    pub fn probe(&mut self, board: &Board) -> Option<Move> {
+        let moves = self.moves(board);
+        let total: u64 = moves.iter().map(|e| e.weight as u64).sum();
+        if total == 0 { return None; }
+        let mut pick = self.rng.below(total);
+        for e in &moves {
+            if pick < e.weight as u64 { return Some(e.mv); }
+            pick -= e.weight as u64;
+        }
+        None
+    }
+}
+
+/// Root moves of `board` with their scores for the side to move, from a
+/// `depth - 1` search after each.
+fn scored_moves(engine: &mut SearchEngine, board: &mut Board, depth: u32) -> Vec<(Move, i32)> {
+    let mut moves = generate_moves(board, true, false);
+    moves.sort_by_key(|m| m.key());
+    moves.into_iter().map(|mv| {
+        let mut child = board.clone();
+        make_move(&mut child, mv);
+        let (_, info) = engine.search(&mut child, depth.max(2) - 1, None);
+        (mv, if board.turn == WHITE { info.score } else { -info.score })
+    }).collect()
+}
+
+/// Book from `games` games of `plies` plies each from the start position.
+/// Every ply picks at random among the moves scoring within BOOK_MARGIN of
+/// the best one; a move's weight is how often it was picked.
+pub fn build_book(games: u32, depth: u32, plies: u32, seed: u64) -> OpeningBook {
+    let mut rng = Rng::new(seed);
+    let mut engine = SearchEngine::new();
+    engine.set_info_callback(Box::new(|_| {}));
+    let mut scored: HashMap<u64, Vec<(Move, i32)>> = HashMap::new();
+    let mut counts: HashMap<(u64, u32), u32> = HashMap::new();
+
+    for _ in 0..games {
+        let mut board = Board::startpos();
+        compute_zobrist(&mut board);
+        for _ in 0..plies {
+            let key = board.zobrist_hash;
+            let candidates = scored.entry(key)
+                .or_insert_with(|| scored_moves(&mut engine, &mut board, depth));
+            let Some(best) = candidates.iter().map(|&(_, s)| s).max() else { break };
+            let good: Vec<Move> = candidates.iter()
+                .filter(|&&(_, s)| s >= best - BOOK_MARGIN)
+                .map(|&(mv, _)| mv)
+                .collect();
+            let mv = good[rng.below(good.len() as u64) as usize];
+            *counts.entry((key, PackedMove::pack(mv).0)).or_insert(0) += 1;
+            make_move(&mut board, mv);
+        }
+    }
+
+    OpeningBook::new(counts.into_iter().map(|((key, packed), n)| BookEntry {
+        key,
+        mv: PackedMove(packed).unpack(),
+        weight: n.min(u16::MAX as u32) as u16,
+    }).collect())
+}
+
+/// Usage: `book <file> [games] [depth] [plies]`.
+pub fn run_build_book(args: &[String]) {
+    let Some(path) = args.first() else {
+        eprintln!("usage: book <file> [games] [depth] [plies]");
+        std::process::exit(1);
+    };
+    let games = args.get(1).and_then(|v| v.parse().ok()).unwrap_or(DEFAULT_GAMES);
+    let depth = args.get(2).and_then(|v| v.parse().ok()).unwrap_or(DEFAULT_DEPTH);
+    let plies = args.get(3).and_then(|v| v.parse().ok()).unwrap_or(DEFAULT_PLIES);
+
+    println!("=== Klikschaak opening book: {} games of {} plies at depth {} ===", games, plies, depth);
+    let book = build_book(games, depth, plies, 0);
+    if let Err(e) = book.save(path) {
+        eprintln!("Failed to write {}: {}", path, e);
+        std::process::exit(1);
+    }
+    println!("Wrote {} entries to {}", book.len(), path);
+}
@@ -1,10 +1,12 @@
-/// Klikschaak Engine - Position Evaluation
+//! Klikschaak Engine - Position Evaluation
 
+use serde::Serialize;
 use crate::types::*;
-use crate::board::Board;
-use crate::movegen::is_in_check;
+use crate::board::{Board, PieceCounts};
+use crate::movegen::{is_in_check, pop_square};
 
-// Piece-square tables (from White's perspective, a1=index 0)
+// Piece-square tables (from White's perspective, printed with rank 8 on the
+// first line, so a8 = index 0; see pst_index)
 const PAWN_TABLE: [i32; 64] = [
       0,   0,   0,   0,   0,   0,   0,   0,
      50,  50,  50,  50,  50,  50,  50,  50,
@@ -82,40 +84,211 @@ const KING_ENDGAME_TABLE: [i32; 64] = [
     -50, -30, -30, -30, -30, -30, -30, -50,
 ];
 
-fn pst_value(pt: u8, sq: u8) -> i32 {
+// Endgame tables: pawns are worth more the further they are, pieces and
+// the king belong in the centre
+const PAWN_ENDGAME_TABLE: [i32; 64] = [
+      0,   0,   0,   0,   0,   0,   0,   0,
+     80,  80,  80,  80,  80,  80,  80,  80,
+     50,  50,  50,  50,  50,  50,  50,  50,
+     30,  30,  30,  30,  30,  30,  30,  30,
+     15,  15,  15,  15,  15,  15,  15,  15,
+      5,   5,   5,   5,   5,   5,   5,   5,
+      0,   0,   0,   0,   0,   0,   0,   0,
+      0,   0,   0,   0,   0,   0,   0,   0,
+];
+
+const KNIGHT_ENDGAME_TABLE: [i32; 64] = [
+    -50, -40, -30, -30, -30, -30, -40, -50,
+    -40, -20, -10,  -5,  -5, -10, -20, -40,
+    -30, -10,  10,  15,  15,  10, -10, -30,
+    -30,  -5,  15,  20,  20,  15,  -5, -30,
+    -30,  -5,  15,  20,  20,  15,  -5, -30,
+    -30, -10,  10,  15,  15,  10, -10, -30,
+    -40, -20, -10,  -5,  -5, -10, -20, -40,
+    -50, -40, -30, -30, -30, -30, -40, -50,
+];
+
+const BISHOP_ENDGAME_TABLE: [i32; 64] = [
+    -15, -10, -10, -10, -10, -10, -10, -15,
+    -10,   0,   0,   0,   0,   0,   0, -10,
+    -10,   0,   5,   5,   5,   5,   0, -10,
+    -10,   0,   5,  10,  10,   5,   0, -10,
+    -10,   0,   5,  10,  10,   5,   0, -10,
+    -10,   0,   5,   5,   5,   5,   0, -10,
+    -10,   0,   0,   0,   0,   0,   0, -10,
+    -15, -10, -10, -10, -10, -10, -10, -15,
+];
+
+const ROOK_ENDGAME_TABLE: [i32; 64] = [
+      5,   5,   5,   5,   5,   5,   5,   5,
+     15,  15,  15,  15,  15,  15,  15,  15,
+      0,   0,   0,   0,   0,   0,   0,   0,
+      0,   0,   0,   0,   0,   0,   0,   0,
+      0,   0,   0,   0,   0,   0,   0,   0,
+      0,   0,   0,   0,   0,   0,   0,   0,
+      0,   0,   0,   0,   0,   0,   0,   0,
+     -5,  -5,  -5,  -5,  -5,  -5,  -5,  -5,
+];
+
+const QUEEN_ENDGAME_TABLE: [i32; 64] = [
+    -20, -10, -10,  -5,  -5, -10, -10, -20,
+    -10,   0,   5,   5,   5,   5,   0, -10,
+    -10,   5,  10,  10,  10,  10,   5, -10,
+     -5,   5,  10,  15,  15,  10,   5,  -5,
+     -5,   5,  10,  15,  15,  10,   5,  -5,
+    -10,   5,  10,  10,  10,  10,   5, -10,
+    -10,   0,   5,   5,   5,   5,   0, -10,
+    -20, -10, -10,  -5,  -5, -10, -10, -20,
+];
+
+/// Middlegame and endgame PST values of a piece type on `sq`, from the
+/// piece owner's point of view.
+pub(crate) fn pst_value(pt: u8, sq: u8, is_white: bool) -> (i32, i32) {
+    let i = pst_index(sq, is_white);
     match pt {
-        PAWN => PAWN_TABLE[sq as usize],
-        KNIGHT => KNIGHT_PST[sq as usize],
-        BISHOP => BISHOP_TABLE[sq as usize],
-        ROOK => ROOK_TABLE[sq as usize],
-        QUEEN => QUEEN_TABLE[sq as usize],
-        _ => 0,
+        PAWN => (PAWN_TABLE[i], PAWN_ENDGAME_TABLE[i]),
+        KNIGHT => (KNIGHT_PST[i], KNIGHT_ENDGAME_TABLE[i]),
+        BISHOP => (BISHOP_TABLE[i], BISHOP_ENDGAME_TABLE[i]),
+        ROOK => (ROOK_TABLE[i], ROOK_ENDGAME_TABLE[i]),
+        QUEEN => (QUEEN_TABLE[i], QUEEN_ENDGAME_TABLE[i]),
+        KING => (KING_MIDDLEGAME_TABLE[i], KING_ENDGAME_TABLE[i]),
+        _ => (0, 0),
     }
 }
 
+/// Table index of `sq`. The tables are printed rank 8 first, so White
+/// reads them mirrored and Black reads them as they are.
+#[inline]
+fn pst_index(sq: u8, is_white: bool) -> usize {
+    if is_white { mirror_square(sq) as usize } else { sq as usize }
+}
+
 #[inline]
 fn mirror_square(sq: u8) -> u8 {
     sq ^ 56
 }
 
-// Passed pawn bonus by rank advancement
-const PASSED_PAWN_BONUS: [i32; 7] = [0, 10, 15, 25, 45, 75, 120];
+/// Game phase weight of each piece type; a full set of pieces adds up to
+/// MAX_PHASE.
+const PHASE_WEIGHTS: [i32; 7] = [0, 0, 1, 1, 2, 4, 0];
+pub const MAX_PHASE: i32 = 24;
+
+/// Game phase from the material on the board: MAX_PHASE in the opening,
+/// 0 with only kings and pawns left. Promotions can't push it past
+/// MAX_PHASE.
+pub fn game_phase(board: &Board) -> i32 {
+    phase_of(&board.piece_counts())
+}
+
+fn phase_of(counts: &PieceCounts) -> i32 {
+    let mut phase = 0;
+    for color in [WHITE, BLACK] {
+        for pt in PAWN..=KING {
+            phase += PHASE_WEIGHTS[pt as usize] * counts.get(color, pt) as i32;
+        }
+    }
+    phase.min(MAX_PHASE)
+}
+
+/// Blend a middlegame and an endgame score by phase
+#[inline]
+fn taper(mg: i32, eg: i32, phase: i32) -> i32 {
+    (mg * phase + eg * (MAX_PHASE - phase)) / MAX_PHASE
+}
+
+/// Evaluation weights. `Default` holds the engine's tuned values; swap in
+/// another set (e.g. from a tuner) via `SearchEngine::set_eval_params`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct EvalParams {
+    /// Material per piece type, indexed like `PIECE_VALUES`
+    pub piece_values: [i32; 7],
+    /// Passed pawn bonus by rank advancement
+    pub passed_pawn_bonus: [i32; 7],
+    /// Extra for a passed pawn that is part of a stack
+    pub passed_pawn_stack_bonus: i32,
+    /// Endgame bonus per square the enemy king is further than the own
+    /// king from the square in front of a passed pawn
+    pub passed_pawn_king_distance: i32,
+    /// Rook on a file without pawns / without own pawns
+    pub rook_open_file: i32,
+    pub rook_half_open_file: i32,
+    /// Two or more bishops, stacked ones included
+    pub bishop_pair: i32,
+    /// Per extra pawn on a file (a stack of two pawns counts as doubled)
+    pub doubled_pawn: i32,
+    /// Per pawn without own pawns on the neighbouring files
+    pub isolated_pawn: i32,
+    /// King on g1/c1 (g8/c8), and the penalty for staying on e1 (e8)
+    pub castled_king: i32,
+    pub uncastled_king: i32,
+    /// Per own pawn in front of the king
+    pub pawn_shield: i32,
+    /// King sharing a square with another piece
+    pub stacked_king: i32,
+    /// Giving check
+    pub check_bonus: i32,
+    /// For the side to move
+    pub tempo: i32,
+}
+
+impl Default for EvalParams {
+    fn default() -> Self {
+        EvalParams {
+            piece_values: PIECE_VALUES,
+            passed_pawn_bonus: [0, 10, 15, 25, 45, 75, 120],
+            passed_pawn_stack_bonus: 15,
+            passed_pawn_king_distance: 5,
+            rook_open_file: 25,
+            rook_half_open_file: 12,
+            bishop_pair: 30,
+            doubled_pawn: 15,
+            isolated_pawn: 12,
+            castled_king: 30,
+            uncastled_king: 20,
+            pawn_shield: 10,
+            stacked_king: 40,
+            check_bonus: 50,
+            tempo: 10,
+        }
+    }
+}
 
 pub const CHECKMATE_SCORE: i32 = 100000;
 pub const DRAW_SCORE: i32 = 0;
 
-pub fn evaluate(board: &Board) -> i32 {
-    let mut score: i32 = 0;
+/// Evaluation from White's point of view, split into its terms. Every
+/// term is White minus Black; `total` is their sum and equals `evaluate`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+pub struct EvalTrace {
+    pub material: i32,
+    pub pst: i32,
+    pub king_safety: i32,
+    pub passed_pawns: i32,
+    /// Doubled and isolated pawns
+    pub pawn_structure: i32,
+    /// Rooks on (half-)open files and the bishop pair
+    pub piece_bonuses: i32,
+    pub stacks: i32,
+    pub check_bonus: i32,
+    pub tempo: i32,
+    pub total: i32,
+}
+
+pub fn evaluate(board: &Board, params: &EvalParams) -> i32 {
+    evaluate_trace(board, params).total
+}
 
-    let mut queens = 0u32;
-    let mut minors = 0u32;
-    let mut king_sq_w: u8 = 0;
-    let mut king_sq_b: u8 = 0;
+pub fn evaluate_trace(board: &Board, params: &EvalParams) -> EvalTrace {
+    let mut t = EvalTrace::default();
 
     let mut w_pawn_files = [0u8; 8];
     let mut b_pawn_files = [0u8; 8];
     let mut w_pawn_sqs = Vec::with_capacity(8);
     let mut b_pawn_sqs = Vec::with_capacity(8);
+    // Rooks per file; the open-file bonuses depend on nothing else
+    let mut rook_files = [[0i32; 8]; 2];
+    // Counted in the same scan rather than by `Board::piece_counts`
+    let mut counts = PieceCounts::default();
 
     for sq in 0..64u8 {
         let stack = &board.squares[sq as usize];
@@ -127,22 +300,9 @@ pub fn evaluate(board: &Board) -> i32 {
             let is_white = pval < 8;
             let pt = pval & 7;
 
-            // Material
-            let value = PIECE_VALUES[pt as usize];
-            if is_white { score += value; } else { score -= value; }
-
-            // PST (defer king)
-            if pt == KING {
-                if is_white { king_sq_w = sq; } else { king_sq_b = sq; }
-            } else if pt >= 1 && pt <= 5 {
-                let table_sq = if is_white { sq } else { mirror_square(sq) };
-                let pst = pst_value(pt, table_sq);
-                if is_white { score += pst; } else { score -= pst; }
-            }
-
-            // Endgame detection
-            if pt == QUEEN { queens += 1; }
-            else if pt == KNIGHT || pt == BISHOP || pt == ROOK { minors += 1; }
+            let color = if is_white { WHITE } else { BLACK } as usize;
+            counts.pieces[color][pt as usize] += 1;
+            if pt == ROOK { rook_files[color][(sq & 7) as usize] += 1; }
 
             // Pawn tracking
             if pt == PAWN {
@@ -158,67 +318,47 @@ pub fn evaluate(board: &Board) -> i32 {
             }
         }
 
-        // Stack evaluation (inline)
-        if stack.count == 2 {
-            let bottom = stack.pieces[0];
-            let top = stack.pieces[1];
-            let b_color = bottom < 8;
-            let t_color = top < 8;
-            if b_color == t_color {
-                let bottom_pt = bottom & 7;
-                let top_pt = top & 7;
-                let mut stack_value: i32 = 0;
-                if (bottom_pt == KNIGHT || bottom_pt == BISHOP) && (top_pt == KNIGHT || top_pt == BISHOP) {
-                    stack_value += 15;
-                }
-                if (bottom_pt == KNIGHT || bottom_pt == BISHOP) && top_pt == ROOK {
-                    stack_value += 20;
-                }
-                if top_pt == QUEEN || bottom_pt == QUEEN {
-                    stack_value += 5;
-                }
-                if bottom_pt == PAWN {
-                    stack_value += 10;
-                }
-                if top_pt != PAWN && bottom_pt == PAWN {
-                    stack_value -= 5;
-                }
-                if b_color { score += stack_value; } else { score -= stack_value; }
-            }
+        // Stack evaluation
+        if stack.has_stack() {
+            let bonus = stack_bonus(stack.bottom(), stack.top());
+            if stack.pieces[0] < 8 { t.stacks += bonus; } else { t.stacks -= bonus; }
         }
     }
 
-    // Endgame detection
-    let endgame = queens == 0 || (queens == 1 && minors <= 1);
-    let king_table = if endgame { &KING_ENDGAME_TABLE } else { &KING_MIDDLEGAME_TABLE };
+    // Material: the board keeps a running total at the default values,
+    // corrected here by the piece counts for any other set
+    t.material = board.material[WHITE as usize] - board.material[BLACK as usize];
+    for pt in PAWN..=KING {
+        let diff = counts.get(WHITE, pt) as i32 - counts.get(BLACK, pt) as i32;
+        t.material += (params.piece_values[pt as usize] - PIECE_VALUES[pt as usize]) * diff;
+    }
 
-    score += king_table[king_sq_w as usize];
-    score -= king_table[mirror_square(king_sq_b) as usize];
+    // PST from the board's running sums, blended by phase
+    let phase = phase_of(&counts);
+    let [(w_mg, w_eg), (b_mg, b_eg)] = board.pst;
+    t.pst = taper(w_mg - b_mg, w_eg - b_eg, phase);
 
     // King safety
-    score += evaluate_king_safety(board);
+    t.king_safety = evaluate_king_safety(board, params);
 
     // Passed pawn evaluation
+    let mut passers = [0u64; 2];
     for &sq in &w_pawn_sqs {
         let file = (sq & 7) as usize;
         let rank = sq >> 3;
         let ahead_mask = !((1u8 << (rank + 1)).wrapping_sub(1));
-        let mut is_passed = true;
-        for f in file.saturating_sub(1)..=(file + 1).min(7) {
-            if b_pawn_files[f] & ahead_mask != 0 {
-                is_passed = false;
-                break;
-            }
-        }
+        let is_passed = b_pawn_files[file.saturating_sub(1)..=(file + 1).min(7)]
+            .iter().all(|&pawns| pawns & ahead_mask == 0);
         if is_passed {
             let advancement = rank as i32 - 1;
             let mut bonus = if advancement >= 0 {
-                PASSED_PAWN_BONUS[advancement.min(6) as usize]
+                params.passed_pawn_bonus[advancement.min(6) as usize]
             } else { 0 };
             if board.squares[sq as usize].count >= 2 {
-                bonus += 15;
+                bonus += params.passed_pawn_stack_bonus;
             }
-            score += bonus;
+            t.passed_pawns += bonus;
+            passers[WHITE as usize] |= 1 << sq;
         }
     }
 
@@ -226,33 +366,130 @@ pub fn evaluate(board: &Board) -> i32 {
         let file = (sq & 7) as usize;
         let rank = sq >> 3;
         let ahead_mask = (1u8 << rank).wrapping_sub(1);
-        let mut is_passed = true;
-        for f in file.saturating_sub(1)..=(file + 1).min(7) {
-            if w_pawn_files[f] & ahead_mask != 0 {
-                is_passed = false;
-                break;
-            }
-        }
+        let is_passed = w_pawn_files[file.saturating_sub(1)..=(file + 1).min(7)]
+            .iter().all(|&pawns| pawns & ahead_mask == 0);
         if is_passed {
             let advancement = 6 - rank as i32;
             let mut bonus = if advancement >= 0 {
-                PASSED_PAWN_BONUS[advancement.min(6) as usize]
+                params.passed_pawn_bonus[advancement.min(6) as usize]
             } else { 0 };
             if board.squares[sq as usize].count >= 2 {
-                bonus += 15;
+                bonus += params.passed_pawn_stack_bonus;
             }
-            score -= bonus;
+            t.passed_pawns -= bonus;
+            passers[BLACK as usize] |= 1 << sq;
         }
     }
 
+    // Kings near passed pawns, an endgame term: the own king escorting
+    // the pawn in, the enemy king too far away to stop it
+    let mut king_escort = 0;
+    for color in [WHITE, BLACK] {
+        let own_king = board.king_sq[color as usize];
+        let enemy_king = board.king_sq[opposite_color(color) as usize];
+        if own_king >= 64 || enemy_king >= 64 { continue; }
+        let sign = if color == WHITE { 1 } else { -1 };
+        let mut bits = passers[color as usize];
+        while bits != 0 {
+            let sq = pop_square(&mut bits);
+            let stop = if color == WHITE { sq + 8 } else { sq.wrapping_sub(8) };
+            if stop >= 64 { continue; } // Pawn on the last rank of an unvalidated board
+            let lead = square_distance(enemy_king, stop) - square_distance(own_king, stop);
+            king_escort += sign * lead * params.passed_pawn_king_distance;
+        }
+    }
+    t.passed_pawns += taper(0, king_escort, phase);
+
+    // Doubled and isolated pawns
+    for (pawn_sqs, files, sign) in [(&w_pawn_sqs, &w_pawn_files, 1), (&b_pawn_sqs, &b_pawn_files, -1)] {
+        let mut per_file = [0i32; 8];
+        for &sq in pawn_sqs {
+            per_file[(sq & 7) as usize] += 1;
+        }
+        let mut penalty = 0;
+        for f in 0..8 {
+            if per_file[f] == 0 { continue; }
+            penalty += (per_file[f] - 1) * params.doubled_pawn;
+            let left = if f > 0 { files[f - 1] } else { 0 };
+            let right = if f < 7 { files[f + 1] } else { 0 };
+            if left == 0 && right == 0 {
+                penalty += per_file[f] * params.isolated_pawn;
+            }
+        }
+        t.pawn_structure -= sign * penalty;
+    }
+
+    // Rooks on open and half-open files
+    for color in [WHITE, BLACK] {
+        let (own, enemy) = if color == WHITE {
+            (&w_pawn_files, &b_pawn_files)
+        } else {
+            (&b_pawn_files, &w_pawn_files)
+        };
+        let mut bonus = 0;
+        for (f, &rooks) in rook_files[color as usize].iter().enumerate() {
+            if rooks > 0 && own[f] == 0 {
+                bonus += rooks * if enemy[f] == 0 { params.rook_open_file } else { params.rook_half_open_file };
+            }
+        }
+        if counts.get(color, BISHOP) >= 2 { bonus += params.bishop_pair; }
+        if color == WHITE { t.piece_bonuses += bonus; } else { t.piece_bonuses -= bonus; }
+    }
+
     // Check bonus
-    if is_in_check(board, BLACK) { score += 50; }
-    if is_in_check(board, WHITE) { score -= 50; }
+    if is_in_check(board, BLACK) { t.check_bonus += params.check_bonus; }
+    if is_in_check(board, WHITE) { t.check_bonus -= params.check_bonus; }
 
-    score
+    t.tempo = if board.turn == WHITE { params.tempo } else { -params.tempo };
+
+    t.total = t.material + t.pst + t.king_safety + t.passed_pawns + t.pawn_structure
+        + t.piece_bonuses + t.stacks + t.check_bonus + t.tempo;
+    t
+}
+
+/// Bonus for two pieces sharing a square, from their owner's point of view.
+/// A stack moves as either piece, so the bonus reflects how much the pair
+/// gains over the two pieces apart, weighed against losing both to one
+/// capture. The order within the stack doesn't change how it moves, so
+/// neither does the bonus. Mixed-colour stacks and stacked kings can't
+/// occur under the rules (see `Board::validate`) and score 0.
+pub fn stack_bonus(bottom: u8, top: u8) -> i32 {
+    if bottom == NO_PIECE || top == NO_PIECE || piece_color(bottom) != piece_color(top) {
+        return 0;
+    }
+    let (a, b) = {
+        let (x, y) = (piece_type(bottom), piece_type(top));
+        (x.min(y), x.max(y))
+    };
+    match (a, b) {
+        // The king never stacks; king safety penalises it if it happens
+        (_, KING) => 0,
+        // Two pawns advance together and can still split to guard each other
+        (PAWN, PAWN) => 10,
+        // A piece escorting a pawn gains pawn captures and pushes, but the
+        // pair is slow to leave the pawn's file
+        (PAWN, KNIGHT) | (PAWN, BISHOP) | (PAWN, ROOK) => 5,
+        // A queen-pawn stack promotes with the queen riding along
+        (PAWN, QUEEN) => 10,
+        // Two minors: more squares covered, little overlap between them
+        (KNIGHT, KNIGHT) | (KNIGHT, BISHOP) | (BISHOP, BISHOP) => 15,
+        // A minor and a rook complement each other best (a rook-knight or a
+        // queen-like rook-bishop)
+        (KNIGHT, ROOK) | (BISHOP, ROOK) => 20,
+        // Two rooks are a battery, but add no new directions
+        (ROOK, ROOK) => 10,
+        // The queen gains knight jumps
+        (KNIGHT, QUEEN) => 10,
+        // The queen already moves like a bishop or rook; only a slight gain
+        // for keeping the pair together
+        (BISHOP, QUEEN) | (ROOK, QUEEN) => 5,
+        // Nothing gained, and both queens go with one capture
+        (QUEEN, QUEEN) => 0,
+        _ => 0,
+    }
 }
 
-fn evaluate_king_safety(board: &Board) -> i32 {
+fn evaluate_king_safety(board: &Board, params: &EvalParams) -> i32 {
     let mut score: i32 = 0;
 
     for color in [WHITE, BLACK] {
@@ -265,11 +502,11 @@ fn evaluate_king_safety(board: &Board) -> i32 {
 
         // Castled king bonus
         if color == WHITE {
-            if king_sq == SQ_G1 || king_sq == SQ_C1 { safety += 30; }
-            else if king_sq == SQ_E1 { safety -= 20; }
+            if king_sq == SQ_G1 || king_sq == SQ_C1 { safety += params.castled_king; }
+            else if king_sq == SQ_E1 { safety -= params.uncastled_king; }
         } else {
-            if king_sq == SQ_G8 || king_sq == SQ_C8 { safety += 30; }
-            else if king_sq == SQ_E8 { safety -= 20; }
+            if king_sq == SQ_G8 || king_sq == SQ_C8 { safety += params.castled_king; }
+            else if king_sq == SQ_E8 { safety -= params.uncastled_king; }
         }
 
         // Pawn shield
@@ -288,7 +525,7 @@ fn evaluate_king_safety(board: &Board) -> i32 {
                     let stack = &board.squares[sq as usize];
                     for i in 0..stack.count {
                         if stack.pieces[i as usize] == pawn {
-                            safety += 10;
+                            safety += params.pawn_shield;
                             break;
                         }
                     }
@@ -298,7 +535,7 @@ fn evaluate_king_safety(board: &Board) -> i32 {
 
         // King in stack is bad
         if board.squares[king_sq as usize].has_stack() {
-            safety -= 40;
+            safety -= params.stacked_king;
         }
 
         if color == WHITE { score += safety; } else { score -= safety; }
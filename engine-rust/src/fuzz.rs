@@ -0,0 +1,155 @@
+//! Klikschaak Engine - Random-play fuzzer for board invariants
+//!
+//! Usage: `fuzz [games] [plies] [seed]`. Plays random legal games and checks
+//! every position on the way: make/unmake of each legal move restores the
+//! board and hash exactly, the incremental hash matches `compute_zobrist`,
+//! there are legal moves unless the game is over, and the color-flipped
+//! position has as many moves and the opposite evaluation. Game `i` uses
+//! seed `seed + i`, so a failure can be replayed from its seed alone.
+
+use crate::board::Board;
+use crate::evaluate::{evaluate, EvalParams};
+use crate::movegen::{
+    generate_moves, make_move, unmake_move, count_legal_moves, has_legal_move, is_checkmate, is_stalemate,
+};
+use crate::rng::Rng;
+use crate::search::compute_zobrist;
+use crate::types::Move;
+
+const DEFAULT_GAMES: u32 = 200;
+const DEFAULT_PLIES: u32 = 200;
+
+/// Check the invariants in `board`, which is left as it was.
+pub fn check_position(board: &mut Board) -> Result<(), String> {
+    let mut fresh = board.clone();
+    compute_zobrist(&mut fresh);
+    if fresh.zobrist_hash != board.zobrist_hash {
+        return Err("incremental hash differs from compute_zobrist".to_string());
+    }
+    board.validate().map_err(|e| format!("invalid position: {}", e))?;
+
+    let legal = generate_moves(board, true, false);
+    let before = board.clone();
+    let fen = board.get_fen_raw();
+    for &mv in &legal {
+        let undo = make_move(board, mv);
+        unmake_move(board, mv, &undo);
+        if *board != before || board.get_fen_raw() != fen || board.zobrist_hash != before.zobrist_hash {
+            return Err(format!("make/unmake of {} changed the board to {}", mv.to_uci_verbose(&before), board.get_fen_raw()));
+        }
+    }
+
+    let game_over = is_checkmate(board) || is_stalemate(board);
+    if legal.is_empty() != game_over {
+        return Err(format!("{} legal moves, but game over is {}", legal.len(), game_over));
+    }
+    if has_legal_move(board) == legal.is_empty() || count_legal_moves(board) != legal.len() {
+        return Err("has_legal_move / count_legal_moves disagree with the move list".to_string());
+    }
+
+    let mut flipped = board.flip();
+    let flipped_moves = generate_moves(&mut flipped, true, false).len();
+    if flipped_moves != legal.len() {
+        return Err(format!("{} legal moves, {} in the flipped position", legal.len(), flipped_moves));
+    }
+    let params = EvalParams::default();
+    let (eval, flipped_eval) = (evaluate(board, &params), evaluate(&flipped, &params));
+    if eval != -flipped_eval {
+        return Err(format!("eval {} but {} in the flipped position", eval, flipped_eval));
+    }
+    Ok(())
+}
+
+/// Play one random game of up to `plies` plies from the start position,
+/// checking every position. Returns how many were checked, or the failure
+/// with the moves that led to it (replayable with `Board::from_moves`).
+pub fn fuzz_game(seed: u64, plies: u32) -> Result<u32, String> {
+    let mut rng = Rng::new(seed);
+    let mut board = Board::startpos();
+    let mut played: Vec<String> = Vec::new();
+
+    for ply in 0..=plies {
+        if let Err(e) = check_position(&mut board) {
+            return Err(format!("{} in {}\n  moves: {}", e, board.get_fen_raw(), played.join(" ")));
+        }
+        let legal = generate_moves(&mut board, true, false);
+        if legal.is_empty() || ply == plies {
+            return Ok(ply + 1);
+        }
+        let mv = legal[rng.below(legal.len() as u64) as usize];
+        played.push(mv.to_uci_verbose(&board));
+        make_move(&mut board, mv);
+    }
+    unreachable!()
+}
+
+/// Walk up to `plies` random moves from the start position (a shuffled one
+/// for every fourth seed), checking the incremental hash after each move,
+/// then unmake them all, checking that each restores the earlier state
+/// exactly. Returns the moves played.
+pub fn random_walk(seed: u64, plies: u32) -> Result<Vec<Move>, String> {
+    let snapshot = |board: &Board| {
+        (board.get_fen(), board.zobrist_hash, board.king_sq, board.unmoved_pawns, board.stacked_count)
+    };
+    let mut rng = Rng::new(seed);
+    let mut board = if seed % 4 == 3 { Board::shuffle_start(seed) } else { Board::startpos() };
+    compute_zobrist(&mut board);
+    let mut played = Vec::new();
+    for _ in 0..plies {
+        let mut moves = generate_moves(&mut board, true, false);
+        if moves.is_empty() { break; }
+        // Generation order is not stable across runs; keep walks reproducible
+        moves.sort_by_key(|m| m.key());
+        let mv = moves[rng.below(moves.len() as u64) as usize];
+        let before = snapshot(&board);
+        let undo = make_move(&mut board, mv);
+        let mut fresh = board.clone();
+        compute_zobrist(&mut fresh);
+        if board.zobrist_hash != fresh.zobrist_hash {
+            return Err(format!("Incremental hash wrong after {} from {}", mv.to_uci(), before.0));
+        }
+        played.push((mv, undo, before));
+    }
+    let moves = played.iter().map(|&(mv, _, _)| mv).collect();
+    while let Some((mv, undo, before)) = played.pop() {
+        let after = board.get_fen();
+        unmake_move(&mut board, mv, &undo);
+        if snapshot(&board) != before {
+            return Err(format!("Undoing {} from {} gave {}, expected {}", mv.to_uci(), after, board.get_fen(), before.0));
+        }
+    }
+    Ok(moves)
+}
+
+pub fn run_fuzz(args: &[String]) {
+    let games = args.first().and_then(|v| v.parse().ok()).unwrap_or(DEFAULT_GAMES);
+    let plies = args.get(1).and_then(|v| v.parse().ok()).unwrap_or(DEFAULT_PLIES);
+    let seed: u64 = args.get(2).and_then(|v| v.parse().ok()).unwrap_or(0);
+
+    println!("=== Klikschaak fuzz: {} games of up to {} plies, seed {} ===", games, plies, seed);
+    let mut positions = 0u64;
+    for game in 0..games {
+        match fuzz_game(seed + game as u64, plies) {
+            Ok(n) => positions += n as u64,
+            Err(e) => {
+                eprintln!("Game {} (seed {}): {}", game + 1, seed + game as u64, e);
+                std::process::exit(1);
+            }
+        }
+    }
+    println!("Checked {} positions in {} games", positions, games);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn random_walks_unwind() {
+        for seed in 0..200 {
+            if let Err(e) = random_walk(seed, 60) {
+                panic!("seed {}: {}", seed, e);
+            }
+        }
+    }
+}
@@ -1,4 +1,4 @@
-/// Klikschaak Engine - WASM entry point
+//! Klikschaak Engine - WASM entry point
 
 use wasm_bindgen::prelude::*;
 
@@ -7,18 +7,29 @@ pub mod board;
 pub mod movegen;
 pub mod evaluate;
 pub mod search;
+pub mod rng;
+pub mod san;
+pub mod book;
 
-// api and bench are native-only
+// api, bench, fuzz, selfplay and uci are native-only
 #[cfg(not(target_arch = "wasm32"))]
 pub mod api;
 #[cfg(not(target_arch = "wasm32"))]
 pub mod bench;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod fuzz;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod selfplay;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod uci;
 
-use board::Board;
-use movegen::generate_moves;
-use search::{SearchEngine, compute_zobrist, MAX_DEPTH};
-use evaluate::CHECKMATE_SCORE;
-use types::move_type_name;
+use board::{Board, BoardView};
+use movegen::{
+    generate_moves, make_move, is_in_check, is_checkmate, is_stalemate, is_fifty_move_draw,
+    is_insufficient_material,
+};
+use search::{SearchEngine, compute_zobrist};
+use types::{Move, move_type_name, parse_square, square_name, WHITE, BLACK, SQ_NONE};
 
 #[wasm_bindgen]
 pub fn wasm_get_moves(fen: &str) -> String {
@@ -40,25 +51,115 @@ pub fn wasm_get_moves(fen: &str) -> String {
     }).to_string()
 }
 
+/// Legal moves starting on `square` (algebraic, e.g. `e2`), every klik,
+/// unklik and combined variant included, for click-to-move UIs.
+#[wasm_bindgen]
+pub fn wasm_moves_from(fen: &str, square: &str) -> String {
+    let mut board = match Board::try_from_fen(fen) {
+        Ok(board) => board,
+        Err(e) => return serde_json::json!({"error": format!("Invalid FEN: {}", e)}).to_string(),
+    };
+    let from = parse_square(square);
+    if from == SQ_NONE {
+        return serde_json::json!({"error": format!("Invalid square '{}'", square)}).to_string();
+    }
+    compute_zobrist(&mut board);
+    let move_list: Vec<serde_json::Value> = generate_moves(&mut board, true, false).iter()
+        .filter(|m| m.from_sq == from)
+        .map(|m| serde_json::json!({
+            "to": square_name(m.to_sq),
+            "type": move_type_name(m.move_type),
+            "uci": m.to_uci(),
+        }))
+        .collect();
+
+    serde_json::json!({
+        "count": move_list.len(),
+        "moves": move_list,
+        "error": null,
+    }).to_string()
+}
+
+/// Apply a UCI move (engine notation) and return the resulting FEN. An
+/// illegal move leaves the position as it was.
+#[wasm_bindgen]
+pub fn wasm_make_move(fen: &str, uci: &str) -> String {
+    let mut board = match Board::try_from_fen(fen) {
+        Ok(board) => board,
+        Err(e) => return serde_json::json!({"error": format!("Invalid FEN: {}", e)}).to_string(),
+    };
+    compute_zobrist(&mut board);
+    let mv = match Move::from_uci(&mut board, uci) {
+        Some(mv) => mv,
+        None => return serde_json::json!({
+            "fen": board.get_fen(),
+            "legal": false,
+            "check": is_in_check(&board, board.turn),
+            "error": null,
+        }).to_string(),
+    };
+    make_move(&mut board, mv);
+
+    serde_json::json!({
+        "fen": board.get_fen(),
+        "legal": true,
+        "check": is_in_check(&board, board.turn),
+        "error": null,
+    }).to_string()
+}
+
+/// The position as JSON (see `BoardView`). Malformed FENs give
+/// `{"error": ...}`.
+#[wasm_bindgen]
+pub fn wasm_board(fen: &str) -> String {
+    let board = match Board::try_from_fen(fen) {
+        Ok(board) => board,
+        Err(e) => return serde_json::json!({"error": format!("Invalid FEN: {}", e)}).to_string(),
+    };
+    let mut view = serde_json::json!(BoardView::from(&board));
+    view["error"] = serde_json::Value::Null;
+    view.to_string()
+}
+
+/// End-of-game flags for a position. Malformed FENs give `{"error": ...}`.
+#[wasm_bindgen]
+pub fn wasm_game_status(fen: &str) -> String {
+    let mut board = match Board::try_from_fen(fen) {
+        Ok(board) => board,
+        Err(e) => return serde_json::json!({"error": format!("Invalid FEN: {}", e)}).to_string(),
+    };
+    if board.king_sq[WHITE as usize] == SQ_NONE || board.king_sq[BLACK as usize] == SQ_NONE {
+        return serde_json::json!({"error": "FEN must contain both kings"}).to_string();
+    }
+    compute_zobrist(&mut board);
+
+    serde_json::json!({
+        "checkmate": is_checkmate(&mut board),
+        "stalemate": is_stalemate(&mut board),
+        "check": is_in_check(&board, board.turn),
+        "fiftyMove": is_fifty_move_draw(&board),
+        "insufficientMaterial": is_insufficient_material(&board),
+        "error": null,
+    }).to_string()
+}
+
 #[wasm_bindgen]
 pub fn wasm_eval(fen: &str, depth: u32) -> String {
-    let depth = depth.max(1).min(20);
+    wasm_eval_rounded(fen, depth, 1)
+}
+
+/// Like `wasm_eval`, with centipawn scores rounded to `granularity`.
+#[wasm_bindgen]
+pub fn wasm_eval_rounded(fen: &str, depth: u32, granularity: u32) -> String {
+    let depth = depth.clamp(1, 20);
 
     let mut board = Board::from_fen(fen);
     let mut searcher = SearchEngine::new();
+    // stdout goes nowhere in the browser
+    searcher.set_info_callback(Box::new(|_| {}));
     let (best_move, info) = searcher.search(&mut board, depth, None);
 
-    let mut score = info.score;
-    let score_type = if score.abs() >= CHECKMATE_SCORE - MAX_DEPTH as i32 {
-        if score > 0 {
-            score = (CHECKMATE_SCORE - score + 1) / 2;
-        } else {
-            score = -(CHECKMATE_SCORE + score + 1) / 2;
-        }
-        "mate"
-    } else {
-        "cp"
-    };
+    let (score, score_type) = info.score_report_rounded(granularity);
 
     serde_json::json!({
         "score": score,
@@ -66,9 +167,11 @@ pub fn wasm_eval(fen: &str, depth: u32) -> String {
         "bestMove": best_move.map(|m| m.to_uci()),
         "pv": info.pv.iter().map(|m| m.to_uci()).collect::<Vec<_>>(),
         "depth": info.depth,
+        "seldepth": info.seldepth,
         "nodes": info.nodes,
         "nps": info.nps,
         "time_ms": info.time_ms,
+        "gameOver": info.terminal.map(|end| end.as_str()),
         "error": null,
     }).to_string()
 }
@@ -1,10 +1,15 @@
-use klikschaak_engine::board::Board;
+use klikschaak_engine::board::{self, Board, FenError, STARTING_FEN, positions_equal_ignoring_clocks};
 use klikschaak_engine::movegen::{self, generate_moves};
 use klikschaak_engine::search::{self, compute_zobrist};
 use klikschaak_engine::evaluate;
+use klikschaak_engine::rng::Rng;
 use klikschaak_engine::types;
 use klikschaak_engine::api;
 use klikschaak_engine::bench;
+use klikschaak_engine::book;
+use klikschaak_engine::fuzz;
+use klikschaak_engine::selfplay;
+use klikschaak_engine::uci;
 
 fn main() {
     let args: Vec<String> = std::env::args().collect();
@@ -13,12 +18,17 @@ fn main() {
         match args[1].as_str() {
             "test" => { run_tests(); return; }
             "bench" => { bench::run_bench(); return; }
+            "selfplay" => { selfplay::run_selfplay(&args[2..]); return; }
+            "book" => { book::run_build_book(&args[2..]); return; }
+            "fuzz" => { fuzz::run_fuzz(&args[2..]); return; }
+            "uci" => { uci::run_uci(); return; }
+            "serve" => { api::run_server(&api::server_addr(args.get(2).map(String::as_str))); return; }
             _ => {}
         }
     }
 
     // Default: run HTTP server
-    api::run_server();
+    api::run_server(&api::server_addr(None));
 }
 
 fn run_tests() {
@@ -73,13 +83,15 @@ fn run_tests() {
     // Test 5: Evaluation
     print!("Test 5: Evaluation from startpos... ");
     let board = Board::startpos();
-    let eval = evaluate::evaluate(&board);
+    let eval = evaluate::evaluate(&board, &evaluate::EvalParams::default());
     println!("OK (score = {})", eval);
 
     // Test 6: Quick search
     print!("Test 6: Search depth 4... ");
     let mut board = Board::startpos();
-    let (best_move, info) = search::find_best_move(&mut board, 4, None);
+    let mut engine = search::SearchEngine::new();
+    engine.set_verbosity(0);
+    let (best_move, info) = engine.search(&mut board, 4, None);
     if let Some(mv) = best_move {
         println!("OK (best: {}, score: {}, nodes: {}, nps: {})",
             mv.to_uci(), info.score, info.nodes, info.nps);
@@ -87,5 +99,2014 @@ fn run_tests() {
         println!("FAIL: no best move found");
     }
 
+    // Test 7: Capturing a stack removes both pieces
+    print!("Test 7: Capture into an enemy stack... ");
+    let cases = [
+        // single piece capture
+        ("4k3/8/8/(nb)7/8/8/8/R3K3 w - - 0 1", "a1a5", "4k3/8/8/R7/8/8/8/4K3 b - - 0 1"),
+        // unklik capture, knight stays behind
+        ("4k3/8/8/(nb)7/8/8/8/(RN)3K3 w - - 0 1", "a1a5u0", "4k3/8/8/R7/8/8/8/N3K3 b - - 0 1"),
+        // combined capture
+        ("4k3/8/8/(nb)7/8/8/8/(RN)3K3 w - - 0 1", "a1a5", "4k3/8/8/(RN)7/8/8/8/4K3 b - - 0 1"),
+    ];
+    for (fen, uci, expected) in cases {
+        let mut board = Board::from_fen(fen);
+        compute_zobrist(&mut board);
+        let mv = find_move(&mut board, uci);
+        let before = board.get_fen();
+        let undo = movegen::make_move(&mut board, mv);
+        assert_eq!(board.get_fen(), expected, "Wrong result for {}", uci);
+        movegen::unmake_move(&mut board, mv, &undo);
+        assert_eq!(board.get_fen(), before, "Unmake failed for {}", uci);
+    }
+    println!("OK");
+
+    // Test 8: Move ordering is independent of generation order
+    print!("Test 8: Deterministic move ordering... ");
+    let mut board = Board::from_fen("r3k2r/pppq1ppp/2n1bn2/3pp3/3PP3/2N1BN2/PPPQ1PPP/(RB)3K2R w KQkq - 0 1");
+    compute_zobrist(&mut board);
+    let moves = generate_moves(&mut board, false, false);
+    let mut reversed = moves.clone();
+    reversed.reverse();
+    let engine = search::SearchEngine::new();
+    let a = engine.order_moves(&board, &moves, 1, None, None);
+    let b = engine.order_moves(&board, &reversed, 1, None, None);
+    assert_eq!(a, b, "Ordering depends on input order");
+    println!("OK ({} moves)", a.len());
+
+    // Test 9: Stop handle cancels a running search
+    print!("Test 9: Cancel search via stop handle... ");
+    let mut engine = search::SearchEngine::new();
+    engine.set_info_callback(Box::new(|_| {}));
+    let stop = engine.stop_handle();
+    let worker = std::thread::spawn(move || {
+        let mut board = Board::startpos();
+        engine.search(&mut board, search::MAX_DEPTH as u32, None)
+    });
+    std::thread::sleep(std::time::Duration::from_millis(100));
+    stop.store(true, std::sync::atomic::Ordering::Relaxed);
+    let (best_move, info) = worker.join().unwrap();
+    assert!(best_move.is_some(), "Cancelled search returned no move");
+    println!("OK (stopped at depth {})", info.depth);
+
+    // Test 10: Checking moves, direct and klik-discovered
+    print!("Test 10: Checking moves... ");
+    let mut board = Board::from_fen("4k3/8/8/8/4N3/2B5/8/K3R3 w - - 0 1");
+    compute_zobrist(&mut board);
+    let checks: Vec<String> = movegen::checking_moves(&mut board).iter().map(|m| m.to_uci()).collect();
+    assert!(checks.contains(&"e4d6".to_string()), "Direct knight check missing: {:?}", checks);
+    assert!(checks.contains(&"e4c3k".to_string()), "Klik discovered check missing: {:?}", checks);
+    assert!(!checks.contains(&"a1b1".to_string()), "Quiet king move reported as check");
+    assert!(!checks.contains(&"c3d4".to_string()), "Quiet bishop move reported as check");
+    println!("OK ({} checks)", checks.len());
+
+    // Test 11: Board editing keeps the hash incremental
+    print!("Test 11: Board edits maintain zobrist... ");
+    let mut board = Board::startpos();
+    compute_zobrist(&mut board);
+    board.remove_piece(types::SQ_D1, 0);
+    board.put_piece(types::SQ_D1, types::W_KNIGHT);
+    board.set_square(types::SQ_G1, types::SquareStack::double(types::W_KNIGHT, types::W_ROOK));
+    board.remove_piece(types::SQ_G1, 1);
+    board.remove_piece(types::make_square(4, 1), 0);
+    board.set_square(types::make_square(4, 3), types::SquareStack::single(types::W_PAWN));
+    board.remove_piece(types::SQ_E8, 0);
+    board.put_piece(types::make_square(3, 4), types::B_KING);
+    let mut fresh = board.clone();
+    compute_zobrist(&mut fresh);
+    assert_eq!(board.zobrist_hash, fresh.zobrist_hash, "Hash drifted after edits");
+    assert_eq!(board.king_sq[types::BLACK as usize], types::make_square(3, 4));
+    assert_eq!(board.unmoved_pawns[types::WHITE as usize] & (1 << 4), 0, "e2 pawn still marked unmoved");
+    println!("OK");
+
+    // Test 12: IID does not change best moves on a small tactical suite
+    print!("Test 12: Tactical suite with and without IID... ");
+    let suite = [
+        ("4k3/8/4K3/8/8/8/8/7R w - - 0 1", "h1h8"),          // mate in one
+        ("4k3/8/8/3q4/8/8/3R4/4K3 w - - 0 1", "d2d5"),       // hanging queen
+        ("4k3/8/8/1q1N4/8/8/8/4K3 w - - 0 1", "d5c7"),       // knight fork
+    ];
+    for (fen, expected) in suite {
+        for iid in [true, false] {
+            let mut board = Board::from_fen(fen);
+            let mut engine = search::SearchEngine::new();
+            engine.set_iid(iid);
+            engine.set_info_callback(Box::new(|_| {}));
+            let (best, _) = engine.search(&mut board, 6, None);
+            let best = best.map(|m| m.to_uci()).unwrap_or_default();
+            assert_eq!(best, expected, "{} (iid {}) chose {}", fen, iid, best);
+        }
+    }
+    println!("OK");
+
+    // Test 13: Score rounding for display
+    print!("Test 13: Score granularity... ");
+    assert_eq!(search::round_score(137, 10), 140);
+    assert_eq!(search::round_score(137, 1), 137);
+    assert_eq!(search::round_score(-137, 10), -140);
+    assert_eq!(search::round_score(134, 10), 130);
+    assert_eq!(search::round_score(137, 0), 137);
+    println!("OK");
+
+    // Test 14: No two legal moves lead to the same position
+    print!("Test 14: No duplicate moves from stacks... ");
+    let fens = [
+        "4k3/8/8/8/8/2(NN)5/4(PP)3/(RB)3K2(BB) w - - 0 1",
+        "4k3/2(PP)5/8/8/8/8/8/(QR)3K3 w - - 0 1",
+        "r3k3/8/8/8/3(nn)4/8/8/4K3 b - - 0 1",
+    ];
+    for fen in fens {
+        let mut board = Board::from_fen(fen);
+        compute_zobrist(&mut board);
+        let mut seen = std::collections::HashMap::new();
+        for mv in generate_moves(&mut board, true, false) {
+            let undo = movegen::make_move(&mut board, mv);
+            let result = board.get_fen();
+            movegen::unmake_move(&mut board, mv, &undo);
+            if let Some(other) = seen.insert(result.clone(), mv.to_uci()) {
+                panic!("{} and {} both lead to {}", other, mv.to_uci(), result);
+            }
+        }
+    }
+    println!("OK");
+
+    // Test 15: Stacked-square count follows klik and unklik moves
+    print!("Test 15: Stacked-square count... ");
+    let scan = |board: &Board| (0..64u8).filter(|&sq| board.has_stack(sq)).count() as u8;
+    let mut board = Board::startpos();
+    compute_zobrist(&mut board);
+    assert_eq!(board.stacked_count, 0);
+    for uci in ["g1e2k", "g8e7k", "e2f4u1", "b8d7k", "f1e2k", "d7c5u1", "e2b5u1", "c8d7k"] {
+        for mv in generate_moves(&mut board, true, false) {
+            let undo = movegen::make_move(&mut board, mv);
+            assert_eq!(board.stacked_count, scan(&board), "after {}", mv.to_uci());
+            movegen::unmake_move(&mut board, mv, &undo);
+            assert_eq!(board.stacked_count, scan(&board), "after undoing {}", mv.to_uci());
+        }
+        let mv = find_move(&mut board, uci);
+        movegen::make_move(&mut board, mv);
+        assert_eq!(board.stacked_count, scan(&board), "after {}", uci);
+    }
+    let fen_board = Board::from_fen(&board.get_fen());
+    assert_eq!(fen_board.stacked_count, board.stacked_count);
+    println!("OK (ends with {} stacks)", board.stacked_count);
+
+    // Test 16: Shuffled back-rank starts search without trouble, with
+    // castling rights only where the king and rook stand on e and a/h
+    print!("Test 16: Shuffled start positions... ");
+    // Seed 15 keeps queenside rights only, seed 17 both sides
+    for seed in (0..8u64).chain([15, 17]) {
+        let mut board = Board::shuffle_start(seed);
+        let fen = board.get_fen();
+        let back: Vec<u8> = (0..8).map(|f| types::piece_type(board.piece_at(f))).collect();
+        let bishops: Vec<usize> = (0..8).filter(|&f| back[f] == types::BISHOP).collect();
+        let rooks: Vec<usize> = (0..8).filter(|&f| back[f] == types::ROOK).collect();
+        let king = back.iter().position(|&t| t == types::KING).unwrap();
+        assert_eq!(bishops.len(), 2, "{}", fen);
+        assert_ne!(bishops[0] % 2, bishops[1] % 2, "Bishops on same color: {}", fen);
+        assert!(rooks.len() == 2 && rooks[0] < king && king < rooks[1], "King not between rooks: {}", fen);
+        assert_eq!(Board::from_fen(&fen).get_fen(), fen);
+        let kingside = king == 4 && rooks[1] == 7;
+        let queenside = king == 4 && rooks[0] == 0;
+        assert_eq!(board.castling & types::CR_W_KINGSIDE != 0, kingside, "{}", fen);
+        assert_eq!(board.castling & types::CR_W_QUEENSIDE != 0, queenside, "{}", fen);
+        assert_eq!(board.castling & types::CR_B_KINGSIDE != 0, kingside, "{}", fen);
+        assert_eq!(board.castling & types::CR_B_QUEENSIDE != 0, queenside, "{}", fen);
+
+        let mut engine = search::SearchEngine::new();
+        engine.set_verbosity(0);
+        let (best_move, _) = engine.search(&mut board, 3, None);
+        let mv = best_move.unwrap_or_else(|| panic!("No move in {}", fen));
+        assert!(generate_moves(&mut board, true, false).contains(&mv), "Illegal {} in {}", mv.to_uci(), fen);
+    }
+    println!("OK");
+
+    // Test 17: Reported time use stays within the budget
+    print!("Test 17: Time budget reporting... ");
+    let reports = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+    let sink = reports.clone();
+    let mut engine = search::SearchEngine::new();
+    engine.set_info_callback(Box::new(move |info| {
+        sink.lock().unwrap().push((info.time_ms, info.time_budget_ms));
+    }));
+    let mut board = Board::startpos();
+    engine.search(&mut board, search::MAX_DEPTH as u32, Some(300));
+    let reports = reports.lock().unwrap().clone();
+    assert!(!reports.is_empty(), "No info reported");
+    for pair in reports.windows(2) {
+        assert!(pair[0].0 <= pair[1].0, "Used time went backwards: {:?}", reports);
+    }
+    for &(used, budget) in &reports {
+        assert_eq!(budget, Some(300), "Budget not reported");
+        assert!(used <= 300, "Used {}ms of a 300ms budget", used);
+    }
+    let (_, info) = engine.search(&mut board, 3, None);
+    assert_eq!(info.time_budget_ms, None);
+    println!("OK ({} updates)", reports.len());
+
+    // Test 18: Exact position comparison ignores move counters
+    print!("Test 18: Position equality ignoring clocks... ");
+    let a = Board::from_fen("r3k2r/8/8/8/4(PN)3/8/8/R3K2R w KQkq - 0 1");
+    let b = Board::from_fen("r3k2r/8/8/8/4(PN)3/8/8/R3K2R w KQkq - 17 42");
+    let c = Board::from_fen("r3k2r/8/8/8/4(PN)3/8/8/R3K2R w Kkq - 0 1");
+    assert!(positions_equal_ignoring_clocks(&a, &b), "Clocks should be ignored");
+    assert!(!positions_equal_ignoring_clocks(&a, &c), "Castling rights differ");
+    // An en passant square nobody can capture on does not matter
+    let d = Board::from_fen("4k3/8/8/8/4P3/8/8/4K3 b - e3 0 1");
+    let e = Board::from_fen("4k3/8/8/8/4P3/8/8/4K3 b - - 0 1");
+    let f = Board::from_fen("4k3/8/8/8/3(pn)P3/8/8/4K3 b - e3 0 1");
+    let g = Board::from_fen("4k3/8/8/8/3(pn)P3/8/8/4K3 b - - 0 1");
+    assert!(positions_equal_ignoring_clocks(&d, &e), "Uncapturable ep should be ignored");
+    assert!(!positions_equal_ignoring_clocks(&f, &g), "Capturable ep should count");
+    println!("OK");
+
+    // Test 19: Game status for the web UI, including malformed FENs
+    print!("Test 19: Game status... ");
+    let status = |fen: &str| -> serde_json::Value {
+        serde_json::from_str(&klikschaak_engine::wasm_game_status(fen)).unwrap()
+    };
+    let mate = status("4k2R/8/4K3/8/8/8/8/8 b - - 0 1");
+    assert_eq!(mate["checkmate"], true);
+    assert_eq!(mate["check"], true);
+    let draw = status("4k3/8/4K3/8/8/8/8/3(NB)4 w - - 100 80");
+    assert_eq!(draw["fiftyMove"], true);
+    assert_eq!(draw["insufficientMaterial"], false, "Two minors can still mate");
+    assert_eq!(status("4k3/8/4K3/8/8/8/8/3N4 w - - 0 1")["insufficientMaterial"], true);
+    for bad in ["", "garbage", "8/8/8/8 w - -", "k7/99999999999/8/9/9/9/9/9/9/9 w - - 0 1",
+                "(KQR)k6/8/8/8/8/8/8/8/8/8/8/8/8 w - - 0 1", "4k3/8/8/8/8/8/8/8 w - - 0 1"] {
+        assert!(status(bad)["error"].is_string(), "No error for {:?}", bad);
+    }
+    println!("OK");
+
+    // Test 20: Forced recapture is played without using the whole budget
+    print!("Test 20: Early stop on an only move... ");
+    let mut board = Board::from_fen("4k3/8/8/8/8/1n6/3q4/3RK3 w - - 0 1");
+    let mut engine = search::SearchEngine::new();
+    engine.set_info_callback(Box::new(|_| {}));
+    let start = std::time::Instant::now();
+    let (best_move, info) = engine.search(&mut board, search::MAX_DEPTH as u32, Some(3000));
+    let elapsed = start.elapsed().as_millis();
+    assert_eq!(best_move.map(|m| m.to_uci()), Some("d1d2".to_string()));
+    assert!(elapsed < 1500, "Only move took {}ms of 3000ms", elapsed);
+    println!("OK (depth {} in {}ms)", info.depth, elapsed);
+
+    // Test 21: Incremental hash after double pushes matches a full recompute
+    print!("Test 21: En passant hashing... ");
+    let fens = [
+        STARTING_FEN,
+        "4k3/8/8/8/3p4/8/4P3/4K3 w - - 0 1",
+        "4k3/8/8/8/3(np)4/8/2P1P3/4K3 w - - 0 1",
+        "4k3/3pp3/8/4P3/8/8/8/4K3 b - - 0 1",
+        "4k3/2(pb)5/8/1P6/8/8/8/4K3 b - - 0 1",
+    ];
+    let mut pushes = 0;
+    for fen in fens {
+        let mut board = Board::from_fen(fen);
+        compute_zobrist(&mut board);
+        for mv in generate_moves(&mut board, true, false) {
+            let undo = movegen::make_move(&mut board, mv);
+            if board.ep_square != types::SQ_NONE {
+                pushes += 1;
+                let mut fresh = board.clone();
+                compute_zobrist(&mut fresh);
+                assert_eq!(board.zobrist_hash, fresh.zobrist_hash, "Hash mismatch after {} in {}", mv.to_uci(), fen);
+                // And after the reply, which clears the ep square
+                for reply in generate_moves(&mut board, true, false) {
+                    let reply_undo = movegen::make_move(&mut board, reply);
+                    let mut fresh = board.clone();
+                    compute_zobrist(&mut fresh);
+                    assert_eq!(board.zobrist_hash, fresh.zobrist_hash, "Hash mismatch after {} {}", mv.to_uci(), reply.to_uci());
+                    movegen::unmake_move(&mut board, reply, &reply_undo);
+                }
+            }
+            movegen::unmake_move(&mut board, mv, &undo);
+        }
+    }
+    println!("OK ({} double pushes)", pushes);
+
+    // Test 22: Checked FEN parsing
+    print!("Test 22: FEN errors... ");
+    let cases = [
+        ("8/8/8/8/8/8/8/8 w", FenError::TooFewFields(2)),
+        ("8/8/8/8/8/8/8 w - -", FenError::BadRankCount(7)),
+        ("8/8/8/8/8/8/8/7 w - -", FenError::BadRankLength(1)),
+        ("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNX w KQkq - 0 1", FenError::UnknownPiece('X')),
+        ("4k3/8/8/8/8/8/8/(NBR)3K3 w - -", FenError::BadStack("NBR".to_string())),
+        ("4k3/8/8/8/8/8/8/(NB3K3 w - -", FenError::BadStack("NB3K3".to_string())),
+        ("4k3/8/8/8/8/8/8/4K3 x - -", FenError::BadSideToMove("x".to_string())),
+        ("4k3/8/8/8/8/8/8/4K3 w KX -", FenError::BadCastling("KX".to_string())),
+        ("4k3/8/8/8/8/8/8/4K3 w - e9", FenError::BadSquare("e9".to_string())),
+        ("4k3/8/8/8/8/8/8/4K3 w - - x 1", FenError::BadCounter("x".to_string())),
+    ];
+    for (fen, expected) in cases {
+        assert_eq!(Board::try_from_fen(fen).err(), Some(expected), "{}", fen);
+    }
+    let board = Board::try_from_fen("rnbqkbnr/pppppppp/8/8/8/8/(PN)PPPPPP(NP)/R1BQKB1R w KQkq - 0 1").unwrap();
+    assert_eq!(board.get_fen(), "rnbqkbnr/pppppppp/8/8/8/8/(PN)PPPPPP(NP)/R1BQKB1R w KQkq - 0 1");
+    println!("OK");
+
+    // Test 23: Puzzle difficulty grows with the depth of the solution
+    print!("Test 23: Puzzle difficulty... ");
+    let mut shallow = Board::from_fen("4k3/8/4K3/8/8/8/8/7R w - - 0 1");
+    let mv = find_move(&mut shallow, "h1h8");
+    let easy = search::puzzle_difficulty(&mut shallow, &[mv]);
+    // Queen sacrifice on h6 that only pays off a few plies later
+    let mut deep = Board::from_fen("r1bq2r1/b4pk1/p1pp1p2/1p2pP2/1P2P1PB/3P4/1PPQ2P1/R3K2R w KQ - 0 1");
+    let mv = find_move(&mut deep, "d2h6");
+    let hard = search::puzzle_difficulty(&mut deep, &[mv]);
+    assert!(easy < hard, "Mate in one rated {} vs queen sacrifice {}", easy, hard);
+    println!("OK ({} < {})", easy, hard);
+
+    // Test 24: Position legality
+    print!("Test 24: Board validation... ");
+    assert_eq!(Board::startpos().validate(), Ok(()));
+    assert!(Board::from_fen("rnbqkbnr/pppppppp/8/8/8/8/(PN)PPPPPP(NP)/R1BQKB1R w KQkq - 0 1").validate().is_ok());
+    let illegal = [
+        ("4k3/8/8/8/8/8/8/2K1K3 w - - 0 1", "white has 2 kings"),
+        ("8/8/8/8/8/8/8/4K3 w - - 0 1", "black has 0 kings"),
+        ("4k3/8/8/8/8/8/8/P3K3 w - - 0 1", "pawn on back rank"),
+        ("4k3/8/8/8/8/8/8/(RP)3K3 w - - 0 1", "pawn on back rank"),
+        ("4k3/8/8/8/8/8/8/(KR)7 w - - 0 1", "king in a stack"),
+        ("4k3/8/8/8/8/8/8/(Rn)3K3 w - - 0 1", "mixes colors"),
+        ("4k3/8/8/8/8/8/8/4R1K1 w - - 0 1", "side not to move is in check"),
+    ];
+    for (fen, reason) in illegal {
+        let err = Board::from_fen(fen).validate().expect_err(fen);
+        assert!(err.contains(reason), "{}: {}", fen, err);
+    }
+    println!("OK");
+
+    // Test 25: Contempt only refuses draws when not behind in material
+    print!("Test 25: Material-aware contempt... ");
+    let play_out = |fen: &str, moves: &[&str]| {
+        let mut board = Board::from_fen(fen);
+        compute_zobrist(&mut board);
+        let mut history = Vec::new();
+        for uci in moves {
+            let mv = find_move(&mut board, uci);
+            history.push(board.zobrist_hash);
+            movegen::make_move(&mut board, mv);
+        }
+        (board, history)
+    };
+    // Knight shuffle: after it, g1f3 repeats the position
+    let (mut behind, history) = play_out("r3k3/8/8/8/8/8/8/4K1N1 w - - 0 1", &["g1f3", "a8a7", "f3g1", "a7a8"]);
+    let mut engine = search::SearchEngine::new();
+    engine.set_info_callback(Box::new(|_| {}));
+    engine.set_contempt(100);
+    engine.set_game_history(&history);
+    let (best_move, info) = engine.search(&mut behind, 5, None);
+    assert_eq!(best_move.map(|m| m.to_uci()), Some("g1f3".to_string()), "Rook down, should repeat");
+    assert_eq!(info.score, 0);
+    assert_eq!(engine.draw_score(&behind), 0);
+
+    let (mut ahead, history) = play_out("4k3/8/8/8/8/8/8/R3K1N1 w - - 0 1", &["g1f3", "e8d8", "f3g1", "d8e8"]);
+    let mut engine = search::SearchEngine::new();
+    engine.set_info_callback(Box::new(|_| {}));
+    engine.set_contempt(100);
+    engine.set_game_history(&history);
+    let (best_move, info) = engine.search(&mut ahead, 5, None);
+    assert_ne!(best_move.map(|m| m.to_uci()), Some("g1f3".to_string()), "Rook up, should not repeat");
+    assert!(info.score > 0);
+    assert_eq!(engine.draw_score(&ahead), -100);
+    println!("OK");
+
+    // Test 26: Pawns never klik onto the promotion rank
+    print!("Test 26: No promotion klik... ");
+    let fens = [
+        "k3R3/4P3/8/8/8/8/8/4K3 w - - 0 1",
+        "1k1R4/3(NP)4/8/8/8/8/8/4K3 w - - 0 1",
+        "4k3/8/8/8/8/8/4p3/4r1K1 b - - 0 1",
+    ];
+    for fen in fens {
+        let mut board = Board::from_fen(fen);
+        compute_zobrist(&mut board);
+        let promo_rank = if board.turn == types::WHITE { 7 } else { 0 };
+        for mv in generate_moves(&mut board, true, false) {
+            let from = *board.stack_at(mv.from_sq);
+            let pawn = types::make_piece(board.turn, types::PAWN);
+            let moves_pawn = match mv.unklik_index {
+                -1 => from.pieces[..from.count as usize].contains(&pawn),
+                i => from.pieces[i as usize] == pawn,
+            };
+            if moves_pawn && types::square_rank(mv.to_sq) == promo_rank {
+                assert!(mv.move_type == types::MT_PROMOTION || mv.move_type == types::MT_PROMOTION_CAPTURE,
+                    "{} kliks onto the promotion rank in {}", mv.to_uci(), fen);
+            }
+        }
+    }
+    println!("OK");
+
+    // Test 27: En passant on a stacked pawn only takes the pawn
+    print!("Test 27: En passant against stacks... ");
+    // (fen, double push, en passant move, unklik index of the capture, result)
+    let cases = [
+        // pawn at the bottom of a stack, pushed together
+        ("7k/8/8/8/3p4/8/4(PR)3/K7 w - - 0 1", "e2e4", "d4e3", 0, "7k/8/8/8/4R3/4p3/8/K7 w - - 0 2"),
+        // pawn on top, pushed together
+        ("7k/8/8/8/3p4/8/4(NP)3/K7 w - - 0 1", "e2e4", "d4e3", 0, "7k/8/8/8/4N3/4p3/8/K7 w - - 0 2"),
+        // black stack with the pawn at the bottom
+        ("7k/3(pb)4/8/4P3/8/8/8/K7 b - - 0 1", "d7d5", "e5d6", 0, "7k/8/3P4/3b4/8/8/8/K7 b - - 0 2"),
+        // capturing pawn leaves its own stack (unklik en passant)
+        ("7k/3p4/8/4(RP)3/8/8/8/K7 b - - 0 1", "d7d5", "e5d6", 1, "7k/8/3P4/4R3/8/8/8/K7 b - - 0 2"),
+    ];
+    for (fen, push, ep, index, expected) in cases {
+        let mut board = Board::from_fen(fen);
+        compute_zobrist(&mut board);
+        let mv = find_move(&mut board, push);
+        movegen::make_move(&mut board, mv);
+        let before = board.get_fen();
+        let hash = board.zobrist_hash;
+        let mv = generate_moves(&mut board, true, false).into_iter()
+            .find(|m| m.move_type == types::MT_EN_PASSANT && m.to_uci() == ep && m.unklik_index == index)
+            .unwrap_or_else(|| panic!("No en passant {} in {}", ep, before));
+        let undo = movegen::make_move(&mut board, mv);
+        assert_eq!(board.get_fen(), expected, "Wrong result for {} in {}", ep, fen);
+        let mut fresh = board.clone();
+        compute_zobrist(&mut fresh);
+        assert_eq!(board.zobrist_hash, fresh.zobrist_hash, "Hash mismatch after {}", ep);
+        movegen::unmake_move(&mut board, mv, &undo);
+        assert_eq!(board.get_fen(), before, "Unmake failed for {}", ep);
+        assert_eq!(board.zobrist_hash, hash);
+    }
+    // A stack carrying a pawn that already moved gives no en passant
+    let mut board = Board::from_fen("7k/8/8/8/8/4(PR)3/8/K7 w - - 0 1");
+    compute_zobrist(&mut board);
+    let mv = find_move(&mut board, "e3e5");
+    movegen::make_move(&mut board, mv);
+    assert_eq!(board.ep_square, types::SQ_NONE);
+    println!("OK");
+
+    // Test 28: Random games unwind to every earlier state exactly
+    print!("Test 28: Make/unmake random walks... ");
+    let mut plies = 0;
+    let mut kinds = std::collections::HashSet::new();
+    for seed in 0..200u64 {
+        let moves = fuzz::random_walk(seed, 60).unwrap_or_else(|e| panic!("Seed {}: {}", seed, e));
+        plies += moves.len();
+        kinds.extend(moves.iter().map(|m| types::move_type_name(m.move_type)));
+    }
+    println!("OK ({} plies, {} move types)", plies, kinds.len());
+
+    // Test 29: Pawn double-move rights are part of the hash
+    print!("Test 29: Unmoved pawns in hash... ");
+    let mut board = Board::startpos();
+    compute_zobrist(&mut board);
+    let mut moved = board.clone();
+    moved.unmoved_pawns[types::WHITE as usize] &= !(1 << 4);
+    compute_zobrist(&mut moved);
+    assert_ne!(board.zobrist_hash, moved.zobrist_hash, "Hash ignores unmoved pawns");
+    assert!(!generate_moves(&mut moved, true, false).iter().any(|m| m.to_uci() == "e2e4"));
+    for (board, uci) in [(&mut board, "e2e4"), (&mut moved, "g1f3")] {
+        let hash = board.zobrist_hash;
+        let mv = find_move(board, uci);
+        let undo = movegen::make_move(board, mv);
+        let mut fresh = board.clone();
+        compute_zobrist(&mut fresh);
+        assert_eq!(board.zobrist_hash, fresh.zobrist_hash, "Incremental hash wrong after {}", uci);
+        movegen::unmake_move(board, mv, &undo);
+        assert_eq!(board.zobrist_hash, hash);
+    }
+    // Editing a home-rank square updates the rights and the hash together
+    let mut edited = board.clone();
+    edited.put_piece(types::parse_square("e2"), types::W_QUEEN);
+    let mut fresh = edited.clone();
+    compute_zobrist(&mut fresh);
+    assert_eq!(edited.zobrist_hash, fresh.zobrist_hash, "set_square left the hash stale");
+    println!("OK");
+
+    // Test 30: Verbose UCI names the piece leaving a stack and round-trips
+    print!("Test 30: Verbose UCI... ");
+    let mut board = Board::from_fen("4k3/8/8/8/8/8/4(NB)3/4K3 w - - 0 1");
+    compute_zobrist(&mut board);
+    let mv = find_move(&mut board, "e2c3u0");
+    assert_eq!(mv.to_uci_verbose(&board), "e2c3uN");
+    assert_eq!(find_move(&mut board, "e2d3u1").to_uci_verbose(&board), "e2d3uB");
+    assert_eq!(find_move(&mut board, "e2d3").to_uci_verbose(&board), "e2d3"); // combined
+    assert_eq!(types::Move::from_uci(&mut board, "e2c3uN"), Some(mv));
+    assert!(types::Move::from_uci(&mut board, "e2c3uB").is_none());
+    let mut checked = 0;
+    for seed in 0..40u64 {
+        let mut rng = Rng::new(seed);
+        let mut board = Board::startpos();
+        compute_zobrist(&mut board);
+        for _ in 0..40 {
+            let mut moves = generate_moves(&mut board, true, false);
+            if moves.is_empty() { break; }
+            moves.sort_by_key(|m| m.key());
+            let names: Vec<String> = moves.iter().map(|m| m.to_uci_verbose(&board)).collect();
+            for (mv, name) in moves.iter().zip(&names) {
+                assert_eq!(names.iter().filter(|n| *n == name).count(), 1,
+                    "{} is ambiguous in {}", name, board.get_fen());
+                assert_eq!(types::Move::from_uci(&mut board, name), Some(*mv), "{} does not round-trip", name);
+                checked += 1;
+            }
+            let mv = moves[rng.below(moves.len() as u64) as usize];
+            movegen::make_move(&mut board, mv);
+        }
+    }
+    println!("OK ({} moves)", checked);
+
+    // Test 31: Tapered evaluation
+    print!("Test 31: Tapered evaluation... ");
+    let params = evaluate::EvalParams::default();
+    assert_eq!(evaluate::game_phase(&Board::startpos()), evaluate::MAX_PHASE);
+    assert_eq!(evaluate::game_phase(&Board::from_fen("4k3/pppp4/8/8/8/8/PPPP4/4K3 w - - 0 1")), 0);
+    // Central pawns are meant to advance in the opening
+    let mut board = Board::startpos();
+    let before = evaluate::evaluate(&board, &params);
+    let mv = find_move(&mut board, "e2e4");
+    movegen::make_move(&mut board, mv);
+    assert!(evaluate::evaluate(&board, &params) > before, "e2e4 should not lose PST score");
+    // In a pawn ending the king wants the centre and the pawn wants to run
+    let eval = |fen: &str| evaluate::evaluate(&Board::from_fen(fen), &params);
+    assert!(eval("7k/8/8/8/4K3/8/4P3/8 w - - 0 1") > eval("7k/8/8/8/8/8/4P3/4K3 w - - 0 1"));
+    assert!(eval("7k/8/4P3/8/8/8/8/4K3 w - - 0 1") > eval("7k/8/8/8/8/8/4P3/4K3 w - - 0 1"));
+    // Trading queens moves the score by about the PST of the queens, not by
+    // a jump from switching king tables
+    let queens_off = eval("r1b1k2r/pppp1ppp/2n2n2/4p3/4P3/2N2N2/PPPP1PPP/R1B1K2R w KQkq - 0 1");
+    let queens_on = eval("r1bqk2r/pppp1ppp/2n2n2/4p3/4P3/2N2N2/PPPPQPPP/R1B1K2R w KQkq - 0 1");
+    assert!((queens_off - queens_on).abs() <= 30, "{} vs {}", queens_off, queens_on);
+    println!("OK");
+
+    // Test 32: Rook file and bishop pair terms
+    print!("Test 32: Rook files and bishop pair... ");
+    // Moving a passer also moves it relative to the kings; leave that out
+    let no_escort = evaluate::EvalParams { passed_pawn_king_distance: 0, ..params.clone() };
+    let eval = |fen: &str| evaluate::evaluate(&Board::from_fen(fen), &no_escort);
+    // Only the pawn's file changes; both pawns are passed on rank 2
+    let open = eval("4k3/8/8/8/8/8/1P6/R3K3 w - - 0 1") - eval("4k3/8/8/8/8/8/P7/R3K3 w - - 0 1");
+    assert!((open - params.rook_open_file).abs() <= 5, "open file worth {}", open);
+    let half = eval("4k3/p7/8/8/8/8/1P6/R3K3 w - - 0 1") - eval("4k3/p7/8/8/8/8/P7/R3K3 w - - 0 1");
+    assert!((half - params.rook_half_open_file).abs() <= 5, "half-open file worth {}", half);
+    // A stacked pair counts; swapping the second bishop for a knight loses it
+    let pair = eval("4k3/8/8/8/8/2(BB)5/8/4K3 w - - 0 1") - eval("4k3/8/8/8/8/2(BN)5/8/4K3 w - - 0 1");
+    let material = types::PIECE_VALUES[types::BISHOP as usize] - types::PIECE_VALUES[types::KNIGHT as usize];
+    assert!((pair - material - params.bishop_pair).abs() <= 15, "bishop pair worth {}", pair - material);
+    println!("OK");
+
+    // Test 33: Pawn structure penalties
+    print!("Test 33: Doubled and isolated pawns... ");
+    // c3 + d2 against d3 + d2: same rank and material, only the doubling differs
+    let doubled = eval("4k3/8/8/8/8/2P5/3P4/4K3 w - - 0 1") - eval("4k3/8/8/8/8/3P4/3P4/4K3 w - - 0 1");
+    assert!(doubled > 0, "doubled pawns not penalised ({})", doubled);
+    // The same for Black
+    let doubled_black = eval("4k3/3p4/3p4/8/8/8/8/4K3 w - - 0 1") - eval("4k3/3p4/2p5/8/8/8/8/4K3 w - - 0 1");
+    assert_eq!(doubled_black, doubled, "penalties not symmetric");
+    // a2 + c2 are isolated, b2 + c2 support each other
+    let isolated = eval("4k3/8/8/8/8/8/1PP5/4K3 w - - 0 1") - eval("4k3/8/8/8/8/8/P1P5/4K3 w - - 0 1");
+    assert!(isolated >= 2 * params.isolated_pawn - 10, "isolated pawns worth {}", isolated);
+    println!("OK");
+
+    // Test 34: Evaluation weights can be swapped at runtime
+    print!("Test 34: Custom eval params... ");
+    let fen = "4k3/8/8/3n4/8/8/8/3QK3 w - - 0 1";
+    let mut liability = evaluate::EvalParams::default();
+    liability.piece_values[types::KNIGHT as usize] = -1000;
+    assert!(evaluate::evaluate(&Board::from_fen(fen), &liability) > eval(fen) + 1000);
+    let mut board = Board::from_fen(fen);
+    compute_zobrist(&mut board);
+    let mut engine = search::SearchEngine::new();
+    engine.set_verbosity(0);
+    let (best, _) = engine.search(&mut board, 3, None);
+    assert_eq!(best.map(|m| m.to_uci()).as_deref(), Some("d1d5"));
+    let mut engine = search::SearchEngine::new();
+    engine.set_verbosity(0);
+    engine.set_eval_params(liability);
+    let (best, _) = engine.search(&mut board, 3, None);
+    assert_ne!(best.map(|m| m.to_uci()).as_deref(), Some("d1d5"), "Engine ignored its eval params");
+    println!("OK");
+
+    // Test 35: The evaluation trace adds up to the evaluation
+    print!("Test 35: Evaluation trace... ");
+    let mut rng = Rng::new(35);
+    let mut board = Board::startpos();
+    for _ in 0..80 {
+        let t = evaluate::evaluate_trace(&board, &params);
+        assert_eq!(t.total, evaluate::evaluate(&board, &params), "Trace differs in {}", board.get_fen());
+        assert_eq!(t.total, t.material + t.pst + t.king_safety + t.passed_pawns + t.pawn_structure
+            + t.piece_bonuses + t.stacks + t.check_bonus + t.tempo);
+        let mut moves = generate_moves(&mut board, true, false);
+        if moves.is_empty() { break; }
+        moves.sort_by_key(|m| m.key());
+        let mv = moves[rng.below(moves.len() as u64) as usize];
+        movegen::make_move(&mut board, mv);
+    }
+    // A knight-rook stack scores for its owner only in the stacks term
+    let stacked = evaluate::evaluate_trace(&Board::from_fen("4k3/8/8/8/8/8/8/(NR)3K3 w - - 0 1"), &params);
+    let apart = evaluate::evaluate_trace(&Board::from_fen("4k3/8/8/8/8/8/8/N2RK3 w - - 0 1"), &params);
+    assert!(stacked.stacks > 0 && apart.stacks == 0);
+    assert_eq!(stacked.material, apart.material);
+    println!("OK");
+
+    // Test 36: Stack bonuses for every composition
+    print!("Test 36: Stack bonuses... ");
+    let pinned = [
+        (types::W_PAWN, types::W_PAWN, 10), (types::W_PAWN, types::W_KNIGHT, 5), (types::W_PAWN, types::W_QUEEN, 10),
+        (types::W_KNIGHT, types::W_BISHOP, 15), (types::W_BISHOP, types::W_BISHOP, 15), (types::W_KNIGHT, types::W_ROOK, 20),
+        (types::W_BISHOP, types::W_ROOK, 20), (types::W_ROOK, types::W_ROOK, 10), (types::W_KNIGHT, types::W_QUEEN, 10),
+        (types::W_ROOK, types::W_QUEEN, 5), (types::W_QUEEN, types::W_QUEEN, 0), (types::W_KING, types::W_ROOK, 0),
+    ];
+    for (bottom, top, bonus) in pinned {
+        assert_eq!(evaluate::stack_bonus(bottom, top), bonus, "{}{}", types::piece_char(bottom), types::piece_char(top));
+    }
+    for bottom in [types::W_PAWN, types::W_KNIGHT, types::W_BISHOP, types::W_ROOK, types::W_QUEEN, types::W_KING] {
+        for top in [types::W_PAWN, types::W_KNIGHT, types::W_BISHOP, types::W_ROOK, types::W_QUEEN, types::W_KING] {
+            let bonus = evaluate::stack_bonus(bottom, top);
+            // Order and colour don't matter; mixed colours score nothing
+            assert_eq!(evaluate::stack_bonus(top, bottom), bonus);
+            assert_eq!(evaluate::stack_bonus(bottom | 8, top | 8), bonus);
+            assert_eq!(evaluate::stack_bonus(bottom, top | 8), 0);
+            assert_eq!(evaluate::stack_bonus(bottom | 8, top), 0);
+        }
+    }
+    // The stacks term is from White's point of view
+    let t = evaluate::evaluate_trace(&Board::from_fen("(rn)3k3/8/8/8/8/8/8/(PQ)3K3 w - - 0 1"), &params);
+    assert_eq!(t.stacks, 10 - 20);
+    println!("OK");
+
+    // Test 37: Tempo bonus and fifty-move draws in search
+    print!("Test 37: Tempo and fifty-move draws... ");
+    let white = evaluate::evaluate_trace(&Board::startpos(), &params);
+    let black = evaluate::evaluate_trace(&Board::from_fen(&STARTING_FEN.replace(" w ", " b ")), &params);
+    assert_eq!((white.tempo, black.tempo), (params.tempo, -params.tempo));
+    assert_eq!(white.total - white.tempo, black.total - black.tempo);
+    // Every move reaches the hundredth half-move, so a queen up is a draw...
+    let mut board = Board::from_fen("7k/8/8/8/8/8/Q7/K7 w - - 99 80");
+    let mut engine = search::SearchEngine::new();
+    engine.set_verbosity(0);
+    let (_, info) = engine.search(&mut board, 3, None);
+    assert_eq!(info.score, 0, "Fifty-move draw not seen");
+    let mut engine = search::SearchEngine::new();
+    engine.set_verbosity(0);
+    engine.set_contempt(50);
+    let (_, info) = engine.search(&mut board, 3, None);
+    assert_eq!(info.score, -50, "Contempt not applied to the fifty-move draw");
+    // ...but mate on the hundredth half-move is still mate
+    let mut board = Board::from_fen("6k1/5ppp/8/8/8/8/8/K3R3 w - - 99 80");
+    let mut engine = search::SearchEngine::new();
+    engine.set_verbosity(0);
+    let (best, info) = engine.search(&mut board, 3, None);
+    assert_eq!(best.map(|m| m.to_uci()).as_deref(), Some("e1e8"));
+    assert!(info.score > evaluate::CHECKMATE_SCORE - 100, "Mate scored {}", info.score);
+    println!("OK");
+
+    // Test 38: Timed searches stay within the hard limit and start no
+    // iteration after the soft limit (half the budget)
+    print!("Test 38: Soft and hard time limits... ");
+    let fens = [STARTING_FEN, "r3k2r/pp1n1ppp/2p1b3/q2(PN)4/3P4/2N2(BQ)2/PPP2PPP/R3K2R w KQkq - 0 1"];
+    for fen in fens {
+        for budget in [100u64, 400] {
+            let iterations = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+            let sink = iterations.clone();
+            let mut engine = search::SearchEngine::new();
+            engine.set_info_callback(Box::new(move |info| sink.lock().unwrap().push(info.time_ms)));
+            let mut board = Board::from_fen(fen);
+            let start = std::time::Instant::now();
+            let (best, info) = engine.search(&mut board, search::MAX_DEPTH as u32, Some(budget));
+            let wall = start.elapsed().as_millis() as u64;
+            assert!(best.is_some());
+            assert!(wall <= budget + 50, "{}ms search took {}ms", budget, wall);
+            let times = iterations.lock().unwrap().clone();
+            assert_eq!(times.last().copied(), Some(info.time_ms), "Result is not the last completed iteration");
+            let started_late = times[..times.len() - 1].iter().find(|&&t| t >= budget / 2);
+            assert!(started_late.is_none(), "{}ms search started an iteration at {:?}ms", budget, started_late);
+        }
+    }
+    println!("OK");
+
+    // Test 39: Full principal variations from the TT
+    print!("Test 39: PV extraction... ");
+    let mut shortest = usize::MAX;
+    for fen in fens {
+        let mut board = Board::from_fen(fen);
+        compute_zobrist(&mut board);
+        let before = board.get_fen();
+        let mut engine = search::SearchEngine::new();
+        engine.set_info_callback(Box::new(|_| {}));
+        let (best, info) = engine.search(&mut board, 6, None);
+        assert_eq!(info.pv.first().copied(), best);
+        assert_eq!(engine.extract_pv(&mut board, 3).len(), 3.min(info.pv.len()));
+        assert_eq!(board.get_fen(), before, "extract_pv left the board changed");
+        // Every move of the line is legal in turn
+        for &mv in &info.pv {
+            assert!(generate_moves(&mut board, true, false).contains(&mv), "Illegal PV move {}", mv.to_uci());
+            movegen::make_move(&mut board, mv);
+        }
+        shortest = shortest.min(info.pv.len());
+    }
+    assert!(shortest >= 4, "PV of only {} moves at depth 6", shortest);
+    println!("OK (shortest PV {} moves)", shortest);
+
+    // Test 40: Aspiration windows widen step by step on a score jump
+    print!("Test 40: Aspiration re-searches... ");
+    let mut board = Board::from_fen(fens[1]);
+    let mut engine = search::SearchEngine::new();
+    engine.set_info_callback(Box::new(|_| {}));
+    let (best, info) = engine.search(&mut board, 6, None);
+    assert!(info.aspiration_researches > 0, "Score jump without a re-search");
+    assert_eq!(info.pv.first().copied(), best);
+    // The counter covers one search only
+    let (_, again) = engine.search(&mut Board::startpos(), 1, None);
+    assert_eq!(again.aspiration_researches, 0);
+    println!("OK ({} re-searches)", info.aspiration_researches);
+
+    // Test 41: Lazy SMP keeps a sound main line
+    print!("Test 41: Parallel search... ");
+    let mut total_nodes = 0;
+    for fen in [fens[0], fens[1], "6k1/5ppp/8/8/8/8/8/K3R3 w - - 0 1"] {
+        let mut board = Board::from_fen(fen);
+        compute_zobrist(&mut board);
+        let before = board.get_fen();
+        let mut engine = search::SearchEngine::new();
+        engine.set_info_callback(Box::new(|_| {}));
+        let (best, info) = engine.search_parallel(&mut board, 6, None, 4);
+        assert_eq!(board.get_fen(), before);
+        assert_eq!(info.pv.first().copied(), best, "Best move is not the main line's");
+        for &mv in &info.pv {
+            assert!(generate_moves(&mut board, true, false).contains(&mv), "Illegal PV move {} in {}", mv.to_uci(), fen);
+            movegen::make_move(&mut board, mv);
+        }
+        total_nodes += info.nodes;
+        if fen.starts_with("6k1") {
+            assert_eq!(best.map(|m| m.to_uci()).as_deref(), Some("e1e8"));
+        }
+    }
+    // A time limit stops the helpers too
+    let mut engine = search::SearchEngine::new();
+    engine.set_info_callback(Box::new(|_| {}));
+    let start = std::time::Instant::now();
+    let (best, _) = engine.search_parallel(&mut Board::startpos(), search::MAX_DEPTH as u32, Some(200), 3);
+    assert!(best.is_some());
+    assert!(start.elapsed().as_millis() <= 300, "Parallel search overran its budget");
+    println!("OK ({} nodes)", total_nodes);
+
+    // Test 42: Engines sharing a table reuse each other's work
+    print!("Test 42: Shared transposition table... ");
+    let tt = std::sync::Arc::new(search::TranspositionTable::new(1 << 16));
+    let mut nodes = Vec::new();
+    let mut answers = Vec::new();
+    for _ in 0..2 {
+        let mut engine = search::SearchEngine::with_tt(tt.clone());
+        engine.set_info_callback(Box::new(|_| {}));
+        let (best, info) = engine.search(&mut Board::from_fen(fens[1]), 6, None);
+        nodes.push(info.nodes);
+        answers.push((best, info.score));
+    }
+    assert_eq!(answers[0], answers[1]);
+    assert!(nodes[1] * 10 < nodes[0], "Second search did not reuse the table: {:?}", nodes);
+    tt.clear();
+    let mut engine = search::SearchEngine::with_tt(tt.clone());
+    engine.set_info_callback(Box::new(|_| {}));
+    let (_, info) = engine.search(&mut Board::from_fen(fens[1]), 6, None);
+    assert!(info.nodes * 2 > nodes[0], "Cleared table still hit");
+    println!("OK ({:?} nodes)", nodes);
+
+    // Test 43: History survives searches and is aged by new_game
+    print!("Test 43: New game aging... ");
+    let mut engine = search::SearchEngine::new();
+    engine.set_info_callback(Box::new(|_| {}));
+    let mut board = Board::from_fen(fens[1]);
+    engine.search(&mut board, 6, None);
+    let moves = generate_moves(&mut board, true, false);
+    let top = *moves.iter().max_by_key(|&&m| engine.history_score(m)).unwrap();
+    let score = engine.history_score(top);
+    assert!(score > 0, "Search left no history");
+    engine.new_game();
+    assert!(engine.history_score(top) > 0 && engine.history_score(top) < score, "History not aged");
+    let (_, info) = engine.search(&mut board, 6, None);
+    assert!(info.nodes * 2 > nodes[0], "new_game kept the TT");
+    engine.clear();
+    assert_eq!(engine.history_score(top), 0);
+    println!("OK");
+
+    // Test 44: Buffer-filling move generation matches generate_moves
+    print!("Test 44: generate_moves_into... ");
+    let mut buf = Vec::new();
+    for fen in fens {
+        let mut board = Board::from_fen(fen);
+        compute_zobrist(&mut board);
+        for (legal_only, captures_only) in [(true, false), (false, false), (false, true)] {
+            movegen::generate_moves_into(&mut board, &mut buf, legal_only, captures_only);
+            assert_eq!(buf, generate_moves(&mut board, legal_only, captures_only), "{}", fen);
+        }
+        let divide: u64 = movegen::perft_divide(&mut board, 3).iter().map(|&(_, n)| n).sum();
+        assert_eq!(movegen::perft(&mut board, 3), divide, "{}", fen);
+    }
+    let mut board = Board::from_fen(fens[1]);
+    let first: Vec<String> = generate_moves(&mut board, true, false).iter().map(|m| m.to_uci_verbose(&board)).collect();
+    let again: Vec<String> = generate_moves(&mut board, true, false).iter().map(|m| m.to_uci_verbose(&board)).collect();
+    assert_eq!(first, again, "Move order is not deterministic");
+    println!("OK");
+
+    // Test 45: PackedMove round-trips every field
+    print!("Test 45: Packed moves... ");
+    assert_eq!(std::mem::size_of::<types::PackedMove>(), 4);
+    let mut board = Board::from_fen("4k3/(PN)7/8/8/8/8/8/4K3 w - - 0 1");
+    let mut moves = generate_moves(&mut board, true, false);
+    assert!(moves.iter().any(|m| m.unklik_index == -1 && m.promotion == types::QUEEN), "No combined promotion");
+    assert!(moves.iter().any(|m| m.unklik_index == 0 && m.promotion == types::KNIGHT), "No unklik promotion");
+    moves.extend(generate_moves(&mut Board::from_fen(fens[1]), true, false));
+    for mv in moves {
+        let packed = types::PackedMove::pack(mv);
+        assert!(packed.0 < 1 << types::PackedMove::BITS, "{} uses too many bits", mv);
+        assert_eq!(packed.unpack(), mv);
+        assert_eq!(types::Move::from_key(mv.key()), mv);
+    }
+    println!("OK");
+
+    // Test 46: Combined moves are unchanged by the target bitset (lists
+    // recorded from the HashSet implementation)
+    print!("Test 46: Combined move targets... ");
+    let cases: [(&str, &[&str]); 3] = [
+        (fens[1], &["d5b4", "d5b6", "d5c6", "d5c7", "d5d6", "d5e3", "d5e6", "d5e7", "d5f4", "d5f6",
+            "f3d1", "f3d3", "f3e2", "f3e3", "f3e4", "f3f4", "f3f5", "f3f6", "f3f7", "f3g3", "f3g4", "f3h3", "f3h5"]),
+        ("4k3/1(PR)6/8/8/8/8/8/4K3 w - - 0 1", &["b7a7", "b7b2", "b7b3", "b7b4", "b7b5", "b7b6",
+            "b7b8b", "b7b8n", "b7b8q", "b7b8r", "b7c7", "b7d7", "b7e7", "b7f7", "b7g7", "b7h7"]),
+        ("4k3/8/8/3(PQ)p3/8/8/8/4K3 w - e6 0 1", &["d5a2", "d5a5", "d5b3", "d5b5", "d5b7", "d5c4", "d5c5",
+            "d5c6", "d5d2", "d5d3", "d5d4", "d5d6", "d5d7", "d5e4", "d5e5", "d5e6", "d5f3", "d5f7", "d5g2"]),
+    ];
+    for (fen, expected) in cases {
+        let mut board = Board::from_fen(fen);
+        let mut combined: Vec<String> = generate_moves(&mut board, true, false).iter()
+            .filter(|m| m.unklik_index == -1)
+            .map(|m| m.to_uci())
+            .collect();
+        combined.sort();
+        assert_eq!(combined, expected, "{}", fen);
+    }
+    println!("OK");
+
+    // Test 47: Running material and PST match a rescan of the board
+    print!("Test 47: Incremental material/PST... ");
+    let scores = |board: &Board| (board.material, board.pst);
+    // Equal king values cancel, but force evaluate to count material itself
+    let mut rescan = evaluate::EvalParams::default();
+    rescan.piece_values[types::KING as usize] += 1;
+    let mut plies = 0;
+    for seed in 0..50u64 {
+        let mut rng = Rng::new(seed);
+        let mut board = Board::from_fen(fens[seed as usize % 2]);
+        compute_zobrist(&mut board);
+        let start = scores(&board);
+        let mut played = Vec::new();
+        for _ in 0..80 {
+            let moves = generate_moves(&mut board, true, false);
+            if moves.is_empty() { break; }
+            let mv = moves[rng.below(moves.len() as u64) as usize];
+            played.push((mv, movegen::make_move(&mut board, mv)));
+            assert_eq!(scores(&board), scores(&Board::from_fen(&board.get_fen())),
+                "Running totals wrong after {} in {}", mv.to_uci(), board.get_fen());
+            assert_eq!(evaluate::evaluate(&board, &params), evaluate::evaluate(&board, &rescan));
+        }
+        plies += played.len();
+        while let Some((mv, undo)) = played.pop() {
+            movegen::unmake_move(&mut board, mv, &undo);
+        }
+        assert_eq!(scores(&board), start);
+    }
+    println!("OK ({} plies)", plies);
+
+    // Test 48: Ray tables stop at the first piece and never wrap a file
+    print!("Test 48: Ray blockers... ");
+    let board = Board::startpos();
+    let names = |sqs: [u8; 8]| sqs.map(|sq| if sq == types::SQ_NONE { "-".to_string() } else { types::square_name(sq) });
+    // Diagonals (-9, -7, 7, 9), then lines (-8, -1, 1, 8)
+    assert_eq!(names(movegen::ray_blockers(&board, types::parse_square("d4"))),
+        ["b2", "f2", "a7", "g7", "d2", "-", "-", "d7"]);
+    assert_eq!(names(movegen::ray_blockers(&board, types::parse_square("h3"))),
+        ["g2", "-", "d7", "-", "h2", "-", "-", "h7"]);
+    println!("OK");
+
+    // Test 49: Clearing one reused engine forgets the TT, also across the
+    // generation counter wrapping
+    print!("Test 49: TT generations... ");
+    let mut engine = search::SearchEngine::new();
+    engine.set_info_callback(Box::new(|_| {}));
+    let start = std::time::Instant::now();
+    for round in 0..300 {
+        let mut board = Board::from_fen(fens[round % 2]);
+        engine.search(&mut board, 2, None);
+        assert!(!engine.extract_pv(&mut board, 4).is_empty(), "Round {} left no PV", round);
+        engine.clear();
+        assert!(engine.extract_pv(&mut board, 4).is_empty(), "Round {} kept the TT", round);
+    }
+    println!("OK (300 searches and clears in {}ms)", start.elapsed().as_millis());
+
+    // Test 50: SAN with disambiguation, stack suffixes and check marks
+    print!("Test 50: SAN output... ");
+    let cases = [
+        (STARTING_FEN, "g1f3", "Nf3"),
+        (STARTING_FEN, "e2e4", "e4"),
+        (STARTING_FEN, "b1d2k", "Nd2=k"),
+        ("4k3/8/8/3p4/4P3/8/8/4K3 w - - 0 1", "e4d5", "exd5"),
+        ("r3k2r/8/8/8/8/8/8/R3K2R w KQkq - 0 1", "e1g1", "O-O"),
+        ("r3k2r/8/8/8/8/8/8/R3K2R w KQkq - 0 1", "e1c1", "O-O-O"),
+        ("4k3/8/8/8/8/8/8/R4RK1 w - - 0 1", "a1e1", "Rae1+"),
+        ("4k3/8/8/8/8/8/8/R4RK1 w - - 0 1", "f1e1", "Rfe1+"),
+        ("4k3/8/8/R7/8/8/8/R5K1 w - - 0 1", "a1a3", "R1a3"),
+        ("4k3/8/8/R7/8/8/8/R5K1 w - - 0 1", "a5a3", "R5a3"),
+        ("6k1/5ppp/8/8/8/8/8/R5K1 w - - 0 1", "a1a8", "Ra8#"),
+        (fens[1], "d5c6", "(PN)xc6"),
+        (fens[1], "d5c6u0", "dxc6=u"),
+        (fens[1], "d5c7u1", "Nc7=u+"),
+        (fens[1], "f3f2U1", "Qf2=uk"),
+        (fens[1], "f3e2u0", "Be2=u"),
+        ("4k3/1P6/8/8/8/8/8/4K3 w - - 0 1", "b7b8q", "b8=Q+"),
+    ];
+    for (fen, uci, expected) in cases {
+        let mut board = Board::from_fen(fen);
+        let mv = find_move(&mut board, uci);
+        assert_eq!(mv.to_san(&board), expected, "{} in {}", uci, fen);
+    }
+    println!("OK");
+
+    // Test 51: SAN parsing round-trips and rejects ambiguous input
+    print!("Test 51: SAN parsing... ");
+    let mut parsed = 0;
+    for fen in [STARTING_FEN, fens[1], "r3k2r/8/8/8/8/8/8/R3K2R w KQkq - 0 1", "4k3/1P6/8/8/8/8/8/4K3 w - - 0 1"] {
+        let mut board = Board::from_fen(fen);
+        for mv in generate_moves(&mut board, true, false) {
+            let san = mv.to_san(&board);
+            assert_eq!(types::Move::from_san(&board, &san), Some(mv), "{} in {}", san, fen);
+            parsed += 1;
+        }
+    }
+    let queens = Board::from_fen("4k3/8/8/8/8/Q1Q5/8/Q5K1 w - - 0 1");
+    let cases = [
+        (queens.clone(), "Qa3b2", Some("a3b2")),
+        (queens.clone(), "Qab2", None),
+        (queens.clone(), "Q3b2", None),
+        (queens.clone(), "Qcb2", Some("c3b2")),
+        (queens.clone(), "Q1b2", Some("a1b2")),
+        (Board::from_fen("4k3/8/8/8/8/8/8/R4RK1 w - - 0 1"), "Re1", None),
+        (Board::from_fen("4k3/8/8/8/8/8/8/R4RK1 w - - 0 1"), "Rae1+", Some("a1e1")),
+        (Board::from_fen("4k3/1P6/8/8/8/8/8/4K3 w - - 0 1"), "b8Q", Some("b7b8q")),
+        (Board::from_fen("4k3/1P6/8/8/8/8/8/4K3 w - - 0 1"), "b8", None),
+        (Board::startpos(), "Nd2", None),
+        (Board::startpos(), "Nd2=k", Some("b1d2k")),
+        (Board::startpos(), "0-0", None),
+        (Board::startpos(), "Nf3!?", Some("g1f3")),
+        (Board::startpos(), "zz", None),
+        (Board::from_fen(fens[1]), "(NP)xc6", Some("d5c6")),
+        (Board::from_fen(fens[1]), "xc6=u", Some("d5c6u0")),
+        (Board::from_fen(fens[1]), "Nc6=u", None),
+    ];
+    for (board, san, expected) in cases {
+        let mv = types::Move::from_san(&board, san).map(|m| m.to_uci());
+        assert_eq!(mv.as_deref(), expected, "{} in {}", san, board.get_fen());
+    }
+    println!("OK ({} moves round-tripped)", parsed);
+
+    // Test 52: Known perft node counts. The lone (NB) stack is counted by
+    // hand at depths 1-2: 2 knight and 7 bishop unkliks, 9 combined moves
+    // and 3 king moves, then 3 king replies except after the four moves
+    // that put a bishop on d4 or e5.
+    print!("Test 52: Perft suite... ");
+    let suite: [(&str, &[u64]); 9] = [
+        (STARTING_FEN, &[34, 1156, 46740, 1875365]),
+        ("k7/8/8/8/8/8/8/(NB)6K w - - 0 1", &[21, 59, 1461, 8130, 189453]),
+        (fens[1], &[78, 3385, 244713]),
+        ("r3k2r/8/8/8/8/8/8/R3K2R w KQkq - 0 1", &[26, 568, 13948, 324739]),
+        ("r3k2r/8/8/8/8/8/8/(RN)3K2(RB) w KQkq - 0 1", &[63, 1285, 69688]),
+        ("r3k2r/8/8/8/8/8/8/R2NKB1R w KQkq - 0 1", &[35, 768, 26728]),
+        ("r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1", &[65, 3662, 243463]),
+        ("4k3/1(PR)6/8/8/8/8/8/4K3 w - - 0 1", &[39, 112, 3141, 13978]),
+        ("4k3/8/8/3(PQ)p3/8/8/8/4K3 w - e6 0 1", &[50, 199, 6880]),
+    ];
+    let mut total = 0;
+    for (fen, expected) in suite {
+        let mut board = Board::from_fen(fen);
+        compute_zobrist(&mut board);
+        let before = (board.get_fen(), board.zobrist_hash);
+        for (depth, &nodes) in expected.iter().enumerate() {
+            let depth = depth as u32 + 1;
+            assert_eq!(movegen::perft(&mut board, depth), nodes, "perft {} of {}", depth, fen);
+            total += nodes;
+        }
+        assert_eq!((board.get_fen(), board.zobrist_hash), before, "Perft did not restore {}", fen);
+    }
+    println!("OK ({} nodes)", total);
+
+    // Test 53: Self-play games run to a legal end
+    print!("Test 53: Self-play... ");
+    let mut engine = search::SearchEngine::new();
+    engine.set_info_callback(Box::new(|_| {}));
+    let mut plies = 0;
+    for seed in 0..3 {
+        let game = selfplay::play_game(&mut engine, seed, 2, None).unwrap_or_else(|e| panic!("{}", e));
+        assert!(!game.moves.is_empty() && game.move_text().starts_with("1. "));
+        plies += game.moves.len();
+    }
+    println!("OK ({} plies)", plies);
+
+    // Test 54: Searching a finished game reports why there is no move
+    print!("Test 54: Terminal root... ");
+    let mut engine = search::SearchEngine::new();
+    engine.set_info_callback(Box::new(|_| {}));
+    let (best, info) = engine.search(&mut Board::from_fen("R5k1/5ppp/8/8/8/8/8/6K1 b - - 0 1"), 4, None);
+    assert_eq!((best, info.terminal, info.score), (None, Some(search::GameEnd::Checkmate), evaluate::CHECKMATE_SCORE));
+    let (best, info) = engine.search(&mut Board::from_fen("k7/2Q5/1K6/8/8/8/8/8 b - - 0 1"), 4, None);
+    assert_eq!((best, info.terminal, info.score), (None, Some(search::GameEnd::Stalemate), 0));
+    let (best, info) = engine.search(&mut Board::startpos(), 2, None);
+    assert!(best.is_some() && info.terminal.is_none());
+    println!("OK");
+
+    // Test 55: LMR schedule grows with depth and move number, and can be
+    // switched off
+    print!("Test 55: LMR table... ");
+    let table = search::LmrTable::new(search::LmrConfig::default());
+    assert_eq!(table.reduction(2, 30), 0);
+    assert_eq!(table.reduction(8, 3), 0);
+    assert_eq!(table.reduction(3, 4), 1);
+    assert!(table.reduction(12, 40) > table.reduction(4, 8));
+    let off = search::LmrTable::new(search::LmrConfig { enabled: false, ..search::LmrConfig::default() });
+    assert_eq!(off.reduction(12, 40), 0);
+    let mut nodes = [0u64; 2];
+    let mut best = [None; 2];
+    for (i, enabled) in [true, false].into_iter().enumerate() {
+        let mut engine = search::SearchEngine::new();
+        engine.set_lmr(search::LmrConfig { enabled, ..search::LmrConfig::default() });
+        engine.set_info_callback(Box::new(|_| {}));
+        let (mv, info) = engine.search(&mut Board::from_fen("6k1/5ppp/8/8/8/8/5PPP/R5K1 w - - 0 1"), 5, None);
+        nodes[i] = info.nodes;
+        best[i] = mv;
+    }
+    assert!(best[0] == best[1] && best[0].is_some_and(|m| m.to_uci() == "a1a8"), "{:?}", best);
+    assert!(nodes[0] < nodes[1], "LMR did not reduce: {:?}", nodes);
+    println!("OK ({} / {} nodes)", nodes[0], nodes[1]);
+
+    // Test 56: Check extensions see a mate in 2 through the checks at depth 2
+    print!("Test 56: Check extensions... ");
+    let mut engine = search::SearchEngine::new();
+    engine.set_info_callback(Box::new(|_| {}));
+    let (mv, info) = engine.search(&mut Board::from_fen("2r4k/6pp/8/8/8/8/4RPPP/4R1K1 w - - 0 1"), 2, None);
+    assert_eq!(mv.map(|m| m.to_uci()).as_deref(), Some("e2e8"));
+    assert!(info.score >= evaluate::CHECKMATE_SCORE - search::MAX_DEPTH as i32, "no mate: {}", info.score);
+    println!("OK");
+
+    // Test 57: Selective depth counts the quiescence plies past the nominal depth
+    print!("Test 57: Seldepth... ");
+    let mut engine = search::SearchEngine::new();
+    engine.set_info_callback(Box::new(|_| {}));
+    let (_, info) = engine.search(&mut Board::from_fen("r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1"), 3, None);
+    assert!(info.seldepth > info.depth, "seldepth {} at depth {}", info.seldepth, info.depth);
+    println!("OK (depth {}, seldepth {})", info.depth, info.seldepth);
+
+    // Test 58: king_sq follows the king through castle-kliks, unkliks next
+    // to it and random games, checked against a fresh scan of the board
+    print!("Test 58: King square tracking... ");
+    let scanned_king_sq = |board: &Board| Board::from_fen(&board.get_fen()).king_sq;
+    let mut board = Board::from_fen("r3k2r/8/8/8/8/8/8/R3KB1R w KQkq - 0 1");
+    for uci in ["e1g1", "e8c8", "f1a6u0", "c8b8", "a1f1k", "h8h2", "g1h2", "b8a7", "f1a1u1", "a7b6"] {
+        let mv = find_move(&mut board, uci);
+        movegen::make_move(&mut board, mv);
+        assert_eq!(board.king_sq, scanned_king_sq(&board), "after {} in {}", uci, board.get_fen());
+    }
+    let mut plies = 0;
+    for seed in 0..100u64 {
+        let mut rng = Rng::new(seed);
+        let mut board = Board::from_fen(fens[(seed % 2) as usize]);
+        for _ in 0..80 {
+            let mut moves = generate_moves(&mut board, true, false);
+            if moves.is_empty() { break; }
+            moves.sort_by_key(|m| m.key());
+            let mv = moves[rng.below(moves.len() as u64) as usize];
+            movegen::make_move(&mut board, mv);
+            assert_eq!(board.king_sq, scanned_king_sq(&board), "after {} in {}", mv.to_uci(), board.get_fen());
+            plies += 1;
+        }
+    }
+    println!("OK ({} plies)", plies);
+
+    // Test 59: make_move_checked keeps legal moves and rejects the rest
+    // without touching the board
+    print!("Test 59: Checked make_move... ");
+    let mut board = Board::from_fen("4k3/4r3/8/8/8/8/4B3/4K3 w - - 0 1");
+    compute_zobrist(&mut board);
+    let before = board.clone();
+    let pinned = types::Move::new(types::parse_square("e2"), types::parse_square("d3"), types::MT_NORMAL);
+    assert_eq!(board.make_move_checked(pinned).err(), Some(board::IllegalMove::LeavesKingInCheck(pinned)));
+    let bogus = types::Move::new(types::parse_square("a1"), types::parse_square("a8"), types::MT_NORMAL);
+    assert_eq!(board.make_move_checked(bogus).err(), Some(board::IllegalMove::NotAMove(bogus)));
+    assert!(board.squares == before.squares && board.zobrist_hash == before.zobrist_hash);
+    let mv = find_move(&mut board, "e1d2");
+    let undo = board.make_move_checked(mv).expect("legal move rejected");
+    assert_eq!(board.king_sq[types::WHITE as usize], types::parse_square("d2"));
+    movegen::unmake_move(&mut board, mv, &undo);
+    assert_eq!(board.get_fen(), before.get_fen());
+    println!("OK");
+
+    // Test 60: Book entries survive the file format, klik and unklik moves
+    // included, and a book hit skips the search
+    print!("Test 60: Opening book... ");
+    let mut board = Board::from_fen(fens[1]);
+    compute_zobrist(&mut board);
+    let picks: Vec<types::Move> = generate_moves(&mut board, true, false).into_iter()
+        .filter(|m| matches!(m.move_type, types::MT_KLIK | types::MT_UNKLIK) || m.unklik_index == -1)
+        .collect();
+    assert!(picks.iter().any(|m| m.move_type == types::MT_KLIK) && picks.iter().any(|m| m.move_type == types::MT_UNKLIK));
+    let entries: Vec<book::BookEntry> = picks.iter()
+        .map(|&mv| book::BookEntry { key: board.zobrist_hash, mv, weight: 1 })
+        .collect();
+    let written = book::OpeningBook::new(entries);
+    let mut loaded = book::OpeningBook::from_bytes(&written.to_bytes()).expect("book round-trip");
+    assert_eq!(loaded.moves(&board).len(), picks.len());
+    assert!(loaded.moves(&board).iter().all(|e| picks.contains(&e.mv)));
+    assert!(loaded.moves(&Board::startpos()).is_empty());
+    let hit = loaded.probe(&board).expect("book miss");
+    assert!(picks.contains(&hit));
+    assert!(book::OpeningBook::from_bytes(&[0; 15]).is_err());
+    let mut engine = search::SearchEngine::new();
+    engine.set_info_callback(Box::new(|_| {}));
+    engine.set_book(Some(loaded));
+    let (mv, info) = engine.search(&mut board.clone(), 6, None);
+    assert!(mv.is_some_and(|m| picks.contains(&m)) && info.nodes == 0);
+    let built = book::build_book(3, 2, 3, 0);
+    assert!(built.moves(&Board::startpos()).iter().map(|e| e.weight).sum::<u16>() == 3);
+    println!("OK ({} moves in book, {} built)", picks.len(), built.len());
+
+    // Test 61: Flipping the colors negates the evaluation and keeps the
+    // moves; flipping twice gives the position back
+    print!("Test 61: Color flip symmetry... ");
+    let mut checked = 0;
+    for seed in 0..40u64 {
+        let mut rng = Rng::new(seed);
+        let mut board = Board::from_fen(fens[(seed % 2) as usize]);
+        compute_zobrist(&mut board);
+        for _ in 0..40 {
+            let mut flipped = board.flip();
+            assert_eq!(flipped.flip().get_fen(), board.get_fen());
+            assert_eq!(evaluate::evaluate(&board, &params), -evaluate::evaluate(&flipped, &params),
+                "eval not symmetric in {}", board.get_fen());
+            assert_eq!(generate_moves(&mut board, true, false).len(), generate_moves(&mut flipped, true, false).len(),
+                "moves differ in {} and {}", board.get_fen(), flipped.get_fen());
+            checked += 1;
+
+            let mut moves = generate_moves(&mut board, true, false);
+            if moves.is_empty() { break; }
+            moves.sort_by_key(|m| m.key());
+            movegen::make_move(&mut board, moves[rng.below(moves.len() as u64) as usize]);
+        }
+    }
+    let mut flipped = Board::from_fen(fens[1]).flip();
+    assert_eq!(movegen::perft(&mut flipped, 3), movegen::perft(&mut Board::from_fen(fens[1]), 3));
+    println!("OK ({} positions)", checked);
+
+    // Test 62: Zobrist keys are all distinct, and different positions in
+    // the first three plies never share a hash
+    print!("Test 62: Zobrist key quality... ");
+    let zob = &*search::ZOBRIST;
+    let mut keys: Vec<u64> = zob.piece_keys.iter().flatten().flatten().copied().collect();
+    keys.push(zob.turn_key);
+    keys.extend(zob.castling_keys);
+    keys.extend(zob.ep_keys);
+    keys.extend(zob.unmoved_pawn_keys.iter().flatten());
+    let distinct: std::collections::HashSet<u64> = keys.iter().copied().collect();
+    assert!(distinct.len() == keys.len() && !distinct.contains(&0));
+    fn walk(board: &mut Board, depth: u32, seen: &mut std::collections::HashMap<u64, Board>) {
+        let mut fresh = board.clone();
+        compute_zobrist(&mut fresh);
+        let earlier = seen.entry(fresh.zobrist_hash).or_insert_with(|| board.clone());
+        assert!(positions_equal_ignoring_clocks(earlier, board),
+            "hash collision: {} and {}", earlier.get_fen(), board.get_fen());
+        if depth == 0 { return; }
+        for mv in generate_moves(board, true, false) {
+            let undo = movegen::make_move(board, mv);
+            walk(board, depth - 1, seen);
+            movegen::unmake_move(board, mv, &undo);
+        }
+    }
+    let mut seen = std::collections::HashMap::new();
+    for fen in fens {
+        walk(&mut Board::from_fen(fen), 3, &mut seen);
+    }
+    println!("OK ({} keys, {} positions)", keys.len(), seen.len());
+
+    // Test 63: An en passant square only changes the position key when the
+    // capture is possible
+    print!("Test 63: Repetition key and en passant... ");
+    let key = |fen: &str| {
+        let mut board = Board::from_fen(fen);
+        compute_zobrist(&mut board);
+        board.position_key()
+    };
+    assert_eq!(key("rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq e3 0 1"),
+               key("rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - 0 1"));
+    assert_ne!(key("rnbqkbnr/ppp1pppp/8/8/3pP3/8/PPPP1PPP/RNBQKBNR b KQkq e3 0 1"),
+               key("rnbqkbnr/ppp1pppp/8/8/3pP3/8/PPPP1PPP/RNBQKBNR b KQkq - 0 1"));
+    // A pawn in a stack can capture too
+    assert_ne!(key("rnbqkbnr/ppp1pppp/8/8/3(pn)P3/8/PPPP1PPP/RNBQKBNR b KQkq e3 0 1"),
+               key("rnbqkbnr/ppp1pppp/8/8/3(pn)P3/8/PPPP1PPP/RNBQKBNR b KQkq - 0 1"));
+    let mut board = Board::startpos();
+    compute_zobrist(&mut board);
+    let mv = find_move(&mut board, "e2e4");
+    movegen::make_move(&mut board, mv);
+    assert_eq!(board.position_key(), key("rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - 0 1"));
+    // Editing a square next to the pushed pawn makes the capture possible
+    board.put_piece(types::parse_square("d4"), types::B_PAWN);
+    let mut fresh = board.clone();
+    compute_zobrist(&mut fresh);
+    assert!(board.ep_capturable() && board.position_key() == fresh.position_key());
+    println!("OK");
+
+    // Test 64: Piece counts look inside stacks
+    print!("Test 64: Piece counts... ");
+    let counts = Board::from_fen(fens[1]).piece_counts();
+    assert_eq!(counts.pieces[types::WHITE as usize], [0, 8, 2, 1, 2, 1, 1]);
+    assert_eq!(counts.pieces[types::BLACK as usize], [0, 6, 1, 1, 2, 1, 1]);
+    assert_eq!((counts.total(types::WHITE), counts.total(types::BLACK), counts.stacked_pieces), (15, 12, 4));
+    let counts = Board::startpos().piece_counts();
+    assert_eq!((counts.get(types::BLACK, types::KNIGHT), counts.stacked_pieces), (2, 0));
+    assert_eq!(evaluate::game_phase(&Board::startpos()), evaluate::MAX_PHASE);
+    assert!(movegen::is_insufficient_material(&Board::from_fen("4k3/8/8/8/8/8/8/N3K3 w - - 0 1")));
+    assert!(!movegen::is_insufficient_material(&Board::from_fen("4k3/8/8/8/8/8/8/(NB)3K3 w - - 0 1")));
+    assert!(!movegen::is_insufficient_material(&Board::from_fen("4k3/4p3/8/8/8/8/8/4K3 w - - 0 1")));
+    println!("OK");
+
+    // Test 65: The JSON board view lists stacks bottom to top
+    print!("Test 65: Board view... ");
+    let view = board::BoardView::from(&Board::from_fen(fens[1]));
+    assert_eq!(view.squares.len(), 64);
+    assert_eq!(view.squares[types::parse_square("d5") as usize], vec!['P', 'N']);
+    assert_eq!(view.squares[types::parse_square("f3") as usize], vec!['B', 'Q']);
+    assert!(view.squares[types::parse_square("e4") as usize].is_empty());
+    assert_eq!(view.king_sq, [Some("e1".to_string()), Some("e8".to_string())]);
+    assert_eq!((view.turn, view.castling.as_str(), view.ep.as_deref()), ("w", "KQkq", None));
+    let json = serde_json::json!(view);
+    assert_eq!(json["kingSq"][1], "e8");
+    assert_eq!(json["fen"], fens[1]);
+    let wasm: serde_json::Value = serde_json::from_str(&klikschaak_engine::wasm_board(fens[1])).unwrap();
+    assert!(wasm["squares"] == json["squares"] && wasm["error"].is_null());
+    let wasm: serde_json::Value = serde_json::from_str(&klikschaak_engine::wasm_board("8/8 w")).unwrap();
+    assert!(wasm["error"].is_string());
+    println!("OK");
+
+    // Test 66: Moves from one square cover every stack variant
+    print!("Test 66: Moves from a square... ");
+    let moves_from = |fen: &str, square: &str| -> serde_json::Value {
+        serde_json::from_str(&klikschaak_engine::wasm_moves_from(fen, square)).unwrap()
+    };
+    let mut board = Board::from_fen(fens[1]);
+    let expected: Vec<String> = generate_moves(&mut board, true, false).iter()
+        .filter(|m| types::square_name(m.from_sq) == "d5")
+        .map(|m| m.to_uci())
+        .collect();
+    let resp = moves_from(fens[1], "d5");
+    let listed = resp["moves"].as_array().unwrap();
+    assert_eq!(listed.iter().map(|m| m["uci"].as_str().unwrap()).collect::<Vec<_>>(), expected);
+    assert!(listed.iter().any(|m| m["type"] == "UNKLIK") && listed.iter().any(|m| m["type"] == "UNKLIK_KLIK"));
+    assert!(listed.iter().all(|m| m["uci"].as_str().unwrap()[2..4] == *m["to"].as_str().unwrap()));
+    assert_eq!(moves_from(fens[1], "e4")["count"], 0);
+    assert!(moves_from(fens[1], "z9")["error"].is_string() && moves_from(fens[1], "e22")["error"].is_string());
+    println!("OK ({} moves from d5)", expected.len());
+
+    // Test 67: A pawn promoting out of a stack from either index leaves its
+    // companion behind and lands alone
+    print!("Test 67: Promotion out of a stack... ");
+    let cases = [
+        ("k7/4(PN)3/8/8/8/8/8/4K3 w - - 0 1", "e7", 0, "N"),
+        ("k7/4(NP)3/8/8/8/8/8/4K3 w - - 0 1", "e7", 1, "N"),
+        ("3rk3/2(RP)5/8/8/8/8/8/4K3 w - - 0 1", "c7", 1, "R"),
+        ("4k3/8/8/8/8/8/1(pb)6/4K3 b - - 0 1", "b2", 0, "b"),
+        // Only the top of two identical pieces unkliks
+        ("4k3/8/8/8/8/8/1(pp)6/4K3 b - - 0 1", "b2", 1, "p"),
+    ];
+    let mut promotions = 0;
+    for (fen, from, index, companion) in cases {
+        let mut board = Board::from_fen(fen);
+        compute_zobrist(&mut board);
+        let from_sq = types::parse_square(from);
+        let moves: Vec<types::Move> = generate_moves(&mut board, true, false).into_iter()
+            .filter(|m| m.from_sq == from_sq && m.promotion != types::NONE && m.unklik_index == index)
+            .collect();
+        assert!(!moves.is_empty(), "no promotion from index {} in {}", index, fen);
+        for mv in moves {
+            let before = board.get_fen();
+            let undo = movegen::make_move(&mut board, mv);
+            let origin = board.squares[from_sq as usize];
+            let landed = board.squares[mv.to_sq as usize];
+            assert_eq!((origin.count, types::piece_char(origin.top()).to_string()), (1, companion.to_string()),
+                "{} in {}", mv.to_uci(), before);
+            assert_eq!((landed.count, types::piece_type(landed.top())), (1, mv.promotion),
+                "{} in {}", mv.to_uci(), before);
+            let mut fresh = board.clone();
+            compute_zobrist(&mut fresh);
+            assert_eq!(board.zobrist_hash, fresh.zobrist_hash);
+            movegen::unmake_move(&mut board, mv, &undo);
+            assert_eq!(board.get_fen(), before);
+            promotions += 1;
+        }
+    }
+    println!("OK ({} promotions)", promotions);
+
+    // Test 68: Verbosity only affects printing: results match, and a
+    // callback still hears every iteration when silenced
+    print!("Test 68: Search verbosity... ");
+    let mut results = Vec::new();
+    for level in [0, 1] {
+        let mut engine = search::SearchEngine::new();
+        engine.set_verbosity(level);
+        let (mv, info) = engine.search(&mut Board::startpos(), 4, None);
+        results.push((mv, info.nodes, info.score));
+    }
+    assert_eq!(results[0], results[1]);
+    let iterations = std::sync::Arc::new(std::sync::atomic::AtomicU32::new(0));
+    let counter = std::sync::Arc::clone(&iterations);
+    let mut engine = search::SearchEngine::new();
+    engine.set_verbosity(0);
+    engine.set_info_callback(Box::new(move |_| { counter.fetch_add(1, std::sync::atomic::Ordering::Relaxed); }));
+    engine.search(&mut Board::startpos(), 4, None);
+    assert_eq!(iterations.load(std::sync::atomic::Ordering::Relaxed), 4);
+    println!("OK");
+
+    // Test 69: A PVS re-search that beats alpha gets the full window, so
+    // the PV reaches the full depth. A one-entry TT keeps the TT line from
+    // patching up a truncated PV.
+    print!("Test 69: PVS re-search keeps the full PV... ");
+    for (fen, depth) in [
+        ("r1bqkbr1/1ppppppp/2n3n1/p7/2P3P1/N7/PP1PPPBP/R1(BQ)1K1NR w KQq - 2 6", 6),
+        ("rnb1kb1r/ppp1pp1p/5np1/2(pq)4B/2P2P2/8/PPPNP1PP/R1BQK1NR w KQkq - 0 7", 4),
+    ] {
+        let board = Board::from_fen(fen);
+        let mut engine = search::SearchEngine::with_tt(std::sync::Arc::new(search::TranspositionTable::new(1)));
+        engine.set_verbosity(0);
+        let (_, info) = engine.search(&mut board.clone(), depth, None);
+        assert_eq!(info.pv.len(), depth as usize, "PV {:?} cut short in {}",
+            info.pv.iter().map(|m| m.to_uci()).collect::<Vec<_>>(), fen);
+        let mut b = board.clone();
+        for mv in &info.pv {
+            assert!(generate_moves(&mut b, true, false).contains(mv), "PV move {} illegal in {}", mv.to_uci(), b.get_fen());
+            movegen::make_move(&mut b, *mv);
+        }
+    }
+    println!("OK");
+
+    // Test 70: A side left with only stacks near the promotion rank keeps
+    // its unklik and unklik-klik moves; a pawn only loses the klik onto the
+    // promotion rank itself. The flipped positions give the same moves.
+    print!("Test 70: Stacks near the promotion rank... ");
+    let moves_of = |fen: &str| -> Vec<String> {
+        let mut board = Board::from_fen(fen);
+        let mut moves: Vec<String> = generate_moves(&mut board, true, false).iter()
+            .map(|m| m.to_uci_verbose(&board)).collect();
+        moves.sort();
+        moves
+    };
+    // White's king on a1 is boxed in by the black king and pawn
+    let cases: [(&str, &[&str]); 5] = [
+        // Both pawns promote: the top one alone, or the stack together
+        ("1r6/(PP)7/8/8/8/1p6/2k5/K7 w - - 0 1", &[
+            "a7a8b", "a7a8buP", "a7a8n", "a7a8nuP", "a7a8q", "a7a8quP", "a7a8r", "a7a8ruP",
+            "a7b8b", "a7b8buP", "a7b8n", "a7b8nuP", "a7b8q", "a7b8quP", "a7b8r", "a7b8ruP"]),
+        // Blocked and nothing to capture: a real stalemate
+        ("r7/(PP)7/8/8/8/1p6/2k5/K7 w - - 0 1", &[]),
+        // No klik onto the knight on a8, but the knight leaves as usual
+        ("N7/(NP)7/8/8/8/1p6/2k5/K7 w - - 0 1", &[
+            "a7b5", "a7b5uN", "a7c6", "a7c6uN", "a7c8uN", "a8b6", "a8c7"]),
+        // One rank further back the pawn kliks onto the knight
+        ("8/N7/(NP)7/8/8/1p6/2k5/K7 w - - 0 1", &[
+            "a6a7UP", "a6b4", "a6b4uN", "a6b8uN", "a6c5", "a6c5uN", "a6c7", "a6c7uN",
+            "a7b5", "a7c6", "a7c8"]),
+        // A knight kliks onto the promotion rank, the stack can't go there
+        ("3Q4/1(NP)6/8/8/8/1p6/2k5/K7 w - - 0 1", &[
+            "b7a5", "b7a5uN", "b7b8b", "b7b8buP", "b7b8n", "b7b8nuP", "b7b8q", "b7b8quP",
+            "b7b8r", "b7b8ruP", "b7c5", "b7c5uN", "b7d6", "b7d6uN", "b7d8UN",
+            "d8a5", "d8a8", "d8b6", "d8b8", "d8c7", "d8c8", "d8d1", "d8d2", "d8d3", "d8d4",
+            "d8d5", "d8d6", "d8d7", "d8e7", "d8e8", "d8f6", "d8f8", "d8g5", "d8g8", "d8h4", "d8h8"]),
+    ];
+    for (fen, expected) in cases {
+        assert_eq!(moves_of(fen), expected.to_vec(), "moves in {}", fen);
+        let mut board = Board::from_fen(fen);
+        assert_eq!(movegen::is_stalemate(&mut board), expected.is_empty(), "stalemate in {}", fen);
+        let mut flipped = Board::from_fen(fen).flip();
+        assert_eq!(generate_moves(&mut flipped, true, false).len(), expected.len(), "flipped {}", fen);
+    }
+    println!("OK");
+
+    // Test 71: Building a position piece by piece matches its FEN, with
+    // king squares and unmoved pawns following along
+    print!("Test 71: Piece-by-piece position building... ");
+    let mut board = Board::new();
+    board.clear();
+    let sq = types::parse_square;
+    assert!(board.add_piece(sq("e1"), types::W_KING));
+    assert!(board.add_piece(sq("e8"), types::B_KING));
+    assert!(board.add_piece(sq("d2"), types::W_PAWN));
+    assert!(board.add_piece(sq("d2"), types::W_KNIGHT));
+    assert!(!board.add_piece(sq("d2"), types::W_BISHOP), "third piece added to a stack");
+    assert!(board.add_piece(sq("c7"), types::B_ROOK));
+    assert!(board.add_piece(sq("c7"), types::B_PAWN));
+    assert!(board.add_piece(sq("a4"), types::W_QUEEN));
+    assert_eq!(board.remove_piece(sq("c7"), 0), types::B_ROOK);
+    assert_eq!(board.remove_piece(sq("c7"), 1), types::NO_PIECE, "removed past the top");
+    board.clear_square(sq("a4"));
+    assert_eq!(board.get_fen(), "4k3/2p5/8/8/8/8/3(PN)4/4K3 w - - 0 1");
+    let mut fresh = Board::from_fen(&board.get_fen());
+    compute_zobrist(&mut fresh);
+    assert_eq!(board.zobrist_hash, fresh.zobrist_hash);
+    assert_eq!(board.unmoved_pawns, fresh.unmoved_pawns);
+    assert_eq!(board.king_sq, [sq("e1"), sq("e8")]);
+    assert_eq!(board.remove_piece(sq("e8"), 0), types::B_KING);
+    assert_eq!(board.king_sq[types::BLACK as usize], types::SQ_NONE);
+    assert_eq!(board.remove_piece(sq("d2"), 0), types::W_PAWN);
+    assert_eq!(board.unmoved_pawns[types::WHITE as usize] & (1 << 3), 0, "d2 pawn still marked unmoved");
+    assert_eq!(board.stack_at(sq("d2")).top(), types::W_KNIGHT);
+    println!("OK");
+
+    // Test 72: get_fen writes the en passant square only when it can be
+    // taken (a stacked pawn counts), get_fen_raw whenever it is set
+    print!("Test 72: En passant square in FEN... ");
+    let mut board = Board::startpos();
+    let mv = find_move(&mut board, "e2e4");
+    movegen::make_move(&mut board, mv);
+    assert_eq!(board.get_fen(), "rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - 0 1");
+    assert_eq!(board.get_fen_raw(), "rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq e3 0 1");
+    assert_eq!(Board::from_fen(&board.get_fen_raw()).ep_square, board.ep_square);
+    let mut strict = Board::from_fen(&board.get_fen());
+    compute_zobrist(&mut strict);
+    compute_zobrist(&mut board);
+    assert_eq!(strict.zobrist_hash, board.zobrist_hash, "dropping a dead ep square changed the key");
+    assert!(positions_equal_ignoring_clocks(&strict, &board));
+    let mut board = Board::from_fen("4k3/2p5/8/1(NP)6/8/8/8/4K3 b - - 0 1");
+    let mv = find_move(&mut board, "c7c5");
+    movegen::make_move(&mut board, mv);
+    assert_eq!(board.get_fen(), "4k3/8/8/1(NP)p5/8/8/8/4K3 w - c6 0 2");
+    assert_eq!(board.get_fen(), board.get_fen_raw());
+    println!("OK");
+
+    // Test 73: SquareStack fills up to MAX_STACK, and removing a piece
+    // drops the ones above it
+    print!("Test 73: Stack height limit... ");
+    let mut stack = types::SquareStack::empty();
+    for i in 0..types::MAX_STACK + 1 {
+        assert_eq!(stack.is_full(), i >= types::MAX_STACK);
+        stack.add(types::W_KNIGHT + (i as u8 % 4));
+    }
+    assert_eq!(stack.count as usize, types::MAX_STACK);
+    assert!(stack.has_stack());
+    assert_eq!(stack.remove_at(0), types::W_KNIGHT);
+    assert_eq!(stack.bottom(), types::W_BISHOP);
+    assert_eq!(stack.pieces[stack.count as usize], types::NO_PIECE);
+    assert_eq!(format!("{:?}", types::SquareStack::double(types::W_PAWN, types::W_ROOK)), "[P, R]");
+    assert_eq!(search::ZOBRIST.piece_keys[types::W_PAWN as usize].len(), types::MAX_STACK);
+    println!("OK");
+
+    // Test 74: With the TT off the search leaves no entries behind and
+    // still finds the same moves
+    print!("Test 74: Search without the transposition table... ");
+    for (fen, expected) in [
+        ("4k3/8/4K3/8/8/8/8/7R w - - 0 1", "h1h8"),
+        ("4k3/8/8/1q1N4/8/8/8/4K3 w - - 0 1", "d5c7"),
+    ] {
+        let mut board = Board::from_fen(fen);
+        compute_zobrist(&mut board);
+        let mut engine = search::SearchEngine::new();
+        engine.set_use_tt(false);
+        engine.set_verbosity(0);
+        let (mv, _) = engine.search(&mut board.clone(), 5, None);
+        assert_eq!(mv.map(|m| m.to_uci()).as_deref(), Some(expected), "without TT in {}", fen);
+        assert!(engine.extract_pv(&mut board, 10).is_empty(), "TT written with use_tt off");
+    }
+    println!("OK");
+
+    // Test 75: A capture that loses material to a recapture is ordered
+    // after one that doesn't, even with the bigger victim
+    print!("Test 75: Losing captures ordered last... ");
+    let mut board = Board::from_fen("4k3/3r4/8/1p6/8/2N5/8/3Q2K1 w - - 0 1");
+    compute_zobrist(&mut board);
+    let captures = generate_moves(&mut board, false, true);
+    let engine = search::SearchEngine::new();
+    let ordered: Vec<String> = engine.order_moves(&board, &captures, 1, None, None).iter()
+        .map(|m| m.to_uci()).collect();
+    assert_eq!(ordered, ["c3b5", "d1d7"]);
+    println!("OK");
+
+    // Test 76: Boards work as HashMap keys: transpositions meet, a dead en
+    // passant square and a FEN round trip don't tell boards apart, the
+    // move counters do
+    print!("Test 76: Board equality and hashing... ");
+    let play = |ucis: &[&str]| {
+        let mut board = Board::startpos();
+        for uci in ucis {
+            let mv = find_move(&mut board, uci);
+            movegen::make_move(&mut board, mv);
+        }
+        board
+    };
+    let a = play(&["g1f3", "g8f6", "b1c3", "b8c6"]);
+    let b = play(&["b1c3", "b8c6", "g1f3", "g8f6"]);
+    let c = play(&["e2e4"]);
+    let mut seen = std::collections::HashMap::new();
+    for board in [&a, &b, &c, &Board::from_fen(&c.get_fen()), &Board::from_fen(&c.get_fen_raw())] {
+        *seen.entry(board.clone()).or_insert(0) += 1;
+    }
+    assert_eq!(seen.len(), 2);
+    assert_eq!(seen[&a], 2);
+    assert_eq!(seen[&c], 3);
+    let mut later = a.clone();
+    later.fullmove += 1;
+    assert_ne!(later, a);
+    assert_eq!(Board::from_fen(STARTING_FEN).zobrist_hash, {
+        let mut board = Board::from_fen(STARTING_FEN);
+        compute_zobrist(&mut board);
+        board.zobrist_hash
+    }, "from_fen left the hash stale");
+    println!("OK");
+
+    // Test 77: attackers_to lists every attacker, both pieces of a stack
+    // included, and agrees with is_attacked everywhere
+    print!("Test 77: Attackers of a square... ");
+    let board = Board::from_fen("k2r3B/8/8/2K2N2/8/4P3/8/B2(QR)4 w - - 0 1");
+    let d4 = types::parse_square("d4");
+    let mut attackers: Vec<String> = movegen::attackers_to(&board, d4, types::WHITE).iter()
+        .map(|&(sq, piece)| format!("{}{}", types::piece_char(piece), types::square_name(sq)))
+        .collect();
+    attackers.sort();
+    assert_eq!(attackers, ["Ba1", "Bh8", "Kc5", "Nf5", "Pe3", "Qd1", "Rd1"]);
+    assert_eq!(movegen::attackers_to(&board, d4, types::BLACK), [(types::parse_square("d8"), types::B_ROOK)]);
+    for fen in fens {
+        let board = Board::from_fen(fen);
+        for sq in 0..64 {
+            for color in [types::WHITE, types::BLACK] {
+                assert_eq!(movegen::is_attacked(&board, sq, color), !movegen::attackers_to(&board, sq, color).is_empty());
+            }
+        }
+    }
+    println!("OK");
+
+    // Test 78: The pin shortcut in the legality filter keeps exactly the
+    // moves that make/unmake accepts, along random games from pinned,
+    // stacked and en passant positions
+    print!("Test 78: Legal moves with pin detection... ");
+    let mut rng = Rng::new(78);
+    let mut checked = 0;
+    for fen in [
+        STARTING_FEN,
+        fens[1],
+        "4k3/8/8/3(PQ)p3/8/8/8/4K3 w - e6 0 1",
+        "4k3/4r3/8/b7/8/2(NB)5/4R3/4K3 w - - 0 1",
+        "8/8/8/KP1(pr)3k/8/8/8/8 w - - 0 1",
+    ] {
+        for game in 0..20 {
+            let mut board = Board::from_fen(fen);
+            for _ in 0..(10 + game) {
+                let mut legal = generate_moves(&mut board, true, false);
+                let mut brute: Vec<types::Move> = generate_moves(&mut board, false, false).into_iter().filter(|&mv| {
+                    let undo = movegen::make_move(&mut board, mv);
+                    let ok = !movegen::is_in_check(&board, types::opposite_color(board.turn));
+                    movegen::unmake_move(&mut board, mv, &undo);
+                    ok
+                }).collect();
+                legal.sort_by_key(|m| m.key());
+                brute.sort_by_key(|m| m.key());
+                assert_eq!(legal, brute, "legal moves differ in {}", board.get_fen());
+                checked += 1;
+                if legal.is_empty() { break; }
+                movegen::make_move(&mut board, legal[rng.below(legal.len() as u64) as usize]);
+            }
+        }
+    }
+    println!("OK ({} positions)", checked);
+
+    // Test 79: The server starts in-process, answers, and returns once the
+    // shutdown flag is set, freeing its port
+    print!("Test 79: Server shutdown... ");
+    let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let addr = format!("127.0.0.1:{}", port);
+    let shutdown = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+    let server = {
+        let (addr, shutdown) = (addr.clone(), std::sync::Arc::clone(&shutdown));
+        std::thread::spawn(move || api::run_server_with_shutdown(&addr, shutdown))
+    };
+    let get_health = || -> Option<String> {
+        use std::io::{Read, Write};
+        let mut stream = std::net::TcpStream::connect(&addr).ok()?;
+        stream.write_all(b"GET /health HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").ok()?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).ok()?;
+        Some(response)
+    };
+    let response = (0..50).find_map(|_| {
+        get_health().or_else(|| { std::thread::sleep(std::time::Duration::from_millis(20)); None })
+    }).expect("server never answered");
+    assert!(response.starts_with("HTTP/1.1 200") && response.ends_with(r#"{"status":"ok"}"#), "{}", response);
+    shutdown.store(true, std::sync::atomic::Ordering::Relaxed);
+    server.join().unwrap().unwrap();
+    std::net::TcpListener::bind(&addr).expect("port still taken after shutdown");
+    println!("OK");
+
+    // Test 80: Several requests share one connection, including pipelined
+    // ones, until the client asks to close it
+    print!("Test 80: Server keep-alive... ");
+    {
+        use std::io::{BufRead, BufReader, Read, Write};
+        let (mut stream, shutdown, server) = connect_test_server();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut read_response = || -> String {
+            let mut content_length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line.trim().is_empty() { break; }
+                if let Some(len) = line.to_lowercase().strip_prefix("content-length:") {
+                    content_length = len.trim().parse().unwrap();
+                }
+            }
+            let mut body = vec![0u8; content_length];
+            reader.read_exact(&mut body).unwrap();
+            String::from_utf8(body).unwrap()
+        };
+
+        stream.write_all(b"GET /health HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        assert_eq!(read_response(), r#"{"status":"ok"}"#);
+        let moves = serde_json::json!({"fen": STARTING_FEN}).to_string();
+        let pipelined = format!(
+            "POST /moves HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}GET /health HTTP/1.1\r\nConnection: close\r\n\r\n",
+            moves.len(), moves);
+        stream.write_all(pipelined.as_bytes()).unwrap();
+        let response: serde_json::Value = serde_json::from_str(&read_response()).unwrap();
+        assert_eq!(response["count"], 34, "{}", response);
+        assert_eq!(read_response(), r#"{"status":"ok"}"#);
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).unwrap();
+        assert!(rest.is_empty(), "connection still open after Connection: close");
+
+        shutdown.store(true, std::sync::atomic::Ordering::Relaxed);
+        server.join().unwrap().unwrap();
+    }
+    println!("OK");
+
+    // Test 81: Oversized bodies get 413 without being allocated, and an
+    // oversized header ends the connection
+    print!("Test 81: Server request limits... ");
+    {
+        use std::io::{Read, Write};
+        let (mut stream, shutdown, server) = connect_test_server();
+        stream.write_all(b"POST /moves HTTP/1.1\r\nContent-Length: 4000000000\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 413"), "{}", response);
+
+        let (mut stream, shutdown2, server2) = connect_test_server();
+        let header = format!("GET /health HTTP/1.1\r\nX-Padding: {}\r\n\r\n", "a".repeat(16 * 1024));
+        let _ = stream.write_all(header.as_bytes());
+        let mut response = String::new();
+        let _ = stream.read_to_string(&mut response);
+        assert!(response.is_empty(), "{}", response);
+
+        for (shutdown, server) in [(shutdown, server), (shutdown2, server2)] {
+            shutdown.store(true, std::sync::atomic::Ordering::Relaxed);
+            server.join().unwrap().unwrap();
+        }
+    }
+    println!("OK");
+
+    // Test 82: GET /moves and /eval take their fields from the query string
+    print!("Test 82: Server GET with query... ");
+    {
+        let get = |target: &str| test_request(&format!("GET {} HTTP/1.1\r\n\r\n", target));
+        let fen = "rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq e3 0 1";
+        let moves = get(&format!("/moves?fen={}", fen.replace(' ', "%20").replace('/', "%2F")));
+        assert_eq!(moves["count"], 34, "{}", moves);
+        assert!(moves["moves"].as_array().unwrap().iter().all(|m| m["uci"].as_str().unwrap().as_bytes()[1] >= b'7'));
+        let eval = get(&format!("/eval?fen={}&depth=2", fen.replace(' ', "+")));
+        assert_eq!(eval["depth"], 2, "{}", eval);
+        assert!(eval["bestMove"].is_string(), "{}", eval);
+    }
+    println!("OK");
+
+    // Test 83: /bestmove answers with the move and the PV's reply only,
+    // and with nulls when the side to move is mated
+    print!("Test 83: Server bestmove... ");
+    {
+        let post = |body: serde_json::Value| {
+            let body = body.to_string();
+            test_request(&format!("POST /bestmove HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}", body.len(), body))
+        };
+        let reply = post(serde_json::json!({"fen": STARTING_FEN, "depth": 3}));
+        assert_eq!(reply.as_object().unwrap().len(), 2, "{}", reply);
+        let mut board = Board::from_fen(STARTING_FEN);
+        let best = find_move(&mut board, reply["bestmove"].as_str().unwrap());
+        movegen::make_move(&mut board, best);
+        find_move(&mut board, reply["ponder"].as_str().unwrap());
+        let mated = post(serde_json::json!({"fen": "7k/6Q1/6K1/8/8/8/8/8 b - - 0 1", "movetime_ms": 50}));
+        assert_eq!(mated, serde_json::json!({"bestmove": null, "ponder": null}));
+    }
+    println!("OK");
+
+    // Test 84: Board::from_moves replays a move list, klik and unklik
+    // moves included, and names the first illegal move
+    print!("Test 84: Board from moves... ");
+    {
+        let moves = ["g1f3", "g8f6", "b1d2k", "b8d7k", "d2c4u1", "d7b6u1"];
+        let board = Board::from_moves("startpos", &moves).unwrap();
+        let mut replayed = Board::startpos();
+        for uci in moves {
+            let mv = find_move(&mut replayed, uci);
+            movegen::make_move(&mut replayed, mv);
+        }
+        assert_eq!(board, replayed);
+        let mut fresh = board.clone();
+        compute_zobrist(&mut fresh);
+        assert_eq!(board.zobrist_hash, fresh.zobrist_hash);
+        assert_eq!(Board::from_moves(STARTING_FEN, &moves), Ok(board));
+
+        let (index, reason) = Board::from_moves("startpos", &["e2e4", "e7e5", "e4e5"]).unwrap_err();
+        assert_eq!(index, 2, "{}", reason);
+        assert_eq!(Board::from_moves("8/8/8 w - - 0 1", &[]).unwrap_err().0, 0);
+    }
+    println!("OK");
+
+    // Test 85: Even without contempt, a winning side doesn't step into a
+    // position seen before in the game, since one repetition scores a draw
+    print!("Test 85: KQ vs K avoids repetition... ");
+    {
+        let fen = "8/8/8/4k3/8/8/8/3QK3 w - - 10 39";
+        let search = |history: &[u64]| {
+            let mut engine = search::SearchEngine::new();
+            engine.set_info_callback(Box::new(|_| {}));
+            engine.set_game_history(history);
+            let (best_move, info) = engine.search(&mut Board::from_fen(fen), 6, None);
+            (best_move.map(|m| m.to_uci()), info.score)
+        };
+        let (fresh_move, _) = search(&[]);
+        assert_eq!(fresh_move.as_deref(), Some("e1e2"));
+
+        // The game reached e1e2's position before: Ke2 Kf5 Ke1 Ke5
+        let mut board = Board::from_fen("8/8/8/4k3/8/8/4K3/3Q4 b - - 7 37");
+        let mut history = Vec::new();
+        for uci in ["e5f5", "e2e1", "f5e5"] {
+            let mv = find_move(&mut board, uci);
+            history.push(board.zobrist_hash);
+            movegen::make_move(&mut board, mv);
+        }
+        assert_eq!(board.get_fen(), fen);
+        let (best_move, score) = search(&history);
+        assert_ne!(best_move.as_deref(), Some("e1e2"), "repeats into a draw");
+        assert!(score > 500, "gave up the win: {}", score);
+    }
+    println!("OK");
+
+    // Test 86: In the endgame a king near its passer's path beats one far
+    // from it, for both colours; with all pieces on, the term is gone
+    print!("Test 86: King distance to passed pawns... ");
+    {
+        let params = evaluate::EvalParams::default();
+        let passed = |fen: &str, params: &evaluate::EvalParams| {
+            evaluate::evaluate_trace(&Board::from_fen(fen), params).passed_pawns
+        };
+        let escorted = passed("8/8/8/8/2k5/3K4/3P4/8 w - - 0 1", &params);
+        let behind = passed("8/8/8/8/2k5/8/3P4/K7 w - - 0 1", &params);
+        assert_eq!(escorted - behind, 3 * params.passed_pawn_king_distance);
+        assert_eq!(passed("8/3p4/3k4/2K5/8/8/8/8 b - - 0 1", &params), -escorted);
+
+        let opening = "rnbqkbnr/pp3ppp/8/8/3P4/8/PPP1PPPP/RNBQKBNR b KQkq - 0 1";
+        let no_escort = evaluate::EvalParams { passed_pawn_king_distance: 0, ..params.clone() };
+        assert!(passed(opening, &params) > 0);
+        assert_eq!(passed(opening, &params), passed(opening, &no_escort));
+    }
+    println!("OK");
+
+    // Test 87: count_legal_moves and has_legal_move agree with the legal
+    // move list, in check, stacked, mated and stalemated positions
+    print!("Test 87: Legal move count... ");
+    {
+        let mut rng = Rng::new(87);
+        let mut checked = 0;
+        for fen in [
+            STARTING_FEN,
+            "4k3/4r3/8/b7/8/2(NB)5/4R3/4K3 w - - 0 1",
+            "4k3/8/8/8/1b6/8/3(PN)4/4K3 w - - 0 1",
+            "7k/6Q1/6K1/8/8/8/8/8 b - - 0 1",
+            "7k/8/6QK/8/8/8/8/8 b - - 0 1",
+        ] {
+            let mut board = Board::from_fen(fen);
+            for _ in 0..30 {
+                let legal = generate_moves(&mut board, true, false);
+                assert_eq!(movegen::count_legal_moves(&mut board), legal.len(), "{}", board.get_fen());
+                assert_eq!(movegen::has_legal_move(&mut board), !legal.is_empty(), "{}", board.get_fen());
+                checked += 1;
+                if legal.is_empty() { break; }
+                movegen::make_move(&mut board, legal[rng.below(legal.len() as u64) as usize]);
+            }
+        }
+        assert!(movegen::is_checkmate(&mut Board::from_fen("7k/6Q1/6K1/8/8/8/8/8 b - - 0 1")));
+        assert!(movegen::is_stalemate(&mut Board::from_fen("7k/8/6QK/8/8/8/8/8 b - - 0 1")));
+        println!("OK ({} positions)", checked);
+    }
+
+    // Test 88: SearchInfo reports cp and mate scores in one place and
+    // serializes with its moves and game end
+    print!("Test 88: Search info score report... ");
+    {
+        let mut info = search::SearchInfo { score: 137, ..search::SearchInfo::new() };
+        assert_eq!(info.score_report(), (137, "cp"));
+        assert_eq!(info.score_report_rounded(10), (140, "cp"));
+        info.score = evaluate::CHECKMATE_SCORE - 3;
+        assert_eq!(info.score_report_rounded(10), (2, "mate"));
+        info.score = -(evaluate::CHECKMATE_SCORE - 4);
+        assert_eq!(info.score_report(), (-2, "mate"));
+
+        let mut engine = search::SearchEngine::new();
+        engine.set_info_callback(Box::new(|_| {}));
+        let (_, info) = engine.search(&mut Board::from_fen("7k/6Q1/6K1/8/8/8/8/8 b - - 0 1"), 3, None);
+        let json = serde_json::to_value(&info).unwrap();
+        assert_eq!(json["terminal"], "checkmate");
+        assert_eq!(json["score"], evaluate::CHECKMATE_SCORE);
+        let (_, info) = engine.search(&mut Board::startpos(), 2, None);
+        let json = serde_json::to_value(&info).unwrap();
+        assert_eq!(json["pv"][0]["from_sq"], info.pv[0].from_sq);
+        assert!(json["terminal"].is_null());
+    }
+    println!("OK");
+
+    // Test 89: verify_pv accepts a searched PV, rejects one with a move
+    // that isn't legal at its ply, and leaves the board as it was
+    print!("Test 89: PV verification... ");
+    {
+        let mut board = Board::from_fen(fens[1]);
+        compute_zobrist(&mut board);
+        let before = board.clone();
+        let mut engine = search::SearchEngine::new();
+        engine.set_info_callback(Box::new(|_| {}));
+        let (_, info) = engine.search(&mut board.clone(), 5, None);
+        assert!(info.pv.len() >= 2);
+        assert!(search::verify_pv(&mut board, &info.pv));
+        // The first move played twice: the second time it's the other side's turn
+        let repeated = [info.pv[0], info.pv[0]];
+        assert!(!search::verify_pv(&mut board, &repeated));
+        assert_eq!(board, before);
+        assert_eq!(board.zobrist_hash, before.zobrist_hash);
+    }
+    println!("OK");
+
+    // Test 90: Futility and reverse futility save nodes without changing
+    // the result, and a back-rank mate is still found with them on
+    print!("Test 90: Futility pruning... ");
+    {
+        let search = |fen: &str, config: search::FutilityConfig| {
+            let mut engine = search::SearchEngine::new();
+            engine.set_info_callback(Box::new(|_| {}));
+            engine.set_futility(config);
+            let (best, info) = engine.search(&mut Board::from_fen(fen), 7, None);
+            (best.map(|m| m.to_uci()), info.score, info.nodes)
+        };
+        let off = search::FutilityConfig { enabled: false, ..Default::default() };
+        let fen = "r1bq1rk1/pp2bppp/2n1pn2/3p4/2PP4/2N1PN2/PP2BPPP/R2QKB1R w KQ - 0 8";
+        let (on_move, _, on_nodes) = search(fen, search::FutilityConfig::default());
+        let (off_move, _, off_nodes) = search(fen, off.clone());
+        assert_eq!(on_move, off_move);
+        assert!(on_nodes < off_nodes, "{} vs {} nodes", on_nodes, off_nodes);
+
+        let mate = "2r3k1/pp3ppp/4p3/3p4/3P4/4PN2/PP3PPP/2R3K1 w - - 0 20";
+        for config in [search::FutilityConfig::default(), off] {
+            let (best, score, _) = search(mate, config);
+            assert_eq!(best.as_deref(), Some("c1c8"));
+            assert!(score >= evaluate::CHECKMATE_SCORE - search::MAX_DEPTH as i32, "{}", score);
+        }
+
+        let mut engine = search::SearchEngine::new();
+        engine.set_futility(search::FutilityConfig { max_depth: 9, ..Default::default() });
+        assert_eq!(engine.futility().max_depth, search::FUTILITY_MAX_DEPTH);
+    }
+    println!("OK");
+
+    // Test 91: The fuzzer's random games pass every invariant, and a
+    // corrupted hash is caught
+    print!("Test 91: Fuzz invariants... ");
+    let positions: u32 = (0..20).map(|seed| fuzz::fuzz_game(seed, 150).unwrap_or_else(|e| panic!("{}", e))).sum();
+    let mut board = Board::from_fen("4k3/8/8/3(PQ)p3/8/8/8/4K3 w - e6 0 1");
+    assert!(fuzz::check_position(&mut board).is_ok());
+    board.zobrist_hash ^= 1;
+    assert!(fuzz::check_position(&mut board).is_err());
+    println!("OK ({} positions)", positions);
+
+    // Test 92: The incremental hash holds through castling-klik and en
+    // passant with stacks, two levels deep. make_move checks this itself
+    // in debug builds and with the verify-zobrist feature; here it is
+    // checked in every build.
+    print!("Test 92: Incremental hash on special moves... ");
+    let mut special = 0;
+    let mut checked = 0;
+    for fen in [
+        "r3k2r/8/8/8/8/8/8/R2BKN1R w KQkq - 0 1",
+        "r2bkn1r/8/8/8/8/8/8/R3K2R b KQkq - 0 1",
+        "4k3/8/8/3(PQ)p3/8/8/8/4K3 w - e6 0 1",
+        "4k3/8/8/8/3P(pn)3/8/8/4K3 b - d3 0 1",
+    ] {
+        let mut board = Board::from_fen(fen);
+        for mv in generate_moves(&mut board, true, false) {
+            let undo = movegen::make_move(&mut board, mv);
+            for reply in generate_moves(&mut board, true, false) {
+                let reply_undo = movegen::make_move(&mut board, reply);
+                let mut fresh = board.clone();
+                compute_zobrist(&mut fresh);
+                assert_eq!(board.zobrist_hash, fresh.zobrist_hash, "after {} {} from {}", mv.to_uci(), reply.to_uci(), fen);
+                movegen::unmake_move(&mut board, reply, &reply_undo);
+                checked += 1;
+            }
+            movegen::unmake_move(&mut board, mv, &undo);
+            special += [types::MT_CASTLE_K_KLIK, types::MT_CASTLE_Q_KLIK, types::MT_EN_PASSANT]
+                .contains(&mv.move_type) as u32;
+        }
+    }
+    assert!(special >= 6, "only {} castling-klik / en passant moves", special);
+    println!("OK ({} positions, verify in make_move: {})", checked,
+        cfg!(any(debug_assertions, feature = "verify-zobrist")));
+
+    // Test 93: Castling onto a lone own piece on f1/d1 (f8/d8) stacks the
+    // rook on top of it, and unmake restores king, rook and that piece.
+    // Two pieces, an enemy piece or nothing to klik with give no castle-klik.
+    print!("Test 93: Castling-klik rook stack... ");
+    let mut castles = 0;
+    for (fen, uci, rook_to, piece) in [
+        ("4k3/8/8/8/8/8/8/4KN1R w K - 0 1", "e1g1", "f1", 'N'),
+        ("4k3/8/8/8/8/8/8/4KB1R w K - 0 1", "e1g1", "f1", 'B'),
+        ("4k3/8/8/8/8/8/8/4KQ1R w K - 0 1", "e1g1", "f1", 'Q'),
+        ("4k3/8/8/8/8/8/8/R2NK3 w Q - 0 1", "e1c1", "d1", 'N'),
+        ("4k3/8/8/8/8/8/8/R2QK3 w Q - 0 1", "e1c1", "d1", 'Q'),
+        ("4kb1r/8/8/8/8/8/8/4K3 b k - 0 1", "e8g8", "f8", 'b'),
+        ("r2qk3/8/8/8/8/8/8/4K3 b q - 0 1", "e8c8", "d8", 'q'),
+        ("4k3/8/8/8/8/8/8/4KN1(BR) w K - 0 1", "e1g1", "f1", 'N'),
+    ] {
+        let mut board = Board::from_fen(fen);
+        let before = board.clone();
+        let mv = generate_moves(&mut board, true, false).into_iter()
+            .find(|m| m.to_uci() == uci && matches!(m.move_type, types::MT_CASTLE_K_KLIK | types::MT_CASTLE_Q_KLIK))
+            .unwrap_or_else(|| panic!("no castle-klik {} in {}", uci, fen));
+        let undo = movegen::make_move(&mut board, mv);
+
+        let rook = if piece.is_ascii_uppercase() { 'R' } else { 'r' };
+        let stack = board.squares[types::parse_square(rook_to) as usize];
+        let names: Vec<char> = stack.pieces[..stack.count as usize].iter().map(|&p| types::piece_char(p)).collect();
+        assert_eq!(names, [piece, rook], "{} in {}", uci, fen);
+        let king_to = types::parse_square(&uci[2..]);
+        assert_eq!(board.king_sq[before.turn as usize], king_to);
+        assert_eq!(board.squares[king_to as usize].count, 1);
+        // The rook's old square keeps whatever shared it
+        let rook_file = if &uci[2..3] == "g" { 'h' } else { 'a' };
+        let rook_from = types::parse_square(&format!("{}{}", rook_file, &uci[3..]));
+        assert_eq!(board.squares[rook_from as usize].count, before.squares[rook_from as usize].count - 1);
+
+        movegen::unmake_move(&mut board, mv, &undo);
+        assert_eq!(board, before, "unmake after {} in {}", uci, fen);
+        assert_eq!(board.get_fen_raw(), before.get_fen_raw());
+        castles += 1;
+    }
+    for fen in [
+        "4k3/8/8/8/8/8/8/4K(NB)1R w K - 0 1",
+        "4k3/8/8/8/8/8/8/4Kn1R w K - 0 1",
+        "4k3/8/8/8/8/8/8/R2nK3 w Q - 0 1",
+        "4k3/8/8/8/8/8/8/RN1BK3 w Q - 0 1",
+    ] {
+        let mut board = Board::from_fen(fen);
+        assert!(generate_moves(&mut board, true, false).iter()
+            .all(|m| !matches!(m.move_type, types::MT_CASTLE_K | types::MT_CASTLE_Q | types::MT_CASTLE_K_KLIK | types::MT_CASTLE_Q_KLIK)),
+            "castling in {}", fen);
+    }
+    println!("OK ({} castle-kliks)", castles);
+
     println!("\n=== All tests passed! ===");
 }
+
+type TestServer = (std::net::TcpStream, std::sync::Arc<std::sync::atomic::AtomicBool>, std::thread::JoinHandle<std::io::Result<()>>);
+
+/// An in-process server on a free port and a connection to it, with the
+/// flag and thread to stop it
+fn connect_test_server() -> TestServer {
+    let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let addr = format!("127.0.0.1:{}", port);
+    let shutdown = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+    let server = {
+        let (addr, shutdown) = (addr.clone(), std::sync::Arc::clone(&shutdown));
+        std::thread::spawn(move || api::run_server_with_shutdown(&addr, shutdown))
+    };
+    let stream = (0..50).find_map(|_| {
+        std::net::TcpStream::connect(&addr).ok()
+            .or_else(|| { std::thread::sleep(std::time::Duration::from_millis(20)); None })
+    }).expect("server never answered");
+    (stream, shutdown, server)
+}
+
+/// Send one request to a fresh test server, with `Connection: close` added
+/// to its headers, and return the JSON body of its 200 response
+fn test_request(request: &str) -> serde_json::Value {
+    use std::io::{Read, Write};
+    let (mut stream, shutdown, server) = connect_test_server();
+    let request = request.replacen("\r\n\r\n", "\r\nConnection: close\r\n\r\n", 1);
+    stream.write_all(request.as_bytes()).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    shutdown.store(true, std::sync::atomic::Ordering::Relaxed);
+    server.join().unwrap().unwrap();
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    assert!(head.starts_with("HTTP/1.1 200"), "{}", response);
+    serde_json::from_str(body).unwrap()
+}
+
+fn find_move(board: &mut Board, uci: &str) -> types::Move {
+    generate_moves(board, true, false).into_iter()
+        .find(|m| m.to_uci() == uci)
+        .unwrap_or_else(|| panic!("Move {} not found in {}", uci, board.get_fen()))
+}
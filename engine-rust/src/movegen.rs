@@ -1,8 +1,8 @@
-/// Klikschaak Engine - Move Generation
+//! Klikschaak Engine - Move Generation
 
 use crate::types::*;
 use crate::board::Board;
-use crate::search::ZOBRIST;
+use crate::search::{ZOBRIST, compute_zobrist, ep_hash, unmoved_pawns_hash};
 
 // Direction offsets
 const KNIGHT_OFFSETS: [i8; 8] = [-17, -15, -10, -6, 6, 10, 15, 17];
@@ -10,12 +10,20 @@ const KING_OFFSETS: [i8; 8] = [-9, -8, -7, -1, 1, 7, 8, 9];
 const BISHOP_DIRECTIONS: [i8; 4] = [-9, -7, 7, 9];
 const ROOK_DIRECTIONS: [i8; 4] = [-8, -1, 1, 8];
 
+// Ray table directions: the four diagonals, then the four lines
+const DIAGONAL_RAYS: std::ops::Range<usize> = 0..4;
+const LINE_RAYS: std::ops::Range<usize> = 4..8;
+
 // Pre-computed move tables
 struct MoveTables {
     knight: [[u8; 8]; 64], // targets, 0xFF = end sentinel
     knight_count: [u8; 64],
     king: [[u8; 8]; 64],
     king_count: [u8; 64],
+    knight_mask: [u64; 64], // same targets as bitsets
+    king_mask: [u64; 64],
+    rays: [[[u8; 7]; 8]; 64], // squares outward from each square, per direction
+    ray_len: [[u8; 8]; 64],
 }
 
 static MOVE_TABLES: std::sync::LazyLock<MoveTables> = std::sync::LazyLock::new(|| {
@@ -24,6 +32,10 @@ static MOVE_TABLES: std::sync::LazyLock<MoveTables> = std::sync::LazyLock::new(|
         knight_count: [0; 64],
         king: [[0xFF; 8]; 64],
         king_count: [0; 64],
+        knight_mask: [0; 64],
+        king_mask: [0; 64],
+        rays: [[[0xFF; 7]; 8]; 64],
+        ray_len: [[0; 8]; 64],
     };
 
     for sq in 0..64i8 {
@@ -37,6 +49,7 @@ static MOVE_TABLES: std::sync::LazyLock<MoveTables> = std::sync::LazyLock::new(|
                 let tr = to >> 3;
                 if (f - tf).abs() <= 2 && (r - tr).abs() <= 2 {
                     tables.knight[sq as usize][ki] = to as u8;
+                    tables.knight_mask[sq as usize] |= 1 << to;
                     ki += 1;
                 }
             }
@@ -51,11 +64,27 @@ static MOVE_TABLES: std::sync::LazyLock<MoveTables> = std::sync::LazyLock::new(|
                 let tr = to >> 3;
                 if (f - tf).abs() <= 1 && (r - tr).abs() <= 1 {
                     tables.king[sq as usize][kii] = to as u8;
+                    tables.king_mask[sq as usize] |= 1 << to;
                     kii += 1;
                 }
             }
         }
         tables.king_count[sq as usize] = kii as u8;
+
+        // Rays stop before leaving the board, so walking them needs no
+        // wrap checks
+        for (d, &direction) in BISHOP_DIRECTIONS.iter().chain(&ROOK_DIRECTIONS).enumerate() {
+            let mut len = 0usize;
+            let mut current = sq;
+            loop {
+                let prev = current;
+                current += direction;
+                if !(0..64).contains(&current) || ((current & 7) - (prev & 7)).abs() > 1 { break; }
+                tables.rays[sq as usize][d][len] = current as u8;
+                len += 1;
+            }
+            tables.ray_len[sq as usize][d] = len as u8;
+        }
     }
 
     tables
@@ -71,6 +100,28 @@ fn king_targets(sq: u8) -> &'static [u8] {
     &t.king[sq as usize][..t.king_count[sq as usize] as usize]
 }
 
+/// Squares from `sq` to the board edge in ray direction `d` (see
+/// DIAGONAL_RAYS and LINE_RAYS), nearest first.
+#[inline]
+fn ray(sq: u8, d: usize) -> &'static [u8] {
+    let t = &*MOVE_TABLES;
+    &t.rays[sq as usize][d][..t.ray_len[sq as usize][d] as usize]
+}
+
+/// First occupied square along each ray from `sq`, SQ_NONE where the ray
+/// reaches the edge unblocked.
+pub fn ray_blockers(board: &Board, sq: u8) -> [u8; 8] {
+    let t = &*MOVE_TABLES;
+    let mut blockers = [SQ_NONE; 8];
+    for (d, blocker) in blockers.iter_mut().enumerate() {
+        let ray = &t.rays[sq as usize][d][..t.ray_len[sq as usize][d] as usize];
+        *blocker = ray.iter().copied()
+            .find(|&to| board.squares[to as usize].count > 0)
+            .unwrap_or(SQ_NONE);
+    }
+    blockers
+}
+
 // Undo info for make/unmake
 #[derive(Clone)]
 pub struct UndoInfo {
@@ -82,6 +133,9 @@ pub struct UndoInfo {
     pub fullmove: u16,
     pub unmoved_pawns: [u8; 2],
     pub zobrist_hash: u64,
+    pub stacked_count: u8,
+    pub material: [i32; 2],
+    pub pst: [(i32, i32); 2],
 }
 
 impl UndoInfo {
@@ -95,32 +149,49 @@ impl UndoInfo {
             fullmove: 1,
             unmoved_pawns: [0xFF, 0xFF],
             zobrist_hash: 0,
+            stacked_count: 0,
+            material: [0, 0],
+            pst: [(0, 0), (0, 0)],
         }
     }
 }
 
-fn sliding_moves(board: &Board, sq: u8, directions: &[i8]) -> Vec<u8> {
-    let mut moves = Vec::with_capacity(14);
-
-    for &direction in directions {
-        let mut current = sq as i8;
-        loop {
-            let prev = current;
-            current += direction;
-            if !(0..64).contains(&current) { break; }
-            if ((current & 7) - (prev & 7)).abs() > 1 { break; }
+/// Remove and return the lowest square of a bitset.
+pub(crate) fn pop_square(bits: &mut u64) -> u8 {
+    let sq = bits.trailing_zeros() as u8;
+    *bits &= *bits - 1;
+    sq
+}
 
-            moves.push(current as u8);
+fn sliding_targets(board: &Board, sq: u8, rays: std::ops::Range<usize>) -> u64 {
+    let mut targets = 0u64;
 
-            if board.squares[current as usize].count > 0 { break; }
+    for d in rays {
+        for &to in ray(sq, d) {
+            targets |= 1 << to;
+            if board.squares[to as usize].count > 0 { break; }
         }
     }
 
-    moves
+    targets
+}
+
+/// Squares a non-pawn piece reaches, including the occupied square that ends
+/// each slider ray. The caller decides between quiet, capture and klik.
+fn piece_targets(board: &Board, sq: u8, pt: u8) -> u64 {
+    match pt {
+        KNIGHT => MOVE_TABLES.knight_mask[sq as usize],
+        BISHOP => sliding_targets(board, sq, DIAGONAL_RAYS),
+        ROOK => sliding_targets(board, sq, LINE_RAYS),
+        QUEEN => sliding_targets(board, sq, 0..8),
+        KING => MOVE_TABLES.king_mask[sq as usize],
+        _ => 0,
+    }
 }
 
-fn pawn_moves(board: &Board, sq: u8, color: u8, captures_only: bool, include_klik: bool) -> Vec<(u8, u8)> {
-    let mut moves = Vec::with_capacity(8);
+/// Pawn targets as `(to_sq, move_type)` pairs, passed to `push`.
+fn pawn_moves(board: &Board, sq: u8, color: u8, captures_only: bool, include_klik: bool,
+              mut push: impl FnMut(u8, u8)) {
     let direction: i8 = if color == WHITE { 1 } else { -1 };
     let start_rank: u8 = if color == WHITE { 1 } else { 6 };
     let promo_rank: u8 = if color == WHITE { 7 } else { 0 };
@@ -136,9 +207,9 @@ fn pawn_moves(board: &Board, sq: u8, color: u8, captures_only: bool, include_kli
             if fwd_stack.count == 0 {
                 // Empty square
                 if square_rank(one_fwd) == promo_rank {
-                    moves.push((one_fwd, MT_PROMOTION));
+                    push(one_fwd, MT_PROMOTION);
                 } else {
-                    moves.push((one_fwd, MT_NORMAL));
+                    push(one_fwd, MT_NORMAL);
 
                     // Double move from start
                     if rank == start_rank && (board.unmoved_pawns[color as usize] & (1 << file)) != 0 {
@@ -147,23 +218,23 @@ fn pawn_moves(board: &Board, sq: u8, color: u8, captures_only: bool, include_kli
                             let two_fwd = two_forward as u8;
                             let two_stack = &board.squares[two_fwd as usize];
                             if two_stack.count == 0 {
-                                moves.push((two_fwd, MT_NORMAL));
-                            } else if include_klik && two_stack.count < 2
+                                push(two_fwd, MT_NORMAL);
+                            } else if include_klik && !two_stack.is_full()
                                 && piece_color(two_stack.top()) == color
                                 && piece_type(two_stack.top()) != KING
                             {
-                                moves.push((two_fwd, MT_KLIK));
+                                push(two_fwd, MT_KLIK);
                             }
                         }
                     }
                 }
-            } else if include_klik && fwd_stack.count < 2
+            } else if include_klik && !fwd_stack.is_full()
                 && piece_color(fwd_stack.top()) == color
                 && piece_type(fwd_stack.top()) != KING
             {
                 // Forward klik (not to promo rank)
                 if square_rank(one_fwd) != promo_rank {
-                    moves.push((one_fwd, MT_KLIK));
+                    push(one_fwd, MT_KLIK);
                 }
             }
         }
@@ -184,29 +255,27 @@ fn pawn_moves(board: &Board, sq: u8, color: u8, captures_only: bool, include_kli
             let target_color = piece_color(target_stack.top());
             if target_color != color {
                 if target_rank == promo_rank {
-                    moves.push((to, MT_PROMOTION_CAPTURE));
+                    push(to, MT_PROMOTION_CAPTURE);
                 } else {
-                    moves.push((to, MT_CAPTURE));
+                    push(to, MT_CAPTURE);
                 }
             }
         }
 
         // En passant
         if to == board.ep_square {
-            moves.push((to, MT_EN_PASSANT));
+            push(to, MT_EN_PASSANT);
         }
     }
-
-    moves
 }
 
-fn generate_piece_moves(board: &Board, sq: u8, piece: u8, include_klik: bool, captures_only: bool) -> Vec<Move> {
-    let mut moves = Vec::with_capacity(32);
+fn generate_piece_moves(board: &Board, sq: u8, piece: u8, include_klik: bool, captures_only: bool,
+                        moves: &mut Vec<Move>) {
     let color = piece_color(piece);
     let pt = piece_type(piece);
 
     if pt == PAWN {
-        for (to_sq, move_type) in pawn_moves(board, sq, color, captures_only, include_klik) {
+        pawn_moves(board, sq, color, captures_only, include_klik, |to_sq, move_type| {
             if move_type == MT_PROMOTION || move_type == MT_PROMOTION_CAPTURE {
                 for &promo in &[QUEEN, ROOK, BISHOP, KNIGHT] {
                     moves.push(Move::with_promotion(sq, to_sq, move_type, promo));
@@ -214,24 +283,13 @@ fn generate_piece_moves(board: &Board, sq: u8, piece: u8, include_klik: bool, ca
             } else {
                 moves.push(Move::new(sq, to_sq, move_type));
             }
-        }
-        return moves;
+        });
+        return;
     }
 
-    let targets: Vec<u8> = match pt {
-        KNIGHT => knight_targets(sq).to_vec(),
-        BISHOP => sliding_moves(board, sq, &BISHOP_DIRECTIONS),
-        ROOK => sliding_moves(board, sq, &ROOK_DIRECTIONS),
-        QUEEN => {
-            let mut t = sliding_moves(board, sq, &BISHOP_DIRECTIONS);
-            t.extend(sliding_moves(board, sq, &ROOK_DIRECTIONS));
-            t
-        }
-        KING => king_targets(sq).to_vec(),
-        _ => return moves,
-    };
-
-    for to_sq in targets {
+    let mut targets = piece_targets(board, sq, pt);
+    while targets != 0 {
+        let to_sq = pop_square(&mut targets);
         let target_stack = &board.squares[to_sq as usize];
 
         if target_stack.count == 0 {
@@ -240,114 +298,48 @@ fn generate_piece_moves(board: &Board, sq: u8, piece: u8, include_klik: bool, ca
             }
         } else if piece_color(target_stack.top()) != color {
             moves.push(Move::new(sq, to_sq, MT_CAPTURE));
-        } else if !captures_only && include_klik && target_stack.count < 2 {
-            if pt != KING && piece_type(target_stack.top()) != KING {
-                moves.push(Move::new(sq, to_sq, MT_KLIK));
-            }
+        } else if !captures_only && include_klik && !target_stack.is_full()
+            && pt != KING && piece_type(target_stack.top()) != KING
+        {
+            moves.push(Move::new(sq, to_sq, MT_KLIK));
         }
     }
-
-    moves
 }
 
-fn generate_combined_moves(board: &Board, sq: u8, pieces: &[u8], captures_only: bool) -> Vec<Move> {
-    let mut moves = Vec::with_capacity(32);
+fn generate_combined_moves(board: &Board, sq: u8, pieces: &[u8], captures_only: bool, moves: &mut Vec<Move>) {
     let color = piece_color(pieces[0]);
-
-    let mut has_pawn = false;
-    for &p in pieces {
-        if piece_type(p) == PAWN {
-            has_pawn = true;
-            break;
-        }
-    }
+    let has_pawn = pieces.iter().any(|&p| piece_type(p) == PAWN);
 
     let back_rank: u8 = if color == WHITE { 0 } else { 7 };
     let promo_rank: u8 = if color == WHITE { 7 } else { 0 };
 
-    let mut all_targets = std::collections::HashSet::new();
-    let mut pawn_targets = std::collections::HashSet::new();
+    // Union of both pieces' targets; a combined move never kliks, so the
+    // pawn's targets are exactly its non-klik moves
+    let mut all_targets = 0u64;
+    let mut pawn_targets = 0u64;
 
     for &piece in pieces {
         let pt = piece_type(piece);
         if pt == PAWN {
-            let direction: i8 = if color == WHITE { 1 } else { -1 };
-            let start_rank: u8 = if color == WHITE { 1 } else { 6 };
-            let rank = square_rank(sq);
-            let file = square_file(sq);
-
-            if !captures_only {
-                let one_forward = sq as i8 + 8 * direction;
-                if (0..64).contains(&one_forward) {
-                    let one_fwd = one_forward as u8;
-                    if board.squares[one_fwd as usize].count == 0 {
-                        pawn_targets.insert(one_fwd);
-                        all_targets.insert(one_fwd);
-
-                        if rank == start_rank && (board.unmoved_pawns[color as usize] & (1 << file)) != 0 {
-                            let two_forward = sq as i8 + 16 * direction;
-                            if (0..64).contains(&two_forward) {
-                                let two_fwd = two_forward as u8;
-                                if board.squares[two_fwd as usize].count == 0 {
-                                    pawn_targets.insert(two_fwd);
-                                    all_targets.insert(two_fwd);
-                                }
-                            }
-                        }
-                    }
-                }
-            }
-
-            // Diagonal captures
-            let file_i = file as i8;
-            for df in [-1i8, 1] {
-                let to_file = file_i + df;
-                if (0..8).contains(&to_file) {
-                    let direction_i: i8 = if color == WHITE { 1 } else { -1 };
-                    let to_sq = sq as i8 + 8 * direction_i + df;
-                    if (0..64).contains(&to_sq) {
-                        let to = to_sq as u8;
-                        let target_stack = &board.squares[to as usize];
-                        if target_stack.count > 0 && piece_color(target_stack.top()) != color {
-                            pawn_targets.insert(to);
-                            all_targets.insert(to);
-                        }
-                        if to == board.ep_square {
-                            pawn_targets.insert(to);
-                            all_targets.insert(to);
-                        }
-                    }
-                }
-            }
+            pawn_moves(board, sq, color, captures_only, false, |to_sq, _| pawn_targets |= 1 << to_sq);
         } else {
-            let targets: Vec<u8> = match pt {
-                KNIGHT => knight_targets(sq).to_vec(),
-                BISHOP => sliding_moves(board, sq, &BISHOP_DIRECTIONS),
-                ROOK => sliding_moves(board, sq, &ROOK_DIRECTIONS),
-                QUEEN => {
-                    let mut t = sliding_moves(board, sq, &BISHOP_DIRECTIONS);
-                    t.extend(sliding_moves(board, sq, &ROOK_DIRECTIONS));
-                    t
-                }
-                KING => king_targets(sq).to_vec(),
-                _ => Vec::new(),
-            };
-            for t in targets {
-                all_targets.insert(t);
-            }
+            all_targets |= piece_targets(board, sq, pt);
         }
     }
+    all_targets |= pawn_targets;
 
-    for to_sq in all_targets {
+    while all_targets != 0 {
+        let to_sq = pop_square(&mut all_targets);
         let to_rank = square_rank(to_sq);
         let target_stack = &board.squares[to_sq as usize];
+        let pawn_target = pawn_targets & (1 << to_sq) != 0;
 
         // Back rank restriction
         if has_pawn && to_rank == back_rank { continue; }
 
         // Carried-to-promo restriction
         if has_pawn && to_rank == promo_rank {
-            if !pawn_targets.contains(&to_sq) { continue; }
+            if !pawn_target { continue; }
             // Combined promotion
             if target_stack.count == 0 {
                 for &promo in &[QUEEN, ROOK, BISHOP, KNIGHT] {
@@ -362,32 +354,30 @@ fn generate_combined_moves(board: &Board, sq: u8, pieces: &[u8], captures_only:
         }
 
         // En passant (combined)
-        if to_sq == board.ep_square && pawn_targets.contains(&to_sq) {
+        if to_sq == board.ep_square && pawn_target {
             moves.push(Move::with_unklik(sq, to_sq, MT_EN_PASSANT, -1));
             continue;
         }
 
         if target_stack.count == 0 {
             if !captures_only {
-                moves.push(Move::new(sq, to_sq, MT_NORMAL));
+                moves.push(Move::with_unklik(sq, to_sq, MT_NORMAL, -1));
             }
         } else if piece_color(target_stack.top()) != color {
-            moves.push(Move::new(sq, to_sq, MT_CAPTURE));
+            moves.push(Move::with_unklik(sq, to_sq, MT_CAPTURE, -1));
         }
-        // Friendly piece: can't klik as combined (would exceed 2 piece max)
+        // Friendly piece: can't klik as combined (would exceed MAX_STACK)
     }
-
-    moves
 }
 
-fn generate_unklik_moves(board: &Board, sq: u8, piece_idx: u8, piece: u8, captures_only: bool) -> Vec<Move> {
-    let mut moves = Vec::with_capacity(32);
+fn generate_unklik_moves(board: &Board, sq: u8, piece_idx: u8, piece: u8, captures_only: bool,
+                         moves: &mut Vec<Move>) {
     let color = piece_color(piece);
     let pt = piece_type(piece);
     let idx = piece_idx as i8;
 
     if pt == PAWN {
-        for (to_sq, base_type) in pawn_moves(board, sq, color, captures_only, true) {
+        pawn_moves(board, sq, color, captures_only, true, |to_sq, base_type| {
             let target_stack = &board.squares[to_sq as usize];
 
             if base_type == MT_EN_PASSANT {
@@ -404,30 +394,17 @@ fn generate_unklik_moves(board: &Board, sq: u8, piece_idx: u8, piece: u8, captur
                 }
             } else if piece_color(target_stack.top()) != color {
                 moves.push(Move::with_unklik(sq, to_sq, MT_UNKLIK, idx));
-            } else if !captures_only && target_stack.count < 2 && piece_type(target_stack.top()) != KING {
-                let promo_rank: u8 = if color == WHITE { 7 } else { 0 };
-                if square_rank(to_sq) != promo_rank {
-                    moves.push(Move::with_unklik(sq, to_sq, MT_UNKLIK_KLIK, idx));
-                }
+            } else if !captures_only && !target_stack.is_full() && piece_type(target_stack.top()) != KING {
+                // pawn_moves never offers a klik onto the promotion rank
+                moves.push(Move::with_unklik(sq, to_sq, MT_UNKLIK_KLIK, idx));
             }
-        }
-        return moves;
+        });
+        return;
     }
 
-    let targets: Vec<u8> = match pt {
-        KNIGHT => knight_targets(sq).to_vec(),
-        BISHOP => sliding_moves(board, sq, &BISHOP_DIRECTIONS),
-        ROOK => sliding_moves(board, sq, &ROOK_DIRECTIONS),
-        QUEEN => {
-            let mut t = sliding_moves(board, sq, &BISHOP_DIRECTIONS);
-            t.extend(sliding_moves(board, sq, &ROOK_DIRECTIONS));
-            t
-        }
-        KING => king_targets(sq).to_vec(),
-        _ => return moves,
-    };
-
-    for to_sq in targets {
+    let mut targets = piece_targets(board, sq, pt);
+    while targets != 0 {
+        let to_sq = pop_square(&mut targets);
         let target_stack = &board.squares[to_sq as usize];
 
         if target_stack.count == 0 {
@@ -436,18 +413,15 @@ fn generate_unklik_moves(board: &Board, sq: u8, piece_idx: u8, piece: u8, captur
             }
         } else if piece_color(target_stack.top()) != color {
             moves.push(Move::with_unklik(sq, to_sq, MT_UNKLIK, idx));
-        } else if !captures_only && target_stack.count < 2 {
-            if pt != KING && piece_type(target_stack.top()) != KING {
-                moves.push(Move::with_unklik(sq, to_sq, MT_UNKLIK_KLIK, idx));
-            }
+        } else if !captures_only && !target_stack.is_full()
+            && pt != KING && piece_type(target_stack.top()) != KING
+        {
+            moves.push(Move::with_unklik(sq, to_sq, MT_UNKLIK_KLIK, idx));
         }
     }
-
-    moves
 }
 
-fn generate_castling_moves(board: &Board) -> Vec<Move> {
-    let mut moves = Vec::with_capacity(4);
+fn generate_castling_moves(board: &Board, moves: &mut Vec<Move>) {
     let color = board.turn;
     let enemy = opposite_color(color);
 
@@ -459,11 +433,11 @@ fn generate_castling_moves(board: &Board) -> Vec<Move> {
 
     // King must be at starting square (not stacked)
     let king_stack = &board.squares[king_sq as usize];
-    if king_stack.count == 0 || king_stack.top() != make_piece(color, KING) { return moves; }
-    if king_stack.count > 1 { return moves; } // King can't be in a stack
+    if king_stack.count == 0 || king_stack.top() != make_piece(color, KING) { return; }
+    if king_stack.count > 1 { return; } // King can't be in a stack
 
     // King can't be in check
-    if is_attacked(board, king_sq, enemy) { return moves; }
+    if is_attacked(board, king_sq, enemy) { return; }
 
     let rook_sq_k = base + 7; // h1/h8
     let rook_sq_q = base;     // a1/a8
@@ -479,18 +453,16 @@ fn generate_castling_moves(board: &Board) -> Vec<Move> {
     // Kingside castle
     if board.castling & ks_rights != 0 {
         let rook_stack = &board.squares[rook_sq_k as usize];
-        if rook_stack.count > 0 && has_rook(rook_stack, rook_piece) {
-            if board.squares[g_sq as usize].count == 0 {
-                if !is_attacked(board, f_sq, enemy) {
-                    let f_stack = &board.squares[f_sq as usize];
-                    if f_stack.count == 0 {
-                        moves.push(Move::new(king_sq, g_sq, MT_CASTLE_K));
-                    } else if f_stack.count == 1 && piece_color(f_stack.pieces[0]) == color
-                        && piece_type(f_stack.pieces[0]) != KING
-                    {
-                        moves.push(Move::new(king_sq, g_sq, MT_CASTLE_K_KLIK));
-                    }
-                }
+        if rook_stack.count > 0 && has_rook(rook_stack, rook_piece)
+            && board.squares[g_sq as usize].count == 0 && !is_attacked(board, f_sq, enemy)
+        {
+            let f_stack = &board.squares[f_sq as usize];
+            if f_stack.count == 0 {
+                moves.push(Move::new(king_sq, g_sq, MT_CASTLE_K));
+            } else if f_stack.count == 1 && piece_color(f_stack.pieces[0]) == color
+                && piece_type(f_stack.pieces[0]) != KING
+            {
+                moves.push(Move::new(king_sq, g_sq, MT_CASTLE_K_KLIK));
             }
         }
     }
@@ -498,23 +470,20 @@ fn generate_castling_moves(board: &Board) -> Vec<Move> {
     // Queenside castle
     if board.castling & qs_rights != 0 {
         let rook_stack = &board.squares[rook_sq_q as usize];
-        if rook_stack.count > 0 && has_rook(rook_stack, rook_piece) {
-            if board.squares[c_sq as usize].count == 0 && board.squares[b_sq as usize].count == 0 {
-                if !is_attacked(board, d_sq, enemy) {
-                    let d_stack = &board.squares[d_sq as usize];
-                    if d_stack.count == 0 {
-                        moves.push(Move::new(king_sq, c_sq, MT_CASTLE_Q));
-                    } else if d_stack.count == 1 && piece_color(d_stack.pieces[0]) == color
-                        && piece_type(d_stack.pieces[0]) != KING
-                    {
-                        moves.push(Move::new(king_sq, c_sq, MT_CASTLE_Q_KLIK));
-                    }
-                }
+        if rook_stack.count > 0 && has_rook(rook_stack, rook_piece)
+            && board.squares[c_sq as usize].count == 0 && board.squares[b_sq as usize].count == 0
+            && !is_attacked(board, d_sq, enemy)
+        {
+            let d_stack = &board.squares[d_sq as usize];
+            if d_stack.count == 0 {
+                moves.push(Move::new(king_sq, c_sq, MT_CASTLE_Q));
+            } else if d_stack.count == 1 && piece_color(d_stack.pieces[0]) == color
+                && piece_type(d_stack.pieces[0]) != KING
+            {
+                moves.push(Move::new(king_sq, c_sq, MT_CASTLE_Q_KLIK));
             }
         }
     }
-
-    moves
 }
 
 fn has_rook(stack: &SquareStack, rook_piece: u8) -> bool {
@@ -525,100 +494,123 @@ fn has_rook(stack: &SquareStack, rook_piece: u8) -> bool {
 }
 
 pub fn is_attacked(board: &Board, sq: u8, by_color: u8) -> bool {
-    let squares = &board.squares;
+    scan_attackers(board, sq, by_color, |_, _| true)
+}
+
+/// Every piece of `by_color` attacking `sq`, as `(square, piece)`. Both
+/// pieces of a stack are listed when both attack.
+pub fn attackers_to(board: &Board, sq: u8, by_color: u8) -> Vec<(u8, u8)> {
+    let mut attackers = Vec::new();
+    scan_attackers(board, sq, by_color, |from, piece| {
+        attackers.push((from, piece));
+        false
+    });
+    attackers
+}
+
+/// Call `found` with each attacker of `sq` by `by_color` until it returns
+/// true; returns whether it did.
+#[inline(always)]
+fn scan_attackers(board: &Board, sq: u8, by_color: u8, mut found: impl FnMut(u8, u8) -> bool) -> bool {
+    // Reports the pieces on `from` whose type passes `attacks`
+    #[inline(always)]
+    fn scan(board: &Board, from: u8, by_color: u8, attacks: impl Fn(u8) -> bool,
+            found: &mut impl FnMut(u8, u8) -> bool) -> bool {
+        let stack = &board.squares[from as usize];
+        stack.pieces[..stack.count as usize].iter()
+            .any(|&piece| piece_color(piece) == by_color && attacks(piece_type(piece)) && found(from, piece))
+    }
 
     // Knight attacks
     for &attacker_sq in knight_targets(sq) {
-        let stack = &squares[attacker_sq as usize];
-        for i in 0..stack.count {
-            let piece = stack.pieces[i as usize];
-            if piece_color(piece) == by_color && piece_type(piece) == KNIGHT {
-                return true;
-            }
-        }
+        if scan(board, attacker_sq, by_color, |pt| pt == KNIGHT, &mut found) { return true; }
     }
 
     // King attacks
     for &attacker_sq in king_targets(sq) {
-        let stack = &squares[attacker_sq as usize];
-        for i in 0..stack.count {
-            let piece = stack.pieces[i as usize];
-            if piece_color(piece) == by_color && piece_type(piece) == KING {
-                return true;
-            }
-        }
-    }
-
-    // Bishop/Queen diagonals
-    for &direction in &BISHOP_DIRECTIONS {
-        let mut current = sq as i8;
-        loop {
-            let prev = current;
-            current += direction;
-            if !(0..64).contains(&current) { break; }
-            if ((current & 7) - (prev & 7)).abs() > 1 { break; }
-
-            let stack = &squares[current as usize];
-            if stack.count > 0 {
-                for i in 0..stack.count {
-                    let piece = stack.pieces[i as usize];
-                    if piece_color(piece) == by_color {
-                        let pt = piece_type(piece);
-                        if pt == BISHOP || pt == QUEEN { return true; }
-                    }
-                }
-                break;
-            }
-        }
+        if scan(board, attacker_sq, by_color, |pt| pt == KING, &mut found) { return true; }
     }
 
-    // Rook/Queen lines
-    for &direction in &ROOK_DIRECTIONS {
-        let mut current = sq as i8;
-        loop {
-            let prev = current;
-            current += direction;
-            if !(0..64).contains(&current) { break; }
-            if ((current & 7) - (prev & 7)).abs() > 1 { break; }
-
-            let stack = &squares[current as usize];
-            if stack.count > 0 {
-                for i in 0..stack.count {
-                    let piece = stack.pieces[i as usize];
-                    if piece_color(piece) == by_color {
-                        let pt = piece_type(piece);
-                        if pt == ROOK || pt == QUEEN { return true; }
-                    }
-                }
-                break;
-            }
-        }
+    // Sliders: only the first piece on each ray can attack
+    for (d, &blocker) in ray_blockers(board, sq).iter().enumerate() {
+        if blocker == SQ_NONE { continue; }
+        let slider = if DIAGONAL_RAYS.contains(&d) { BISHOP } else { ROOK };
+        if scan(board, blocker, by_color, |pt| pt == slider || pt == QUEEN, &mut found) { return true; }
     }
 
     // Pawn attacks
     let pawn_direction: i8 = if by_color == WHITE { 1 } else { -1 };
-    let enemy_pawn = make_piece(by_color, PAWN);
     let sq_file = (sq & 7) as i8;
 
     for df in [-1i8, 1] {
         let attacker_sq = sq as i8 - 8 * pawn_direction + df;
-        if (0..64).contains(&attacker_sq) && ((attacker_sq & 7) - sq_file).abs() == 1 {
-            let stack = &squares[attacker_sq as usize];
-            for i in 0..stack.count {
-                if stack.pieces[i as usize] == enemy_pawn { return true; }
-            }
+        if (0..64).contains(&attacker_sq) && ((attacker_sq & 7) - sq_file).abs() == 1
+            && scan(board, attacker_sq as u8, by_color, |pt| pt == PAWN, &mut found)
+        {
+            return true;
         }
     }
 
     false
 }
 
+/// Squares holding `color`'s pieces that are the only blocker between its
+/// king and an enemy slider on the same line.
+pub fn pinned_squares(board: &Board, color: u8) -> u64 {
+    let king_sq = board.king_sq[color as usize];
+    if king_sq == SQ_NONE { return 0; }
+    let mut pinned = 0u64;
+    for d in 0..8 {
+        let slider = if DIAGONAL_RAYS.contains(&d) { BISHOP } else { ROOK };
+        let mut blocker = None;
+        for &sq in ray(king_sq, d) {
+            let stack = &board.squares[sq as usize];
+            if stack.count == 0 { continue; }
+            if piece_color(stack.top()) == color {
+                if blocker.is_some() { break; }
+                blocker = Some(sq);
+                continue;
+            }
+            let pins = stack.pieces[..stack.count as usize].iter()
+                .any(|&p| piece_type(p) == slider || piece_type(p) == QUEEN);
+            if let Some(own) = blocker.filter(|_| pins) {
+                pinned |= 1 << own;
+            }
+            break;
+        }
+    }
+    pinned
+}
+
 pub fn is_in_check(board: &Board, color: u8) -> bool {
     let king_sq = board.king_sq[color as usize];
     if king_sq == SQ_NONE { return false; }
     is_attacked(board, king_sq, opposite_color(color))
 }
 
+/// Side to move is in check and has no legal move.
+pub fn is_checkmate(board: &mut Board) -> bool {
+    is_in_check(board, board.turn) && !has_legal_move(board)
+}
+
+/// Side to move is not in check but has no legal move.
+pub fn is_stalemate(board: &mut Board) -> bool {
+    !is_in_check(board, board.turn) && !has_legal_move(board)
+}
+
+/// Fifty moves (100 plies) without a capture or pawn move.
+pub fn is_fifty_move_draw(board: &Board) -> bool {
+    board.halfmove_clock >= 100
+}
+
+/// Neither side can mate: only kings, plus at most one knight or bishop
+/// on the whole board (a lone minor cannot klik into anything useful).
+pub fn is_insufficient_material(board: &Board) -> bool {
+    let counts = board.piece_counts();
+    [WHITE, BLACK].iter().all(|&c| counts.get(c, PAWN) + counts.get(c, ROOK) + counts.get(c, QUEEN) == 0)
+        && [WHITE, BLACK].iter().map(|&c| counts.get(c, KNIGHT) + counts.get(c, BISHOP)).sum::<u8>() <= 1
+}
+
 pub fn is_legal(board: &mut Board, mv: Move) -> bool {
     let undo = make_move(board, mv);
     let legal = !is_in_check(board, opposite_color(board.turn));
@@ -626,8 +618,156 @@ pub fn is_legal(board: &mut Board, mv: Move) -> bool {
     legal
 }
 
+impl Move {
+    /// Parse a move in the engine's UCI dialect (`e2e4`, `b1d2k`, `a1a5u0`,
+    /// `e7e8q`, ...) or the verbose form from `to_uci_verbose`, by matching
+    /// it against the legal moves of `board`.
+    pub fn from_uci(board: &mut Board, uci: &str) -> Option<Move> {
+        let moves = generate_moves(board, true, false);
+        // The verbose form is unique, so it wins over a plain match
+        moves.iter().find(|m| m.to_uci_verbose(board) == uci)
+            .or_else(|| moves.iter().find(|m| m.to_uci() == uci))
+            .copied()
+    }
+
+    /// Like `to_uci`, but a single piece leaving a stack is named by its
+    /// letter instead of its index (`e2e4uN`, `d1d3UB`, `e5d6uP` for an
+    /// unklik en passant), so the move still means the same thing without
+    /// the stack order at hand. `board` is the position before the move.
+    pub fn to_uci_verbose(&self, board: &Board) -> String {
+        let stack = &board.squares[self.from_sq as usize];
+        if stack.count < 2 || self.unklik_index < 0 || self.unklik_index as u8 >= stack.count {
+            return self.to_uci();
+        }
+        let letter = piece_char(stack.pieces[self.unklik_index as usize]).to_ascii_uppercase();
+        let mut s = format!("{}{}", square_name(self.from_sq), square_name(self.to_sq));
+        if self.promotion != NONE {
+            s.push(piece_char(make_piece(BLACK, self.promotion)));
+        }
+        s.push(if self.move_type == MT_UNKLIK_KLIK { 'U' } else { 'u' });
+        s.push(letter);
+        s
+    }
+}
+
+/// Legal moves that leave the opponent in check, including discovered
+/// checks from klik/unklik moves.
+pub fn checking_moves(board: &mut Board) -> Vec<Move> {
+    let mut moves = generate_moves(board, true, false);
+    moves.retain(|&mv| {
+        let undo = make_move(board, mv);
+        let check = is_in_check(board, board.turn);
+        unmake_move(board, mv, &undo);
+        check
+    });
+    moves
+}
+
+/// Count leaf nodes of the legal move tree to the given depth.
+pub fn perft(board: &mut Board, depth: u32) -> u64 {
+    let mut buffers = vec![Vec::with_capacity(128); depth as usize];
+    perft_with_buffers(board, depth, &mut buffers)
+}
+
+// One move buffer per remaining ply, so the whole walk allocates nothing
+fn perft_with_buffers(board: &mut Board, depth: u32, buffers: &mut [Vec<Move>]) -> u64 {
+    if depth == 0 { return 1; }
+    let (moves, rest) = buffers.split_first_mut().expect("one buffer per ply");
+    generate_moves_into(board, moves, true, false);
+    if depth == 1 { return moves.len() as u64; }
+
+    let mut nodes = 0;
+    for &mv in moves.iter() {
+        let undo = make_move(board, mv);
+        nodes += perft_with_buffers(board, depth - 1, rest);
+        unmake_move(board, mv, &undo);
+    }
+    nodes
+}
+
+/// Perft split by root move, for tracking down move generation differences.
+pub fn perft_divide(board: &mut Board, depth: u32) -> Vec<(Move, u64)> {
+    let moves = generate_moves(board, true, false);
+    moves.into_iter().map(|mv| {
+        let undo = make_move(board, mv);
+        let nodes = perft(board, depth.saturating_sub(1));
+        unmake_move(board, mv, &undo);
+        (mv, nodes)
+    }).collect()
+}
+
 pub fn generate_moves(board: &mut Board, legal_only: bool, captures_only: bool) -> Vec<Move> {
     let mut moves = Vec::with_capacity(128);
+    generate_moves_into(board, &mut moves, legal_only, captures_only);
+    moves
+}
+
+/// Like `generate_moves`, but fills a caller-owned buffer (cleared first) so
+/// hot loops can reuse one allocation across calls.
+pub fn generate_moves_into(board: &mut Board, moves: &mut Vec<Move>, legal_only: bool, captures_only: bool) {
+    moves.clear();
+    let color = board.turn;
+
+    if board.stacked_count == 0 {
+        // Fast path: without stacks there are no unklik or combined moves
+        for sq in 0..64u8 {
+            let piece = board.squares[sq as usize].top();
+            if piece != NO_PIECE && piece_color(piece) == color {
+                generate_piece_moves(board, sq, piece, true, captures_only, moves);
+            }
+        }
+    } else {
+        generate_stacked_position_moves(board, moves, captures_only);
+    }
+
+    // Castling (not during captures-only)
+    if !captures_only {
+        generate_castling_moves(board, moves);
+    }
+
+    if legal_only {
+        let suspects = legality_suspects(board);
+        moves.retain(|&mv| is_legal_pseudo(board, mv, suspects));
+    }
+}
+
+/// Squares whose moves need the make/unmake legality test: every square
+/// when in check, else the pinned ones. Out of check, only a king move, en
+/// passant or a pinned piece leaving its square can expose the king. An
+/// unklik leaves the other piece behind as a blocker, so it is safe either
+/// way.
+fn legality_suspects(board: &Board) -> u64 {
+    let color = board.turn;
+    if is_in_check(board, color) { u64::MAX } else { pinned_squares(board, color) }
+}
+
+/// Legality of a pseudo-legal move, testing it by make/unmake only when
+/// `legality_suspects` says it could expose the king.
+fn is_legal_pseudo(board: &mut Board, mv: Move, suspects: u64) -> bool {
+    let king_sq = board.king_sq[board.turn as usize];
+    if mv.from_sq != king_sq && mv.move_type != MT_EN_PASSANT && suspects & (1 << mv.from_sq) == 0 {
+        return true;
+    }
+    is_legal(board, mv)
+}
+
+/// Number of legal moves, without building the legal move list.
+pub fn count_legal_moves(board: &mut Board) -> usize {
+    let suspects = legality_suspects(board);
+    generate_moves(board, false, false).into_iter()
+        .filter(|&mv| is_legal_pseudo(board, mv, suspects))
+        .count()
+}
+
+/// Whether the side to move has any legal move; stops testing at the
+/// first one found.
+pub fn has_legal_move(board: &mut Board) -> bool {
+    let suspects = legality_suspects(board);
+    generate_moves(board, false, false).into_iter()
+        .any(|mv| is_legal_pseudo(board, mv, suspects))
+}
+
+fn generate_stacked_position_moves(board: &mut Board, moves: &mut Vec<Move>, captures_only: bool) {
     let color = board.turn;
 
     for sq in 0..64u8 {
@@ -636,47 +776,30 @@ pub fn generate_moves(board: &mut Board, legal_only: bool, captures_only: bool)
 
         if stack.count >= 2 {
             // Stacked position
-            let mut friendly_pieces: Vec<(u8, u8)> = Vec::new();
-            for idx in 0..stack.count {
-                let p = stack.pieces[idx as usize];
-                if piece_color(p) == color {
-                    friendly_pieces.push((idx, p));
-                }
+            let [bottom, top] = stack.pieces;
+            let own_bottom = piece_color(bottom) == color;
+            let own_top = piece_color(top) == color;
+
+            // Generate unklik moves. Unkliking either of two identical pieces
+            // gives the same position, so only the top one is generated.
+            if own_bottom && !(own_top && bottom == top) {
+                generate_unklik_moves(board, sq, 0, bottom, captures_only, moves);
             }
-
-            // Generate unklik moves
-            for &(idx, piece) in &friendly_pieces {
-                moves.extend(generate_unklik_moves(board, sq, idx, piece, captures_only));
+            if own_top {
+                generate_unklik_moves(board, sq, 1, top, captures_only, moves);
             }
 
             // Combined moves if both friendly
-            if friendly_pieces.len() == 2 {
-                let pieces: Vec<u8> = friendly_pieces.iter().map(|&(_, p)| p).collect();
-                moves.extend(generate_combined_moves(board, sq, &pieces, captures_only));
+            if own_bottom && own_top {
+                generate_combined_moves(board, sq, &stack.pieces[..stack.count as usize], captures_only, moves);
             }
         } else {
             let piece = stack.pieces[0];
             if piece_color(piece) == color {
-                moves.extend(generate_piece_moves(board, sq, piece, true, captures_only));
+                generate_piece_moves(board, sq, piece, true, captures_only, moves);
             }
         }
     }
-
-    // Castling (not during captures-only)
-    if !captures_only {
-        moves.extend(generate_castling_moves(board));
-    }
-
-    if legal_only {
-        moves.retain(|&mv| {
-            let undo = make_move(board, mv);
-            let legal = !is_in_check(board, opposite_color(board.turn));
-            unmake_move(board, mv, &undo);
-            legal
-        });
-    }
-
-    moves
 }
 
 pub fn make_move(board: &mut Board, mv: Move) -> UndoInfo {
@@ -692,6 +815,10 @@ pub fn make_move(board: &mut Board, mv: Move) -> UndoInfo {
     undo.fullmove = board.fullmove;
     undo.unmoved_pawns = board.unmoved_pawns;
     undo.zobrist_hash = board.zobrist_hash;
+    undo.stacked_count = board.stacked_count;
+    undo.material = board.material;
+    undo.pst = board.pst;
+    let old_ep_hash = ep_hash(board);
 
     // Save from and to squares
     undo.modified.push((from_sq, board.squares[from_sq as usize]));
@@ -754,8 +881,6 @@ pub fn make_move(board: &mut Board, mv: Move) -> UndoInfo {
             } else {
                 board.squares[rook_to as usize] = SquareStack::single(rook);
             }
-
-            board.king_sq[board.turn as usize] = to_sq;
         }
 
         MT_UNKLIK | MT_UNKLIK_KLIK => {
@@ -764,24 +889,17 @@ pub fn make_move(board: &mut Board, mv: Move) -> UndoInfo {
             if mt == MT_UNKLIK_KLIK {
                 board.squares[to_sq as usize].add(moving_piece);
             } else {
+                // Plain unklik or unklik-capture (whole target stack is taken)
                 board.squares[to_sq as usize].clear();
                 board.squares[to_sq as usize] = SquareStack::single(moving_piece);
             }
-
-            if piece_type(moving_piece) == KING {
-                board.king_sq[board.turn as usize] = to_sq;
-            }
         }
 
         MT_KLIK => {
             let old_stack = board.squares[from_sq as usize];
             board.squares[from_sq as usize].clear();
             for i in 0..old_stack.count {
-                let piece = old_stack.pieces[i as usize];
-                board.squares[to_sq as usize].add(piece);
-                if piece_type(piece) == KING {
-                    board.king_sq[board.turn as usize] = to_sq;
-                }
+                board.squares[to_sq as usize].add(old_stack.pieces[i as usize]);
             }
         }
 
@@ -793,10 +911,23 @@ pub fn make_move(board: &mut Board, mv: Move) -> UndoInfo {
             };
             undo.modified.push((captured_sq, board.squares[captured_sq as usize]));
 
-            let old_stack = board.squares[from_sq as usize];
-            board.squares[from_sq as usize].clear();
-            board.squares[captured_sq as usize].clear();
-            board.squares[to_sq as usize] = old_stack;
+            if mv.unklik_index >= 0 && from_stack.count >= 2 {
+                // Unklik en passant: only the pawn leaves the stack
+                let pawn = board.squares[from_sq as usize].remove_at(mv.unklik_index as u8);
+                board.squares[to_sq as usize] = SquareStack::single(pawn);
+            } else {
+                let old_stack = board.squares[from_sq as usize];
+                board.squares[from_sq as usize].clear();
+                board.squares[to_sq as usize] = old_stack;
+            }
+
+            // Only the passed pawn is taken; a piece stacked with it stays
+            let enemy_pawn = make_piece(opposite_color(board.turn), PAWN);
+            let captured = &mut board.squares[captured_sq as usize];
+            match (0..captured.count).rev().find(|&i| captured.pieces[i as usize] == enemy_pawn) {
+                Some(idx) => { captured.remove_at(idx); }
+                None => captured.clear(),
+            }
         }
 
         MT_PROMOTION | MT_PROMOTION_CAPTURE => {
@@ -819,8 +950,9 @@ pub fn make_move(board: &mut Board, mv: Move) -> UndoInfo {
                 } else {
                     board.squares[to_sq as usize] = SquareStack::single(promoted_piece);
                 }
-            } else if mv.unklik_index > 0 || from_stack.count >= 2 {
-                // Unklik promotion
+            } else if from_stack.count >= 2 {
+                // Unklik promotion: only the pawn at unklik_index (0 or 1)
+                // leaves, its companion stays on from_sq
                 board.squares[from_sq as usize].remove_at(mv.unklik_index as u8);
                 board.squares[to_sq as usize].clear();
                 board.squares[to_sq as usize] = SquareStack::single(promoted_piece);
@@ -833,21 +965,26 @@ pub fn make_move(board: &mut Board, mv: Move) -> UndoInfo {
         }
 
         _ => {
-            // Normal move or capture
+            // Normal move or capture. Capturing a stack takes both of its
+            // pieces: the attacker always lands on an empty square.
             let old_stack = board.squares[from_sq as usize];
             board.squares[from_sq as usize].clear();
             board.squares[to_sq as usize].clear();
 
             for i in 0..old_stack.count {
-                let piece = old_stack.pieces[i as usize];
-                board.squares[to_sq as usize].add(piece);
-                if piece_type(piece) == KING {
-                    board.king_sq[board.turn as usize] = to_sq;
-                }
+                board.squares[to_sq as usize].add(old_stack.pieces[i as usize]);
             }
         }
     }
 
+    // Every move type carries the king from from_sq to to_sq, so one check
+    // after the board update keeps king_sq right for all of them
+    let king = make_piece(board.turn, KING);
+    let left = &board.squares[from_sq as usize];
+    if board.king_sq[board.turn as usize] == from_sq && !left.pieces[..left.count as usize].contains(&king) {
+        board.king_sq[board.turn as usize] = to_sq;
+    }
+
     // Update castling rights
     if from_sq == SQ_E1 || to_sq == SQ_E1 { board.castling &= !CR_WHITE; }
     if from_sq == SQ_E8 || to_sq == SQ_E8 { board.castling &= !CR_BLACK; }
@@ -857,19 +994,28 @@ pub fn make_move(board: &mut Board, mv: Move) -> UndoInfo {
     if from_sq == SQ_H8 || to_sq == SQ_H8 { board.castling &= !CR_B_KINGSIDE; }
 
     // Update halfmove clock
-    let is_capture = mt == MT_CAPTURE || mt == MT_EN_PASSANT || mt == MT_PROMOTION_CAPTURE;
+    // Unklik captures keep MT_UNKLIK, so also look at what stood on to_sq
+    let to_stack = undo.modified[1].1;
+    let is_capture = mt == MT_CAPTURE || mt == MT_EN_PASSANT || mt == MT_PROMOTION_CAPTURE
+        || (to_stack.count > 0 && piece_color(to_stack.top()) != board.turn);
     if moving_piece_type == PAWN || is_capture {
         board.halfmove_clock = 0;
     } else {
         board.halfmove_clock += 1;
     }
 
-    // Update en passant square
+    // Update en passant square. A stack carrying a pawn only counts as a
+    // double push when it goes straight ahead from the pawn's home rank
+    // without capturing and the pawn has never moved.
     board.ep_square = SQ_NONE;
-    if moving_piece_type == PAWN {
+    if moving_piece_type == PAWN && !is_capture {
         let from_rank = square_rank(from_sq);
         let to_rank = square_rank(to_sq);
-        if (to_rank as i8 - from_rank as i8).unsigned_abs() == 2 {
+        let home_rank = if board.turn == WHITE { 1 } else { 6 };
+        let file = square_file(from_sq);
+        if (to_rank as i8 - from_rank as i8).unsigned_abs() == 2 && from_rank == home_rank
+            && file == square_file(to_sq) && undo.unmoved_pawns[board.turn as usize] & (1 << file) != 0
+        {
             board.ep_square = (from_sq + to_sq) / 2;
         }
     }
@@ -878,13 +1024,7 @@ pub fn make_move(board: &mut Board, mv: Move) -> UndoInfo {
     let color_moved = board.turn;
     let from_rank = square_rank(from_sq);
     let from_file = square_file(from_sq);
-    if moving_piece_type == PAWN {
-        if color_moved == WHITE && from_rank == 1 {
-            board.unmoved_pawns[WHITE as usize] &= !(1 << from_file);
-        } else if color_moved == BLACK && from_rank == 6 {
-            board.unmoved_pawns[BLACK as usize] &= !(1 << from_file);
-        }
-    } else if mt == MT_NORMAL || mt == MT_CAPTURE || mt == MT_KLIK {
+    if moving_piece_type == PAWN || mt == MT_NORMAL || mt == MT_CAPTURE || mt == MT_KLIK {
         if color_moved == WHITE && from_rank == 1 {
             board.unmoved_pawns[WHITE as usize] &= !(1 << from_file);
         } else if color_moved == BLACK && from_rank == 6 {
@@ -898,41 +1038,62 @@ pub fn make_move(board: &mut Board, mv: Move) -> UndoInfo {
         board.fullmove += 1;
     }
 
-    // Incremental Zobrist hash update
+    // Incremental Zobrist hash, material and PST update
     let zob = &*ZOBRIST;
     let mut h = undo.zobrist_hash;
+    let mut stacked = board.stacked_count;
 
     for &(msq, ref old_stack) in &undo.modified {
         for i in 0..old_stack.count {
             let piece = old_stack.pieces[i as usize];
             h ^= zob.piece_keys[piece as usize][i as usize][msq as usize];
         }
-        let new_stack = &board.squares[msq as usize];
+        let new_stack = board.squares[msq as usize];
+        stacked = stacked + new_stack.has_stack() as u8 - old_stack.has_stack() as u8;
         for i in 0..new_stack.count {
             let piece = new_stack.pieces[i as usize];
             h ^= zob.piece_keys[piece as usize][i as usize][msq as usize];
         }
+        board.update_scores(msq, old_stack, -1);
+        board.update_scores(msq, &new_stack, 1);
     }
 
     // Castling hash
     h ^= zob.castling_keys[undo.castling as usize] ^ zob.castling_keys[board.castling as usize];
 
     // EP hash
-    if undo.ep_square != SQ_NONE {
-        h ^= zob.ep_keys[(undo.ep_square & 7) as usize];
-    }
-    if board.ep_square != SQ_NONE {
-        h ^= zob.ep_keys[(board.ep_square & 7) as usize];
-    }
+    h ^= old_ep_hash ^ ep_hash(board);
+
+    // Pawn double-move rights hash
+    h ^= unmoved_pawns_hash([
+        undo.unmoved_pawns[0] ^ board.unmoved_pawns[0],
+        undo.unmoved_pawns[1] ^ board.unmoved_pawns[1],
+    ]);
 
     // Toggle turn
     h ^= zob.turn_key;
 
     board.zobrist_hash = h;
+    board.stacked_count = stacked;
+
+    if cfg!(any(debug_assertions, feature = "verify-zobrist")) {
+        verify_zobrist(board, mv);
+    }
 
     undo
 }
 
+/// Recompute the hash after `make_move` and panic if the incremental one
+/// differs. On in debug builds and with the `verify-zobrist` feature, so a
+/// hashing bug fails loudly instead of quietly poisoning the TT.
+#[cold]
+fn verify_zobrist(board: &Board, mv: Move) {
+    let mut fresh = board.clone();
+    compute_zobrist(&mut fresh);
+    assert_eq!(board.zobrist_hash, fresh.zobrist_hash,
+        "incremental hash wrong after {} (now {})", mv.to_uci(), board.get_fen_raw());
+}
+
 pub fn unmake_move(board: &mut Board, _mv: Move, undo: &UndoInfo) {
     // Restore modified squares
     for &(sq, ref old_stack) in &undo.modified {
@@ -946,5 +1107,8 @@ pub fn unmake_move(board: &mut Board, _mv: Move, undo: &UndoInfo) {
     board.fullmove = undo.fullmove;
     board.unmoved_pawns = undo.unmoved_pawns;
     board.zobrist_hash = undo.zobrist_hash;
+    board.stacked_count = undo.stacked_count;
+    board.material = undo.material;
+    board.pst = undo.pst;
     board.turn = opposite_color(board.turn);
 }
@@ -0,0 +1,236 @@
+//! Klikschaak Engine - Standard Algebraic Notation
+//!
+//! Chess SAN extended for stacks: a combined move names both pieces in
+//! stack order (`(PN)d6`), and a suffix marks klik (`=k`), unklik (`=u`)
+//! and unklik-klik (`=uk`) moves, e.g. `Nd2=k`, `Bxf7=u`, `O-O=k`.
+
+use crate::types::*;
+use crate::board::Board;
+use crate::movegen::{generate_moves, make_move, is_in_check, is_checkmate};
+
+impl Move {
+    /// SAN of this move in `board`, the position before the move, with
+    /// `+`/`#` appended when it gives check or mate.
+    pub fn to_san(&self, board: &Board) -> String {
+        let mut board = board.clone();
+        let legal = generate_moves(&mut board, true, false);
+        let mut san = san_body(&board, *self, &legal);
+
+        make_move(&mut board, *self);
+        if is_checkmate(&mut board) {
+            san.push('#');
+        } else if is_in_check(&board, board.turn) {
+            san.push('+');
+        }
+        san
+    }
+
+    /// Resolve SAN against the legal moves of `board`. Check marks and
+    /// annotations (`+#!?`) are ignored, `0-0` is read as `O-O`, and the
+    /// `x` and `=` of captures and promotions may be left out. Returns
+    /// `None` when no legal move or more than one matches.
+    pub fn from_san(board: &Board, san: &str) -> Option<Move> {
+        let parsed = parse_san(san)?;
+        let mut board = board.clone();
+        let legal = generate_moves(&mut board, true, false);
+
+        let mut found = None;
+        for &mv in &legal {
+            if parsed.matches(&board, mv) {
+                if found.is_some() { return None; }
+                found = Some(mv);
+            }
+        }
+        found
+    }
+}
+
+/// The parts of a SAN string that select a move.
+struct ParsedSan {
+    castle: Option<bool>, // Some(kingside)
+    mover: String,        // as written by `mover_letters`
+    from_file: Option<u8>,
+    from_rank: Option<u8>,
+    capture: bool,
+    to_sq: u8,
+    promotion: u8,
+    suffix: &'static str,
+}
+
+impl ParsedSan {
+    fn matches(&self, board: &Board, mv: Move) -> bool {
+        if stack_suffix(board, mv) != self.suffix { return false; }
+        if let Some(kingside) = self.castle {
+            return match mv.move_type {
+                MT_CASTLE_K | MT_CASTLE_K_KLIK => kingside,
+                MT_CASTLE_Q | MT_CASTLE_Q_KLIK => !kingside,
+                _ => false,
+            };
+        }
+        if matches!(mv.move_type, MT_CASTLE_K | MT_CASTLE_K_KLIK | MT_CASTLE_Q | MT_CASTLE_Q_KLIK) {
+            return false;
+        }
+
+        let mover = mover_letters(board, mv);
+        // A combined mover may name the pieces in either order
+        let reversed: String = self.mover.chars().rev()
+            .map(|c| match c { '(' => ')', ')' => '(', c => c })
+            .collect();
+        mv.to_sq == self.to_sq
+            && mv.promotion == self.promotion
+            && (mover == self.mover || (mover.starts_with('(') && mover == reversed))
+            && self.from_file.is_none_or(|f| square_file(mv.from_sq) == f)
+            && self.from_rank.is_none_or(|r| square_rank(mv.from_sq) == r)
+            && (!self.capture || is_capture(board, mv))
+    }
+}
+
+fn parse_san(san: &str) -> Option<ParsedSan> {
+    let mut s = san.trim().trim_end_matches(['+', '#', '!', '?']);
+
+    let mut suffix = "";
+    for candidate in ["=uk", "=u", "=k"] {
+        if let Some(rest) = s.strip_suffix(candidate) {
+            suffix = candidate;
+            s = rest;
+            break;
+        }
+    }
+
+    let mut parsed = ParsedSan {
+        castle: None,
+        mover: String::new(),
+        from_file: None,
+        from_rank: None,
+        capture: false,
+        to_sq: SQ_NONE,
+        promotion: NONE,
+        suffix,
+    };
+
+    match s {
+        "O-O" | "0-0" => { parsed.castle = Some(true); return Some(parsed); }
+        "O-O-O" | "0-0-0" => { parsed.castle = Some(false); return Some(parsed); }
+        _ => {}
+    }
+
+    // Promotion piece at the end, with or without '='
+    if let Some(last) = s.chars().last().filter(|c| "QRBNqrbn".contains(*c)) {
+        parsed.promotion = piece_type(char_to_piece(last.to_ascii_uppercase()));
+        s = s[..s.len() - 1].trim_end_matches('=');
+    }
+
+    // Mover: a stack in parentheses, a piece letter, or nothing for a pawn
+    if s.starts_with('(') {
+        let end = s.find(')')?;
+        let inner = &s[1..end];
+        if inner.len() != 2 || !inner.chars().all(|c| "PNBRQ".contains(c)) { return None; }
+        parsed.mover = s[..=end].to_string();
+        s = &s[end + 1..];
+    } else if let Some(c) = s.chars().next().filter(|c| "NBRQK".contains(*c)) {
+        parsed.mover = c.to_string();
+        s = &s[1..];
+    }
+
+    if s.len() < 2 || !s.is_ascii() { return None; }
+    let (hints, to) = s.split_at(s.len() - 2);
+    parsed.to_sq = parse_square(to);
+    if parsed.to_sq == SQ_NONE { return None; }
+
+    for c in hints.chars() {
+        match c {
+            'a'..='h' if parsed.from_file.is_none() && parsed.from_rank.is_none() && !parsed.capture => {
+                parsed.from_file = Some(c as u8 - b'a');
+            }
+            '1'..='8' if parsed.from_rank.is_none() && !parsed.capture => {
+                parsed.from_rank = Some(c as u8 - b'1');
+            }
+            'x' | ':' if !parsed.capture => parsed.capture = true,
+            _ => return None,
+        }
+    }
+    Some(parsed)
+}
+
+/// SAN without the check marker. `legal` are the legal moves of `board`,
+/// needed for disambiguation.
+fn san_body(board: &Board, mv: Move, legal: &[Move]) -> String {
+    let suffix = stack_suffix(board, mv);
+    match mv.move_type {
+        MT_CASTLE_K | MT_CASTLE_K_KLIK => return format!("O-O{}", suffix),
+        MT_CASTLE_Q | MT_CASTLE_Q_KLIK => return format!("O-O-O{}", suffix),
+        _ => {}
+    }
+
+    let mover = mover_letters(board, mv);
+    let capture = is_capture(board, mv);
+    let mut san = mover.clone();
+
+    // Other legal moves that would otherwise be written the same
+    let rivals: Vec<Move> = legal.iter().copied()
+        .filter(|&m| m.from_sq != mv.from_sq && m.to_sq == mv.to_sq && m.promotion == mv.promotion)
+        .filter(|&m| mover_letters(board, m) == mover && stack_suffix(board, m) == suffix)
+        .collect();
+
+    let from = square_name(mv.from_sq);
+    let (file, rank) = (&from[..1], &from[1..]);
+    if mover.is_empty() && capture {
+        // Pawn captures always name the file they leave
+        san.push_str(file);
+        if rivals.iter().any(|m| square_file(m.from_sq) == square_file(mv.from_sq)) {
+            san.push_str(rank);
+        }
+    } else if !rivals.is_empty() {
+        if rivals.iter().all(|m| square_file(m.from_sq) != square_file(mv.from_sq)) {
+            san.push_str(file);
+        } else if rivals.iter().all(|m| square_rank(m.from_sq) != square_rank(mv.from_sq)) {
+            san.push_str(rank);
+        } else {
+            san.push_str(&from);
+        }
+    }
+
+    if capture { san.push('x'); }
+    san.push_str(&square_name(mv.to_sq));
+    if mv.promotion != NONE {
+        san.push('=');
+        san.push(piece_char(make_piece(WHITE, mv.promotion)));
+    }
+    san.push_str(suffix);
+    san
+}
+
+/// Piece letter of the mover: empty for a pawn, both letters in stack
+/// order for a combined move.
+fn mover_letters(board: &Board, mv: Move) -> String {
+    let stack = &board.squares[mv.from_sq as usize];
+    let letter = |piece: u8| match piece_type(piece) {
+        PAWN => String::new(),
+        _ => piece_char(piece).to_ascii_uppercase().to_string(),
+    };
+    if stack.has_stack() && mv.unklik_index < 0 {
+        let [bottom, top] = stack.pieces.map(|p| piece_char(p).to_ascii_uppercase());
+        format!("({}{})", bottom, top)
+    } else if stack.has_stack() && (mv.unklik_index as u8) < stack.count {
+        letter(stack.pieces[mv.unklik_index as usize])
+    } else {
+        letter(stack.top())
+    }
+}
+
+fn is_capture(board: &Board, mv: Move) -> bool {
+    let target = &board.squares[mv.to_sq as usize];
+    mv.move_type == MT_EN_PASSANT
+        || (target.count > 0 && piece_color(target.top()) != board.turn)
+}
+
+fn stack_suffix(board: &Board, mv: Move) -> &'static str {
+    let leaves_stack = board.squares[mv.from_sq as usize].has_stack() && mv.unklik_index >= 0;
+    let kliks = matches!(mv.move_type, MT_KLIK | MT_UNKLIK_KLIK | MT_CASTLE_K_KLIK | MT_CASTLE_Q_KLIK);
+    match (leaves_stack, kliks) {
+        (true, true) => "=uk",
+        (true, false) => "=u",
+        (false, true) => "=k",
+        (false, false) => "",
+    }
+}
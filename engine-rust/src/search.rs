@@ -1,11 +1,18 @@
-/// Klikschaak Engine - Alpha-Beta Search
+//! Klikschaak Engine - Alpha-Beta Search
 
 #[cfg(not(target_arch = "wasm32"))]
 use std::time::Instant;
+use std::sync::Arc;
+use serde::Serialize;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicU8, Ordering};
 use crate::types::*;
 use crate::board::Board;
-use crate::movegen::{generate_moves, make_move, unmake_move, is_in_check};
-use crate::evaluate::{evaluate, CHECKMATE_SCORE, DRAW_SCORE};
+use crate::movegen::{
+    generate_moves, has_legal_move, make_move, unmake_move, is_in_check, is_attacked, is_checkmate, is_fifty_move_draw,
+};
+use crate::evaluate::{evaluate, EvalParams, CHECKMATE_SCORE, DRAW_SCORE};
+use crate::book::OpeningBook;
+use crate::rng::Rng;
 
 pub const MAX_DEPTH: usize = 64;
 pub const INFINITY: i32 = 1000000;
@@ -15,23 +22,87 @@ fn is_capture_type(mt: u8) -> bool {
     mt == MT_CAPTURE || mt == MT_EN_PASSANT || mt == MT_PROMOTION_CAPTURE
 }
 
+/// Why a position has no legal moves.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GameEnd {
+    Checkmate,
+    Stalemate,
+}
+
+impl GameEnd {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            GameEnd::Checkmate => "checkmate",
+            GameEnd::Stalemate => "stalemate",
+        }
+    }
+}
+
 // Search info
-#[derive(Clone)]
+#[derive(Clone, Serialize)]
 pub struct SearchInfo {
     pub nodes: u64,
     pub depth: u32,
+    pub seldepth: u32, // Deepest ply reached, quiescence included
     pub score: i32,
     pub pv: Vec<Move>,
     pub time_ms: u64,
+    pub time_budget_ms: Option<u64>, // None when the search has no time limit
     pub nps: u64,
+    pub aspiration_researches: u32, // Re-searches after aspiration window fails, all iterations
+    pub terminal: Option<GameEnd>,  // Set when the root has no legal moves
 }
 
 impl SearchInfo {
     pub fn new() -> Self {
-        SearchInfo { nodes: 0, depth: 0, score: 0, pv: Vec::new(), time_ms: 0, nps: 0 }
+        SearchInfo {
+            nodes: 0, depth: 0, seldepth: 0, score: 0, pv: Vec::new(), time_ms: 0, time_budget_ms: None, nps: 0,
+            aspiration_researches: 0, terminal: None,
+        }
+    }
+
+    /// The score (White's point of view) as (value, "cp" | "mate"), a mate
+    /// counted in moves and negative when Black mates.
+    pub fn score_report(&self) -> (i32, &'static str) {
+        self.score_report_rounded(1)
+    }
+
+    /// Like `score_report`, with centipawns rounded to `granularity` (see
+    /// `round_score`); mate counts stay exact.
+    pub fn score_report_rounded(&self, granularity: u32) -> (i32, &'static str) {
+        match mate_in(self.score) {
+            Some(moves) => (moves, "mate"),
+            None => (round_score(self.score, granularity), "cp"),
+        }
     }
 }
 
+impl Default for SearchInfo {
+    fn default() -> Self {
+        SearchInfo::new()
+    }
+}
+
+/// Moves to mate for a mate score, negative when the other side mates;
+/// None for any other score.
+pub fn mate_in(score: i32) -> Option<i32> {
+    if score.abs() < CHECKMATE_SCORE - MAX_DEPTH as i32 { return None; }
+    Some(if score > 0 { (CHECKMATE_SCORE - score + 1) / 2 } else { -(CHECKMATE_SCORE + score + 1) / 2 })
+}
+
+/// Round a centipawn score to the nearest multiple of `granularity` for
+/// display (halves round away from zero). Granularity 0 or 1 is a no-op.
+pub fn round_score(score: i32, granularity: u32) -> i32 {
+    if granularity <= 1 { return score; }
+    let g = granularity as i32;
+    let rounded = (score.abs() + g / 2) / g * g;
+    if score < 0 { -rounded } else { rounded }
+}
+
+/// Receives the search info after each completed iteration
+pub type InfoCallback = Box<dyn FnMut(&SearchInfo) + Send>;
+
 // Transposition table
 const TT_EXACT: u8 = 0;
 const TT_ALPHA: u8 = 1; // Upper bound
@@ -39,55 +110,139 @@ const TT_BETA: u8 = 2;  // Lower bound
 
 #[derive(Clone, Copy)]
 struct TTEntry {
-    key: u64,
     depth: i32,
     score: i32,
     flag: u8,
-    best_move: Option<Move>,
+    best_move: Option<PackedMove>,
+}
+
+impl TTEntry {
+    // Bits 0-23 score, 24-31 depth, 32-33 flag, 34-55 packed move + 1 (0
+    // for no move), 56 set so an empty slot (0) never decodes as an entry,
+    // 57-63 table generation (added by the table on store)
+    const OCCUPIED: u64 = 1 << 56;
+    const GENERATION_SHIFT: u32 = 57;
+    const GENERATIONS: u8 = 128;
+
+    fn pack(&self) -> u64 {
+        let mv = self.best_move.map_or(0, |m| m.0 as u64 + 1);
+        (self.score as u32 as u64 & 0xFF_FFFF)
+            | (self.depth.clamp(i8::MIN as i32, i8::MAX as i32) as i8 as u8 as u64) << 24
+            | (self.flag as u64 & 3) << 32
+            | mv << 34
+            | Self::OCCUPIED
+    }
+
+    fn unpack(data: u64) -> Self {
+        let mv = (data >> 34) & 0x3F_FFFF;
+        TTEntry {
+            score: ((data as u32) << 8) as i32 >> 8, // sign-extend 24 bits
+            depth: (data >> 24) as u8 as i8 as i32,
+            flag: (data >> 32 & 3) as u8,
+            best_move: if mv == 0 { None } else { Some(PackedMove(mv as u32 - 1)) },
+        }
+    }
+}
+
+/// Transposition table that search threads can share. A slot stores the
+/// key XORed with the packed entry next to the entry, so a slot torn by two
+/// threads writing at once fails the key check instead of returning a mix
+/// of both entries.
+///
+/// Entries are tagged with the generation they were stored in and read as
+/// empty in any other, so clearing the table is a counter bump rather than
+/// a pass over every slot.
+pub struct TranspositionTable {
+    slots: Vec<[AtomicU64; 2]>,
+    generation: AtomicU8,
+}
+
+impl TranspositionTable {
+    pub fn new(entries: usize) -> Self {
+        let entries = entries.max(1);
+        TranspositionTable {
+            slots: (0..entries).map(|_| [AtomicU64::new(0), AtomicU64::new(0)]).collect(),
+            generation: AtomicU8::new(0),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.slots.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.slots.is_empty()
+    }
+
+    /// Forget all entries by moving to the next generation. The slots are
+    /// only zeroed when the generation counter wraps around, so entries
+    /// that old can't come back.
+    pub fn clear(&self) {
+        let next = (self.generation.load(Ordering::Relaxed) + 1) % TTEntry::GENERATIONS;
+        if next == 0 {
+            for slot in &self.slots {
+                slot[0].store(0, Ordering::Relaxed);
+                slot[1].store(0, Ordering::Relaxed);
+            }
+        }
+        self.generation.store(next, Ordering::Relaxed);
+    }
+
+    fn probe(&self, key: u64) -> Option<TTEntry> {
+        let slot = &self.slots[(key as usize) % self.slots.len()];
+        let data = slot[1].load(Ordering::Relaxed);
+        if data & TTEntry::OCCUPIED == 0
+            || (data >> TTEntry::GENERATION_SHIFT) as u8 != self.generation.load(Ordering::Relaxed)
+            || slot[0].load(Ordering::Relaxed) ^ data != key
+        {
+            return None;
+        }
+        Some(TTEntry::unpack(data))
+    }
+
+    fn store(&self, key: u64, entry: TTEntry) {
+        let slot = &self.slots[(key as usize) % self.slots.len()];
+        let data = entry.pack() | (self.generation.load(Ordering::Relaxed) as u64) << TTEntry::GENERATION_SHIFT;
+        slot[0].store(key ^ data, Ordering::Relaxed);
+        slot[1].store(data, Ordering::Relaxed);
+    }
 }
 
 // Zobrist hashing
 pub struct ZobristKeys {
-    pub piece_keys: [[[u64; 64]; 2]; 15], // [piece_val][stack_idx][sq]
+    pub piece_keys: [[[u64; 64]; MAX_STACK]; 15], // [piece_val][stack_idx][sq]
     pub turn_key: u64,
     pub castling_keys: [u64; 16],
     pub ep_keys: [u64; 8],
+    pub unmoved_pawn_keys: [[u64; 8]; 2], // [color][file]
 }
 
 impl ZobristKeys {
     fn new(seed: u64) -> Self {
-        // Simple xorshift64 PRNG to match Python's Random(42) output
-        // We need deterministic keys but they don't need to match Python exactly
-        let mut state = seed;
-        let mut next = || -> u64 {
-            state ^= state << 13;
-            state ^= state >> 7;
-            state ^= state << 17;
-            state
-        };
-
-        let mut piece_keys = [[[0u64; 64]; 2]; 15];
-        for p in 0..15 {
-            for si in 0..2 {
-                for sq in 0..64 {
-                    piece_keys[p][si][sq] = next();
-                }
-            }
+        // SplitMix64 is deterministic and well mixed from its first output,
+        // even from a small seed
+        let mut rng = Rng::new(seed);
+        let mut next = || rng.next_u64();
+
+        let mut piece_keys = [[[0u64; 64]; MAX_STACK]; 15];
+        for key in piece_keys.iter_mut().flatten().flatten() {
+            *key = next();
         }
 
         let turn_key = next();
 
         let mut castling_keys = [0u64; 16];
-        for i in 0..16 {
-            castling_keys[i] = next();
-        }
+        castling_keys.fill_with(&mut next);
 
         let mut ep_keys = [0u64; 8];
-        for i in 0..8 {
-            ep_keys[i] = next();
+        ep_keys.fill_with(&mut next);
+
+        let mut unmoved_pawn_keys = [[0u64; 8]; 2];
+        for key in unmoved_pawn_keys.iter_mut().flatten() {
+            *key = next();
         }
 
-        ZobristKeys { piece_keys, turn_key, castling_keys, ep_keys }
+        ZobristKeys { piece_keys, turn_key, castling_keys, ep_keys, unmoved_pawn_keys }
     }
 }
 
@@ -113,23 +268,132 @@ pub fn compute_zobrist(board: &mut Board) {
 
     h ^= zob.castling_keys[board.castling as usize];
 
-    if board.ep_square != SQ_NONE {
-        h ^= zob.ep_keys[(board.ep_square & 7) as usize];
-    }
+    h ^= ep_hash(board);
+
+    h ^= unmoved_pawns_hash(board.unmoved_pawns);
 
     board.zobrist_hash = h;
 }
 
+/// Hash contribution of the en passant square, which only counts when a
+/// pawn of the side to move can make the capture (FIDE repetition rules;
+/// pins are not considered). compute_zobrist and the incremental update in
+/// make_move both use this, so they cannot disagree.
+pub fn ep_hash(board: &Board) -> u64 {
+    if board.ep_capturable() {
+        ZOBRIST.ep_keys[(board.ep_square & 7) as usize]
+    } else {
+        0
+    }
+}
+
+/// Hash contribution of the pawn double-move rights (one key per color and
+/// file). The hash is linear in the mask, so passing `old ^ new` gives the
+/// incremental update.
+pub fn unmoved_pawns_hash(mask: [u8; 2]) -> u64 {
+    let mut h = 0;
+    for (color, &bits) in mask.iter().enumerate() {
+        let mut bits = bits;
+        while bits != 0 {
+            h ^= ZOBRIST.unmoved_pawn_keys[color][bits.trailing_zeros() as usize];
+            bits &= bits - 1;
+        }
+    }
+    h
+}
+
+/// Late move reduction schedule. A quiet, non-checking move searched after
+/// the first `min_moves` moves at depth `min_depth` or more is reduced by
+/// `base + ln(depth) * ln(move_number) / divisor` plies, one less at PV
+/// nodes.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LmrConfig {
+    pub enabled: bool,
+    pub base: f64,
+    pub divisor: f64,
+    pub min_depth: i32,
+    pub min_moves: u32,
+}
+
+impl Default for LmrConfig {
+    fn default() -> Self {
+        LmrConfig { enabled: true, base: 0.75, divisor: 2.25, min_depth: 3, min_moves: 3 }
+    }
+}
+
+/// Futility pruning near the leaves, at depths 1 to `max_depth` (at most
+/// FUTILITY_MAX_DEPTH). Out of check, quiet moves are skipped when the
+/// static eval plus `margins[depth]` can't reach alpha; with `reverse`, a
+/// non-PV node whose static eval still beats beta by `reverse_margin` per
+/// ply of depth returns the static eval (static null move). Neither
+/// applies when alpha or beta is a mate score.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FutilityConfig {
+    pub enabled: bool,
+    pub margins: [i32; FUTILITY_MAX_DEPTH as usize + 1],
+    pub max_depth: i32,
+    pub reverse: bool,
+    pub reverse_margin: i32,
+}
+
+impl Default for FutilityConfig {
+    fn default() -> Self {
+        FutilityConfig { enabled: true, margins: [0, 100, 300, 500, 700], max_depth: 3, reverse: true, reverse_margin: 120 }
+    }
+}
+
+/// Reductions by depth and move number (both capped at 63), built from an
+/// `LmrConfig`.
+#[derive(Clone)]
+pub struct LmrTable {
+    config: LmrConfig,
+    reductions: [[u8; 64]; 64],
+}
+
+impl LmrTable {
+    pub fn new(config: LmrConfig) -> Self {
+        let mut reductions = [[0u8; 64]; 64];
+        for (depth, row) in reductions.iter_mut().enumerate().skip(1) {
+            for (moves, r) in row.iter_mut().enumerate().skip(1) {
+                let value = config.base + (depth as f64).ln() * (moves as f64).ln() / config.divisor;
+                *r = value.clamp(0.0, 63.0) as u8;
+            }
+        }
+        LmrTable { config, reductions }
+    }
+
+    pub fn config(&self) -> &LmrConfig {
+        &self.config
+    }
+
+    /// Plies to reduce the `move_number`th legal move (1-based) at `depth`,
+    /// before the quiet/check conditions and the PV adjustment.
+    pub fn reduction(&self, depth: i32, move_number: u32) -> i32 {
+        if !self.config.enabled || depth < self.config.min_depth || move_number <= self.config.min_moves {
+            return 0;
+        }
+        self.reductions[depth.clamp(0, 63) as usize][move_number.min(63) as usize] as i32
+    }
+}
+
 pub struct SearchEngine {
     nodes: u64,
     #[cfg(not(target_arch = "wasm32"))]
     start_time: Instant,
+    // Hard time limit: the search aborts mid-iteration once it is reached
     max_time_ms: u64,
+    // Soft time limit: no new iteration starts once it has passed
+    soft_time_ms: u64,
+    max_nodes: u64,
     stop_search: bool,
+    // Set from another thread to abort the running search
+    stop_flag: Arc<AtomicBool>,
+    // Helper threads of search_parallel also stop when this is set; unlike
+    // stop_flag it is not reset when a search starts
+    abort_flag: Option<Arc<AtomicBool>>,
 
-    // Transposition table (fixed size array)
-    tt: Vec<Option<TTEntry>>,
-    tt_size: usize,
+    // Transposition table, shared with the helper threads of search_parallel
+    tt: Arc<TranspositionTable>,
 
     // Killer moves
     killers: [[Option<Move>; 2]; MAX_DEPTH],
@@ -139,36 +403,240 @@ pub struct SearchEngine {
 
     // Countermove heuristic
     countermove: [[Option<Move>; 64]; 64],
+
+    // Called after each completed iteration; prints a UCI info line when unset
+    info_callback: Option<InfoCallback>,
+    // Without a callback: 0 prints nothing, 1 the final iteration, 2 every
+    // iteration
+    verbosity: u8,
+
+    // Internal iterative deepening at PV nodes without a TT move
+    use_iid: bool,
+    futility: FutilityConfig,
+    // Probe and store the transposition table in the main search
+    use_tt: bool,
+
+    // Draw avoidance in centipawns for the searching side, applied only
+    // while it is not behind in material
+    contempt: i32,
+    root_color: u8,
+
+    // Hashes of the game positions before the root (oldest first), and
+    // that history plus the current search path during a search
+    game_history: Vec<u64>,
+    positions: Vec<u64>,
+    root_ply: usize,
+    // Check extensions taken on the current path
+    extensions: i32,
+    // Deepest ply from the root reached in this search
+    seldepth: u32,
+
+    eval_params: EvalParams,
+    lmr: LmrTable,
+    book: Option<OpeningBook>,
+
+    // Non-zero in helper threads: perturbs the order of quiet moves so the
+    // threads search different parts of the tree first
+    ordering_seed: u64,
 }
 
-// Futility margins
-const FUTILITY_MARGINS: [i32; 3] = [0, 100, 300];
+// Deepest depth `FutilityConfig` has margins for
+pub const FUTILITY_MAX_DEPTH: i32 = 4;
+// Quiescence delta pruning: slack over the material a capture wins
+const DELTA_MARGIN: i32 = 200;
 const ASPIRATION_WINDOW: i32 = 50;
+// Widenings of a failing window side before it opens fully
+const ASPIRATION_MAX_WIDENINGS: u32 = 2;
+// Internal iterative deepening: minimum depth and reduction
+const IID_MIN_DEPTH: i32 = 4;
+const IID_REDUCTION: i32 = 2;
+// Stop early under a time limit when every other root move is this much worse
+const ONLY_MOVE_MARGIN: i32 = 200;
+const ONLY_MOVE_MIN_DEPTH: u32 = 4;
+// History is divided by 2^this on new_game
+const NEW_GAME_HISTORY_SHIFT: u32 = 2;
+// Check extensions allowed along one path from the root
+const MAX_CHECK_EXTENSIONS: i32 = 4;
+// Nodes between polls of the clock and the stop flag
+const TIME_CHECK_INTERVAL: u64 = 2048;
+// Assumed growth of the next iteration before two iterations were timed,
+// and the bound on the measured growth
+const DEFAULT_BRANCHING: u64 = 4;
+const MAX_BRANCHING: u64 = 16;
+// Share of the time limit after which no new iteration is started
+const SOFT_TIME_PERCENT: u64 = 50;
 
 impl SearchEngine {
     pub fn new() -> Self {
-        let tt_size = 1 << 20; // ~1M entries
+        SearchEngine::with_tt(Arc::new(TranspositionTable::new(1 << 20))) // ~1M entries
+    }
+
+    /// An engine using `tt`, which may be shared with other engines (each
+    /// keeps its own killers and history).
+    pub fn with_tt(tt: Arc<TranspositionTable>) -> Self {
         SearchEngine {
             nodes: 0,
             #[cfg(not(target_arch = "wasm32"))]
             start_time: Instant::now(),
             max_time_ms: u64::MAX,
+            soft_time_ms: u64::MAX,
+            max_nodes: u64::MAX,
             stop_search: false,
-            tt: vec![None; tt_size],
-            tt_size,
+            stop_flag: Arc::new(AtomicBool::new(false)),
+            abort_flag: None,
+            tt,
             killers: [[None; 2]; MAX_DEPTH],
             history: [[0; 64]; 64],
             countermove: [[None; 64]; 64],
+            info_callback: None,
+            verbosity: 2,
+            use_iid: true,
+            futility: FutilityConfig::default(),
+            use_tt: true,
+            contempt: 0,
+            root_color: WHITE,
+            game_history: Vec::new(),
+            positions: Vec::with_capacity(MAX_DEPTH),
+            root_ply: 0,
+            extensions: 0,
+            seldepth: 0,
+            eval_params: EvalParams::default(),
+            lmr: LmrTable::new(LmrConfig::default()),
+            book: None,
+            ordering_seed: 0,
+        }
+    }
+
+    /// Receive search progress after every completed iteration instead of
+    /// having it printed to stdout.
+    pub fn set_info_callback(&mut self, callback: InfoCallback) {
+        self.info_callback = Some(callback);
+    }
+
+    pub fn clear_info_callback(&mut self) {
+        self.info_callback = None;
+    }
+
+    /// How much progress is printed to stdout when no info callback is
+    /// set: 0 nothing, 1 only the last completed iteration, 2 (the
+    /// default) every iteration.
+    pub fn set_verbosity(&mut self, level: u8) {
+        self.verbosity = level;
+    }
+
+    /// Enable or disable internal iterative deepening (on by default).
+    pub fn set_iid(&mut self, enabled: bool) {
+        self.use_iid = enabled;
+    }
+
+    /// Futility and reverse futility pruning (defaults to
+    /// `FutilityConfig::default()`; set `enabled: false` to turn both off).
+    pub fn set_futility(&mut self, config: FutilityConfig) {
+        self.futility = FutilityConfig { max_depth: config.max_depth.min(FUTILITY_MAX_DEPTH), ..config };
+    }
+
+    pub fn futility(&self) -> &FutilityConfig {
+        &self.futility
+    }
+
+    /// Enable or disable transposition table probes and stores in the
+    /// main search (on by default), to measure what the table saves.
+    pub fn set_use_tt(&mut self, enabled: bool) {
+        self.use_tt = enabled;
+    }
+
+    /// Draw avoidance in centipawns. A draw counts as -contempt for the
+    /// searching side unless it is behind in material, so saving draws are
+    /// still taken when worse.
+    pub fn set_contempt(&mut self, contempt: i32) {
+        self.contempt = contempt;
+    }
+
+    /// Evaluation weights used at the leaves (defaults to
+    /// `EvalParams::default()`).
+    pub fn set_eval_params(&mut self, params: EvalParams) {
+        self.eval_params = params;
+    }
+
+    /// Late move reduction schedule (defaults to `LmrConfig::default()`;
+    /// set `enabled: false` to search every move at full depth).
+    pub fn set_lmr(&mut self, config: LmrConfig) {
+        self.lmr = LmrTable::new(config);
+    }
+
+    pub fn lmr_table(&self) -> &LmrTable {
+        &self.lmr
+    }
+
+    /// Opening book consulted before each search; a book hit is returned
+    /// at once without searching. `None` (the default) turns it off.
+    pub fn set_book(&mut self, book: Option<OpeningBook>) {
+        self.book = book;
+    }
+
+    /// Zobrist hashes of the positions played before the one being searched,
+    /// oldest first, so repetitions of earlier game positions are seen.
+    pub fn set_game_history(&mut self, hashes: &[u64]) {
+        self.game_history = hashes.to_vec();
+    }
+
+    /// Score of a draw from the side to move's point of view.
+    pub fn draw_score(&self, board: &Board) -> i32 {
+        // No contempt when behind in material
+        let (own, opp) = (self.root_color as usize, opposite_color(self.root_color) as usize);
+        if self.contempt == 0 || board.material[own] < board.material[opp] {
+            return DRAW_SCORE;
+        }
+        if board.turn == self.root_color {
+            DRAW_SCORE - self.contempt
+        } else {
+            DRAW_SCORE + self.contempt
         }
     }
 
+    /// The current position (last on the path) occurred before with the
+    /// same side to move since the last irreversible move. One earlier
+    /// occurrence is enough to score a draw: if repeating is best now, it
+    /// will be again, and the opponent can complete the threefold.
+    fn is_repetition(&self, board: &Board) -> bool {
+        let len = self.positions.len();
+        let limit = (board.halfmove_clock as usize).min(len - 1);
+        (2..=limit).step_by(2).any(|back| self.positions[len - 1 - back] == board.zobrist_hash)
+    }
+
+    /// Handle for cancelling a running search from another thread. Setting it
+    /// makes `search` return its best move so far; it is reset on every call.
+    pub fn stop_handle(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.stop_flag)
+    }
+
+    /// Forget everything learned: TT, killers, history and countermoves.
     pub fn clear(&mut self) {
-        for entry in self.tt.iter_mut() { *entry = None; }
+        self.tt.clear();
         self.killers = [[None; 2]; MAX_DEPTH];
         self.history = [[0; 64]; 64];
         self.countermove = [[None; 64]; 64];
     }
 
+    /// Reset between games. Positions from the old game won't recur, so the
+    /// TT, killers and game history go; history is only aged, and
+    /// countermoves are kept, since good quiet moves tend to stay good.
+    pub fn new_game(&mut self) {
+        self.tt.clear();
+        self.killers = [[None; 2]; MAX_DEPTH];
+        self.game_history.clear();
+        for row in self.history.iter_mut() {
+            for h in row.iter_mut() {
+                *h >>= NEW_GAME_HISTORY_SHIFT;
+            }
+        }
+    }
+
+    /// Current history heuristic score of a quiet move
+    pub fn history_score(&self, mv: Move) -> i32 {
+        self.history[mv.from_sq as usize][mv.to_sq as usize]
+    }
+
     fn decay_history(&mut self) {
         for i in 0..64 {
             for j in 0..64 {
@@ -178,41 +646,106 @@ impl SearchEngine {
     }
 
     pub fn search(&mut self, board: &mut Board, depth: u32, time_limit_ms: Option<u64>) -> (Option<Move>, SearchInfo) {
+        self.search_with_limits(board, depth, time_limit_ms, None)
+    }
+
+    /// Search bounded by depth, wall-clock time and/or node count, whichever
+    /// is hit first. Node limits make results reproducible across machines.
+    pub fn search_with_limits(&mut self, board: &mut Board, depth: u32, time_limit_ms: Option<u64>,
+                              max_nodes: Option<u64>) -> (Option<Move>, SearchInfo) {
         self.nodes = 0;
         #[cfg(not(target_arch = "wasm32"))]
         { self.start_time = Instant::now(); }
         self.max_time_ms = time_limit_ms.unwrap_or(u64::MAX);
+        self.soft_time_ms = time_limit_ms.map_or(u64::MAX, |ms| ms * SOFT_TIME_PERCENT / 100);
+        self.max_nodes = max_nodes.unwrap_or(u64::MAX);
         self.stop_search = false;
+        self.stop_flag.store(false, Ordering::Relaxed);
+        // Killers are indexed by remaining depth, so they don't carry over
+        // to another search; history and countermoves do
+        self.killers = [[None; 2]; MAX_DEPTH];
 
         compute_zobrist(board);
+        self.root_color = board.turn;
+        self.positions.clear();
+        self.positions.extend_from_slice(&self.game_history);
+        self.positions.push(board.zobrist_hash);
+        self.root_ply = self.positions.len() - 1;
+        self.extensions = 0;
+        self.seldepth = 0;
 
         let mut info = SearchInfo::new();
+        info.time_budget_ms = time_limit_ms;
+
+        // Nothing to search: report the game result instead of a null move
+        if !has_legal_move(board) {
+            let (end, score) = if is_in_check(board, board.turn) {
+                (GameEnd::Checkmate, if board.turn == WHITE { -CHECKMATE_SCORE } else { CHECKMATE_SCORE })
+            } else {
+                (GameEnd::Stalemate, DRAW_SCORE)
+            };
+            info.terminal = Some(end);
+            info.score = score;
+            return (None, info);
+        }
+
+        if let Some(mv) = self.book.as_mut().and_then(|book| book.probe(board)) {
+            info.pv = vec![mv];
+            return (Some(mv), info);
+        }
+
         let mut best_move: Option<Move> = None;
         let mut prev_score = 0i32;
+        let mut prev_iter_nodes = 0u64;
 
         for d in 1..=depth {
             if self.stop_search { break; }
+            let iter_start_ms = self.elapsed_ms();
+            let iter_start_nodes = self.nodes;
 
             self.decay_history();
 
             let (score, pv) = if d <= 1 {
                 self.alpha_beta(board, d as i32, -INFINITY, INFINITY, None)
             } else {
-                let alpha_w = prev_score - ASPIRATION_WINDOW;
-                let beta_w = prev_score + ASPIRATION_WINDOW;
-
-                let (score, pv) = self.alpha_beta(board, d as i32, alpha_w, beta_w, None);
-
-                if !self.stop_search && (score <= alpha_w || score >= beta_w) {
-                    self.alpha_beta(board, d as i32, -INFINITY, INFINITY, None)
-                } else {
-                    (score, pv)
+                // On a fail, double the window on the failing side; after
+                // ASPIRATION_MAX_WIDENINGS fails that side opens fully
+                let (mut alpha_w, mut beta_w) = (prev_score - ASPIRATION_WINDOW, prev_score + ASPIRATION_WINDOW);
+                let (mut low_delta, mut high_delta) = (ASPIRATION_WINDOW, ASPIRATION_WINDOW);
+                let (mut low_fails, mut high_fails) = (0, 0);
+                loop {
+                    let (score, pv) = self.alpha_beta(board, d as i32, alpha_w, beta_w, None);
+                    if self.stop_search { break (score, pv); }
+                    if score <= alpha_w {
+                        low_fails += 1;
+                        low_delta *= 2;
+                        alpha_w = if low_fails > ASPIRATION_MAX_WIDENINGS { -INFINITY } else { prev_score - low_delta };
+                    } else if score >= beta_w {
+                        high_fails += 1;
+                        high_delta *= 2;
+                        beta_w = if high_fails > ASPIRATION_MAX_WIDENINGS { INFINITY } else { prev_score + high_delta };
+                    } else {
+                        break (score, pv);
+                    }
+                    info.aspiration_researches += 1;
                 }
             };
 
             if !self.stop_search {
+                // Read the clock before the PV work below, capped at the hard
+                // limit: its last poll passed, but up to TIME_CHECK_INTERVAL
+                // nodes may have run since
+                let elapsed = self.elapsed_ms().min(self.max_time_ms);
+
+                // The returned PV is cut short by TT cutoffs and re-searches;
+                // the TT line is longer and starts with the same move
+                let tt_pv = self.extract_pv(board, MAX_DEPTH);
+                let pv = if tt_pv.first() == pv.first() && tt_pv.len() > pv.len() { tt_pv } else { pv };
+                debug_assert!(verify_pv(board, &pv), "illegal PV at depth {} from {}", d, board.get_fen());
+
                 prev_score = score;
                 info.depth = d;
+                info.seldepth = self.seldepth;
                 info.score = if board.turn == WHITE { score } else { -score };
                 info.pv = pv.clone();
                 info.nodes = self.nodes;
@@ -221,19 +754,44 @@ impl SearchEngine {
                     best_move = Some(*mv);
                 }
 
-                #[cfg(not(target_arch = "wasm32"))]
-                let elapsed = self.start_time.elapsed().as_millis() as u64;
-                #[cfg(target_arch = "wasm32")]
-                let elapsed = 0u64;
                 info.time_ms = elapsed;
-                info.nps = if elapsed > 0 { self.nodes * 1000 / elapsed } else { 0 };
+                info.nps = (self.nodes * 1000).checked_div(elapsed).unwrap_or(0);
+
+                if let Some(callback) = self.info_callback.as_mut() {
+                    callback(&info);
+                } else if self.verbosity >= 2 {
+                    print_info(&info);
+                }
+
+                // A forced move is not worth the rest of the time budget
+                if time_limit_ms.is_some() && d >= ONLY_MOVE_MIN_DEPTH {
+                    if let Some(mv) = best_move {
+                        if self.is_only_move(board, mv, score, d as i32 / 2) {
+                            break;
+                        }
+                    }
+                }
 
-                let pv_str: Vec<String> = pv.iter().map(|m| m.to_uci()).collect();
-                println!("info depth {} score cp {} nodes {} nps {} time {} pv {}",
-                    d, info.score, self.nodes, info.nps, info.time_ms, pv_str.join(" "));
+                // Past the soft limit, or when the next iteration can't
+                // finish before the hard one, stop: it would be aborted and
+                // its work thrown away
+                let iter_nodes = self.nodes - iter_start_nodes;
+                let branching = match iter_nodes.checked_div(prev_iter_nodes) {
+                    Some(ratio) if d > 2 => ratio.clamp(1, MAX_BRANCHING),
+                    _ => DEFAULT_BRANCHING,
+                };
+                prev_iter_nodes = iter_nodes;
+                let predicted = elapsed.saturating_sub(iter_start_ms).saturating_mul(branching);
+                if elapsed >= self.soft_time_ms || elapsed.saturating_add(predicted) > self.max_time_ms {
+                    break;
+                }
             }
         }
 
+        if self.info_callback.is_none() && self.verbosity == 1 && info.depth > 0 {
+            print_info(&info);
+        }
+
         if best_move.is_none() {
             let moves = generate_moves(board, true, false);
             if !moves.is_empty() {
@@ -244,22 +802,133 @@ impl SearchEngine {
         (best_move, info)
     }
 
+    /// Reduced-depth null-window check that every root move other than
+    /// `best` scores at least ONLY_MOVE_MARGIN below `score` (side to move
+    /// POV). Also true when `best` is the only legal move.
+    fn is_only_move(&mut self, board: &mut Board, best: Move, score: i32, depth: i32) -> bool {
+        let threshold = score - ONLY_MOVE_MARGIN;
+        for mv in generate_moves(board, true, false) {
+            if mv == best { continue; }
+            let undo = make_move(board, mv);
+            self.positions.push(board.zobrist_hash);
+            let (s, _) = self.alpha_beta(board, depth - 1, -threshold, -threshold + 1, Some(mv));
+            self.positions.pop();
+            unmake_move(board, mv, &undo);
+            if self.stop_search || -s >= threshold {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Follow the TT best moves from `board` for at most `max_len` plies.
+    /// Stops at a missing entry, a move that isn't legal here (a hash
+    /// collision) or a position already on the line. `board` is restored.
+    pub fn extract_pv(&self, board: &mut Board, max_len: usize) -> Vec<Move> {
+        let mut pv = Vec::new();
+        let mut undos = Vec::new();
+        let mut seen = vec![board.zobrist_hash];
+        while pv.len() < max_len {
+            let entry = match self.tt.probe(board.zobrist_hash) {
+                Some(entry) => entry,
+                None => break,
+            };
+            let mv = match entry.best_move.map(PackedMove::unpack) {
+                Some(mv) if generate_moves(board, true, false).contains(&mv) => mv,
+                _ => break,
+            };
+            undos.push(make_move(board, mv));
+            pv.push(mv);
+            if seen.contains(&board.zobrist_hash) { break; }
+            seen.push(board.zobrist_hash);
+        }
+        for (mv, undo) in pv.iter().zip(&undos).rev() {
+            unmake_move(board, *mv, undo);
+        }
+        pv
+    }
+
+    /// Lazy SMP: search with `threads - 1` helper threads sharing this
+    /// engine's transposition table. The helpers search the same position
+    /// with slightly different move ordering, so the entries they leave
+    /// speed up the main thread; the best move, score and PV come from the
+    /// main thread alone, `nodes` and `nps` count all threads.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn search_parallel(&mut self, board: &mut Board, depth: u32, time_limit_ms: Option<u64>,
+                           threads: usize) -> (Option<Move>, SearchInfo) {
+        if threads <= 1 {
+            return self.search(board, depth, time_limit_ms);
+        }
+        let abort = Arc::new(AtomicBool::new(false));
+        let helpers: Vec<(SearchEngine, Board)> = (1..threads).map(|i| {
+            let mut helper = SearchEngine::with_tt(Arc::clone(&self.tt));
+            helper.info_callback = Some(Box::new(|_| {}));
+            helper.abort_flag = Some(Arc::clone(&abort));
+            helper.ordering_seed = i as u64;
+            helper.use_iid = self.use_iid;
+            helper.futility = self.futility.clone();
+            helper.use_tt = self.use_tt;
+            helper.contempt = self.contempt;
+            helper.game_history = self.game_history.clone();
+            helper.eval_params = self.eval_params.clone();
+            helper.lmr = self.lmr.clone();
+            (helper, board.clone())
+        }).collect();
+
+        std::thread::scope(|scope| {
+            let handles: Vec<_> = helpers.into_iter().map(|(mut helper, mut board)| {
+                scope.spawn(move || helper.search(&mut board, depth, time_limit_ms).1.nodes)
+            }).collect();
+
+            let (best_move, mut info) = self.search(board, depth, time_limit_ms);
+            abort.store(true, Ordering::Relaxed);
+            for handle in handles {
+                info.nodes += handle.join().unwrap_or(0);
+            }
+            info.nps = (info.nodes * 1000).checked_div(info.time_ms).unwrap_or(0);
+            (best_move, info)
+        })
+    }
+
+    /// Milliseconds since the search started (always 0 on WASM, which has
+    /// no clock and searches by depth only).
+    fn elapsed_ms(&self) -> u64 {
+        #[cfg(not(target_arch = "wasm32"))]
+        { self.start_time.elapsed().as_millis() as u64 }
+        #[cfg(target_arch = "wasm32")]
+        { 0 }
+    }
+
+    /// Poll the hard time limit and the stop flag every TIME_CHECK_INTERVAL
+    /// nodes, and the node limit on every node. alpha_beta and quiescence
+    /// both count nodes, so both call this or the interval can be skipped.
+    /// Returns true once the search has to stop.
+    #[inline]
+    fn check_limits(&mut self) -> bool {
+        if self.nodes.is_multiple_of(TIME_CHECK_INTERVAL)
+            && (self.elapsed_ms() >= self.max_time_ms
+                || self.stop_flag.load(Ordering::Relaxed)
+                || self.abort_flag.as_ref().is_some_and(|abort| abort.load(Ordering::Relaxed))) {
+            self.stop_search = true;
+        }
+        if self.nodes >= self.max_nodes {
+            self.stop_search = true;
+        }
+        self.stop_search
+    }
+
     fn alpha_beta(&mut self, board: &mut Board, depth: i32, mut alpha: i32, beta: i32,
                   prev_move: Option<Move>) -> (i32, Vec<Move>) {
         self.nodes += 1;
+        if self.check_limits() { return (0, Vec::new()); }
 
-        // Time check (disabled on WASM — depth-limited only)
-        #[cfg(not(target_arch = "wasm32"))]
-        if self.nodes % 4096 == 0 {
-            let elapsed = self.start_time.elapsed().as_millis() as u64;
-            if elapsed >= self.max_time_ms {
-                self.stop_search = true;
-                return (0, Vec::new());
-            }
+        // Repetition or fifty-move draw below the root (a mate on the
+        // hundredth half-move still counts as mate)
+        if self.positions.len() > self.root_ply + 1
+            && (self.is_repetition(board) || (is_fifty_move_draw(board) && !is_checkmate(board))) {
+            return (self.draw_score(board), Vec::new());
         }
 
-        if self.stop_search { return (0, Vec::new()); }
-
         // Leaf node
         if depth <= 0 {
             let score = self.quiescence(board, alpha, beta, 0);
@@ -268,33 +937,45 @@ impl SearchEngine {
 
         // TT lookup
         let tt_key = board.zobrist_hash;
-        let tt_idx = (tt_key as usize) % self.tt_size;
         let mut tt_move: Option<Move> = None;
 
-        if let Some(entry) = &self.tt[tt_idx] {
-            if entry.key == tt_key {
-                if entry.depth >= depth {
-                    match entry.flag {
-                        TT_EXACT => return (entry.score, entry.best_move.map_or(Vec::new(), |m| vec![m])),
-                        TT_ALPHA => { if entry.score <= alpha { return (alpha, Vec::new()); } }
-                        TT_BETA => { if entry.score >= beta { return (beta, Vec::new()); } }
-                        _ => {}
-                    }
+        let entry = if self.use_tt { self.tt.probe(tt_key) } else { None };
+        if let Some(entry) = entry {
+            if entry.depth >= depth {
+                match entry.flag {
+                    TT_EXACT => return (entry.score, entry.best_move.map_or(Vec::new(), |m| vec![m.unpack()])),
+                    TT_ALPHA if entry.score <= alpha => return (alpha, Vec::new()),
+                    TT_BETA if entry.score >= beta => return (beta, Vec::new()),
+                    _ => {}
                 }
-                tt_move = entry.best_move;
             }
+            tt_move = entry.best_move.map(PackedMove::unpack);
+        }
+
+        // Internal iterative deepening: a reduced search supplies a move to try first
+        let is_pv = beta - alpha > 1;
+        if self.use_iid && is_pv && tt_move.is_none() && depth >= IID_MIN_DEPTH {
+            let (_, pv) = self.alpha_beta(board, depth - IID_REDUCTION, alpha, beta, prev_move);
+            if self.stop_search { return (0, Vec::new()); }
+            tt_move = pv.first().copied();
         }
 
         let in_check = is_in_check(board, board.turn);
 
-        // Futility pruning
+        // Futility pruning, off near mate scores so mates aren't pruned away
         let mut futile = false;
-        if !in_check && depth <= 2 {
+        let mate_bound = CHECKMATE_SCORE - MAX_DEPTH as i32;
+        if self.futility.enabled && !in_check && depth <= self.futility.max_depth
+            && alpha.abs() < mate_bound && beta.abs() < mate_bound {
             let static_eval = {
-                let e = evaluate(board);
+                let e = evaluate(board, &self.eval_params);
                 if board.turn == BLACK { -e } else { e }
             };
-            if static_eval + FUTILITY_MARGINS[depth as usize] <= alpha {
+            // Reverse futility: too far above beta for a quiet reply to matter
+            if self.futility.reverse && !is_pv && static_eval - self.futility.reverse_margin * depth >= beta {
+                return (static_eval, Vec::new());
+            }
+            if static_eval + self.futility.margins[depth as usize] <= alpha {
                 futile = true;
             }
         }
@@ -306,7 +987,7 @@ impl SearchEngine {
             return if in_check {
                 (-CHECKMATE_SCORE + (MAX_DEPTH as i32 - depth), Vec::new())
             } else {
-                (DRAW_SCORE, Vec::new())
+                (self.draw_score(board), Vec::new())
             };
         }
 
@@ -338,37 +1019,45 @@ impl SearchEngine {
 
             legal_count += 1;
             let gives_check = is_in_check(board, board.turn);
+            self.positions.push(board.zobrist_hash);
+
+            // Checks are searched one ply deeper, a limited number of times per path
+            let extension = (gives_check && self.extensions < MAX_CHECK_EXTENSIONS) as i32;
+            let new_depth = depth - 1 + extension;
+            self.extensions += extension;
 
             let (score, child_pv) = if legal_count == 1 {
-                let (s, pv) = self.alpha_beta(board, depth - 1, -beta, -alpha, Some(mv));
+                let (s, pv) = self.alpha_beta(board, new_depth, -beta, -alpha, Some(mv));
                 (-s, pv)
             } else {
-                // LMR
-                let reduction = if depth >= 3 && legal_count > 3 && !is_cap && !in_check && !gives_check {
-                    1
+                // PVS: later moves get a null-window search, reduced by LMR
+                // for quiet late moves. One that beats alpha is re-searched at
+                // full depth, then with the full window in a PV node so its
+                // score is exact and its PV complete.
+                let reduction = if !is_cap && !in_check && !gives_check {
+                    (self.lmr.reduction(depth, legal_count) - is_pv as i32).min(depth - 2).max(0)
                 } else {
                     0
                 };
 
-                let (s, _) = self.alpha_beta(board, depth - 1 - reduction, -alpha - 1, -alpha, Some(mv));
+                let (s, _) = self.alpha_beta(board, new_depth - reduction, -alpha - 1, -alpha, Some(mv));
                 let mut score = -s;
 
-                let child_pv = if reduction > 0 && score > alpha {
-                    let (s, _) = self.alpha_beta(board, depth - 1, -alpha - 1, -alpha, Some(mv));
+                if reduction > 0 && score > alpha {
+                    let (s, _) = self.alpha_beta(board, new_depth, -alpha - 1, -alpha, Some(mv));
                     score = -s;
-                    Vec::new()
-                } else {
-                    Vec::new()
-                };
+                }
 
-                if alpha < score && score < beta {
-                    let (s, pv) = self.alpha_beta(board, depth - 1, -beta, -score, Some(mv));
+                if is_pv && score > alpha {
+                    let (s, pv) = self.alpha_beta(board, new_depth, -beta, -alpha, Some(mv));
                     (-s, pv)
                 } else {
-                    (score, child_pv)
+                    (score, Vec::new())
                 }
             };
 
+            self.extensions -= extension;
+            self.positions.pop();
             unmake_move(board, mv, &undo);
 
             if self.stop_search { return (0, Vec::new()); }
@@ -387,11 +1076,9 @@ impl SearchEngine {
                 // Beta cutoff
                 if !is_cap {
                     let d = depth as usize;
-                    if d < MAX_DEPTH {
-                        if self.killers[d][0] != Some(mv) {
-                            self.killers[d][1] = self.killers[d][0];
-                            self.killers[d][0] = Some(mv);
-                        }
+                    if d < MAX_DEPTH && self.killers[d][0] != Some(mv) {
+                        self.killers[d][1] = self.killers[d][0];
+                        self.killers[d][0] = Some(mv);
                     }
                     self.history[mv.from_sq as usize][mv.to_sq as usize] += depth * depth;
                     if let Some(pm) = prev_move {
@@ -407,7 +1094,7 @@ impl SearchEngine {
             return if in_check {
                 (-CHECKMATE_SCORE + (MAX_DEPTH as i32 - depth), Vec::new())
             } else {
-                (DRAW_SCORE, Vec::new())
+                (self.draw_score(board), Vec::new())
             };
         }
 
@@ -420,23 +1107,28 @@ impl SearchEngine {
             TT_EXACT
         };
 
-        self.tt[tt_idx] = Some(TTEntry {
-            key: tt_key,
-            depth,
-            score: best_score,
-            flag,
-            best_move,
-        });
+        if self.use_tt {
+            self.tt.store(tt_key, TTEntry {
+                depth,
+                score: best_score,
+                flag,
+                best_move: best_move.map(PackedMove::pack),
+            });
+        }
 
         (best_score, best_pv)
     }
 
     fn quiescence(&mut self, board: &mut Board, mut alpha: i32, beta: i32, qdepth: i32) -> i32 {
         self.nodes += 1;
+        if self.check_limits() { return 0; }
+        // Captures are not pushed onto the path, so add them to its length
+        let ply = (self.positions.len() - 1 - self.root_ply) as u32 + qdepth as u32;
+        self.seldepth = self.seldepth.max(ply);
 
         // Stand pat
         let stand_pat = {
-            let e = evaluate(board);
+            let e = evaluate(board, &self.eval_params);
             if board.turn == BLACK { -e } else { e }
         };
 
@@ -444,16 +1136,18 @@ impl SearchEngine {
         if alpha < stand_pat { alpha = stand_pat; }
         if qdepth >= 10 { return alpha; }
 
-        // Captures only
+        // Captures only, ordered as in the main search
         let captures = generate_moves(board, false, true);
+        let ordered = self.order_moves(board, &captures, MAX_DEPTH, None, None);
 
-        // Sort captures by MVV-LVA
-        let mut scored: Vec<(i32, Move)> = captures.iter()
-            .map(|&m| (self.mvv_lva_score(board, m), m))
-            .collect();
-        scored.sort_by(|a, b| b.0.cmp(&a.0));
+        for mv in ordered {
+            // Delta pruning: even winning this material back with a margin
+            // to spare leaves the capture short of alpha
+            let gain = captured_value(board, mv) + if mv.promotion != NONE {
+                PIECE_VALUES[mv.promotion as usize] - PIECE_VALUES[PAWN as usize]
+            } else { 0 };
+            if stand_pat + gain + DELTA_MARGIN < alpha { continue; }
 
-        for (_, mv) in scored {
             let undo = make_move(board, mv);
 
             if is_in_check(board, opposite_color(board.turn)) {
@@ -480,20 +1174,23 @@ impl SearchEngine {
         false
     }
 
-    fn mvv_lva_score(&self, board: &Board, mv: Move) -> i32 {
-        let target = &board.squares[mv.to_sq as usize];
-        let victim_value = if target.count == 0 {
-            100 // en passant
+    /// A capture that gives up more than it takes if the target square is
+    /// defended: a cheap stand-in for static exchange evaluation.
+    fn is_losing_capture(&self, board: &Board, mv: Move) -> bool {
+        let value = |pieces: &[u8]| -> i32 {
+            pieces.iter().map(|&p| PIECE_VALUES[piece_type(p) as usize]).sum()
+        };
+        let from = &board.squares[mv.from_sq as usize];
+        let attacker = if mv.unklik_index >= 0 && (mv.unklik_index as u8) < from.count {
+            value(&from.pieces[mv.unklik_index as usize..=mv.unklik_index as usize])
         } else {
-            let mut v = 0i32;
-            for i in 0..target.count {
-                let p = target.pieces[i as usize];
-                if piece_color(p) != board.turn {
-                    v += PIECE_VALUES[piece_type(p) as usize];
-                }
-            }
-            v
+            value(&from.pieces[..from.count as usize])
         };
+        attacker > captured_value(board, mv) && is_attacked(board, mv.to_sq, opposite_color(board.turn))
+    }
+
+    fn mvv_lva_score(&self, board: &Board, mv: Move) -> i32 {
+        let victim_value = captured_value(board, mv);
 
         let from_stack = &board.squares[mv.from_sq as usize];
         let attacker = if mv.unklik_index >= 0 && (mv.unklik_index as u8) < from_stack.count {
@@ -511,7 +1208,17 @@ impl SearchEngine {
         victim_value * 10 - attacker_value
     }
 
-    fn order_moves(&self, board: &Board, moves: &[Move], depth: usize,
+    /// Quiet-move ordering noise for Lazy SMP helpers: 0-31, fixed per move
+    /// and seed, so helpers explore in a different order; always 0 in the main thread.
+    #[inline]
+    fn ordering_noise(&self, mv: Move) -> i32 {
+        if self.ordering_seed == 0 { return 0; }
+        ((mv.key() as u64 ^ self.ordering_seed).wrapping_mul(0x9E37_79B9_7F4A_7C15) >> 59) as i32
+    }
+
+    /// Sort moves best-first for the search. Ties are broken by `Move::key`,
+    /// so the result does not depend on the order moves were generated in.
+    pub fn order_moves(&self, board: &Board, moves: &[Move], depth: usize,
                    tt_move: Option<Move>, prev_move: Option<Move>) -> Vec<Move> {
         let cm = prev_move.and_then(|pm| self.countermove[pm.from_sq as usize][pm.to_sq as usize]);
 
@@ -519,7 +1226,8 @@ impl SearchEngine {
             let score = if tt_move == Some(mv) {
                 10_000_000
             } else if self.is_capture(board, mv) {
-                1_000_000 + self.mvv_lva_score(board, mv)
+                let score = self.mvv_lva_score(board, mv);
+                if self.is_losing_capture(board, mv) { 600_000 + score } else { 1_000_000 + score }
             } else if depth < MAX_DEPTH && self.killers[depth][0] == Some(mv) {
                 900_000
             } else if depth < MAX_DEPTH && self.killers[depth][1] == Some(mv) {
@@ -527,17 +1235,91 @@ impl SearchEngine {
             } else if cm == Some(mv) {
                 700_000
             } else {
-                self.history[mv.from_sq as usize][mv.to_sq as usize]
+                self.history[mv.from_sq as usize][mv.to_sq as usize] + self.ordering_noise(mv)
             };
             (score, mv)
         }).collect();
 
-        scored.sort_by(|a, b| b.0.cmp(&a.0));
+        scored.sort_by_key(|&(s, m)| (std::cmp::Reverse(s), m.key()));
         scored.into_iter().map(|(_, m)| m).collect()
     }
 }
 
+impl Default for SearchEngine {
+    fn default() -> Self {
+        SearchEngine::new()
+    }
+}
+
+fn print_info(info: &SearchInfo) {
+    let pv: Vec<String> = info.pv.iter().map(|m| m.to_uci()).collect();
+    println!("info depth {} seldepth {} score cp {} nodes {} nps {} time {} pv {}",
+        info.depth, info.seldepth, info.score, info.nodes, info.nps, info.time_ms, pv.join(" "));
+}
+
 pub fn find_best_move(board: &mut Board, depth: u32, time_limit_ms: Option<u64>) -> (Option<Move>, SearchInfo) {
     let mut engine = SearchEngine::new();
     engine.search(board, depth, time_limit_ms)
 }
+
+/// Value of the enemy pieces `mv` captures: a whole stack at once, or the
+/// pawn taken en passant.
+fn captured_value(board: &Board, mv: Move) -> i32 {
+    if mv.move_type == MT_EN_PASSANT { return PIECE_VALUES[PAWN as usize]; }
+    let target = &board.squares[mv.to_sq as usize];
+    target.pieces[..target.count as usize].iter()
+        .filter(|&&p| piece_color(p) != board.turn)
+        .map(|&p| PIECE_VALUES[piece_type(p) as usize])
+        .sum()
+}
+
+/// Whether each move of `pv` is legal where it is played, starting from
+/// `board`, which is left as it was. The first illegal move is printed to
+/// stderr with its ply. Debug builds check every iteration's PV with it.
+pub fn verify_pv(board: &mut Board, pv: &[Move]) -> bool {
+    let mut played = Vec::with_capacity(pv.len());
+    let mut legal = true;
+    for (ply, &mv) in pv.iter().enumerate() {
+        if !generate_moves(board, true, false).contains(&mv) {
+            eprintln!("PV move {} at ply {} is illegal in {}", mv.to_uci(), ply, board.get_fen());
+            legal = false;
+            break;
+        }
+        played.push((mv, make_move(board, mv)));
+    }
+    for (mv, undo) in played.iter().rev() {
+        unmake_move(board, *mv, undo);
+    }
+    legal
+}
+
+/// Deepest iteration `puzzle_difficulty` tries before giving up.
+pub const PUZZLE_MAX_DEPTH: u32 = 10;
+
+/// Rough puzzle difficulty: the first iterative-deepening depth at which
+/// the engine's best move is the solution's first move, or
+/// PUZZLE_MAX_DEPTH + 1 if it never is. Moves are compared exactly, so
+/// klik and unklik solutions must carry the right type and index.
+pub fn puzzle_difficulty(board: &mut Board, solution: &[Move]) -> u32 {
+    let target = match solution.first() {
+        Some(&mv) => mv,
+        None => return 0,
+    };
+
+    let mut engine = SearchEngine::new();
+    let stop = engine.stop_handle();
+    let found = Arc::new(AtomicU32::new(0));
+    let sink = Arc::clone(&found);
+    engine.set_info_callback(Box::new(move |info| {
+        if info.pv.first() == Some(&target) && sink.load(Ordering::Relaxed) == 0 {
+            sink.store(info.depth, Ordering::Relaxed);
+            stop.store(true, Ordering::Relaxed);
+        }
+    }));
+    engine.search(board, PUZZLE_MAX_DEPTH, None);
+
+    match found.load(Ordering::Relaxed) {
+        0 => PUZZLE_MAX_DEPTH + 1,
+        depth => depth,
+    }
+}
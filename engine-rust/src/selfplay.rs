@@ -0,0 +1,156 @@
+//! Klikschaak Engine - Self-play driver for shaking out rules bugs
+//!
+//! Usage: `selfplay [games] [depth] [movetime_ms]`. Each game starts with a
+//! few random plies from the start position so the games differ, then the
+//! engine plays both sides until the game ends or hits the ply limit.
+
+use crate::board::Board;
+use crate::movegen::{
+    generate_moves, has_legal_move, make_move, is_in_check, is_fifty_move_draw, is_insufficient_material,
+};
+use crate::rng::Rng;
+use crate::search::{SearchEngine, compute_zobrist};
+use crate::types::*;
+
+const DEFAULT_GAMES: u32 = 10;
+const DEFAULT_DEPTH: u32 = 4;
+const RANDOM_PLIES: u32 = 4;
+const MAX_PLIES: usize = 400;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum GameResult {
+    WhiteWins,
+    BlackWins,
+    Draw,
+}
+
+impl GameResult {
+    pub fn score(self) -> &'static str {
+        match self {
+            GameResult::WhiteWins => "1-0",
+            GameResult::BlackWins => "0-1",
+            GameResult::Draw => "1/2-1/2",
+        }
+    }
+}
+
+/// A finished self-play game: moves in SAN, the result and why it ended.
+pub struct Game {
+    pub moves: Vec<String>,
+    pub result: GameResult,
+    pub reason: &'static str,
+}
+
+impl Game {
+    /// Numbered move list, `1. e4 e5 2. Nf3 ...`
+    pub fn move_text(&self) -> String {
+        let mut text = String::new();
+        for (i, san) in self.moves.iter().enumerate() {
+            if i % 2 == 0 {
+                if i > 0 { text.push(' '); }
+                text.push_str(&format!("{}. ", i / 2 + 1));
+            } else {
+                text.push(' ');
+            }
+            text.push_str(san);
+        }
+        text
+    }
+}
+
+/// The game is over in `board`: result and reason, checked in the order
+/// mate/stalemate, threefold, fifty moves, insufficient material.
+/// `history` holds the hashes of the earlier positions.
+fn game_over(board: &mut Board, history: &[u64]) -> Option<(GameResult, &'static str)> {
+    if !has_legal_move(board) {
+        if !is_in_check(board, board.turn) {
+            return Some((GameResult::Draw, "stalemate"));
+        }
+        let winner = if board.turn == WHITE { GameResult::BlackWins } else { GameResult::WhiteWins };
+        return Some((winner, "checkmate"));
+    }
+    if history.iter().filter(|&&h| h == board.zobrist_hash).count() >= 2 {
+        return Some((GameResult::Draw, "threefold repetition"));
+    }
+    if is_fifty_move_draw(board) {
+        return Some((GameResult::Draw, "fifty-move rule"));
+    }
+    if is_insufficient_material(board) {
+        return Some((GameResult::Draw, "insufficient material"));
+    }
+    None
+}
+
+/// Play one game. Returns an error describing the position if the engine
+/// picks a move that is not legal or the incremental hash goes wrong.
+pub fn play_game(engine: &mut SearchEngine, seed: u64, depth: u32, movetime_ms: Option<u64>) -> Result<Game, String> {
+    let mut rng = Rng::new(seed);
+    let mut board = Board::startpos();
+    compute_zobrist(&mut board);
+    let mut history: Vec<u64> = Vec::new();
+    let mut moves = Vec::new();
+    engine.new_game();
+
+    while moves.len() < MAX_PLIES {
+        if let Some((result, reason)) = game_over(&mut board, &history) {
+            return Ok(Game { moves, result, reason });
+        }
+
+        let legal = generate_moves(&mut board, true, false);
+        let mv = if (moves.len() as u32) < RANDOM_PLIES {
+            legal[rng.below(legal.len() as u64) as usize]
+        } else {
+            engine.set_game_history(&history);
+            let mut search_board = board.clone();
+            match engine.search_with_limits(&mut search_board, depth, movetime_ms, None).0 {
+                Some(mv) => mv,
+                None => return Err(format!("no move returned in {}", board.get_fen())),
+            }
+        };
+        if !legal.contains(&mv) {
+            return Err(format!("illegal move {} in {} after {}",
+                mv.to_uci_verbose(&board), board.get_fen(), moves.join(" ")));
+        }
+
+        moves.push(mv.to_san(&board));
+        history.push(board.zobrist_hash);
+        make_move(&mut board, mv);
+
+        let mut fresh = board.clone();
+        compute_zobrist(&mut fresh);
+        if fresh.zobrist_hash != board.zobrist_hash {
+            return Err(format!("incremental hash wrong after {} in {}", mv.to_uci(), board.get_fen()));
+        }
+    }
+    Ok(Game { moves, result: GameResult::Draw, reason: "ply limit" })
+}
+
+pub fn run_selfplay(args: &[String]) {
+    let games = args.first().and_then(|v| v.parse().ok()).unwrap_or(DEFAULT_GAMES);
+    let depth = args.get(1).and_then(|v| v.parse().ok()).unwrap_or(DEFAULT_DEPTH);
+    let movetime_ms = args.get(2).and_then(|v| v.parse().ok());
+
+    println!("=== Klikschaak self-play: {} games at depth {}{} ===\n", games, depth,
+        movetime_ms.map_or(String::new(), |ms| format!(", {}ms per move", ms)));
+
+    let mut engine = SearchEngine::new();
+    engine.set_info_callback(Box::new(|_| {}));
+    let mut tally = [0u32; 3]; // white wins, black wins, draws
+
+    for game in 0..games {
+        match play_game(&mut engine, game as u64, depth, movetime_ms) {
+            Ok(g) => {
+                println!("Game {}: {} ({}, {} plies)", game + 1, g.result.score(), g.reason, g.moves.len());
+                println!("  {}\n", g.move_text());
+                tally[g.result as usize] += 1;
+            }
+            Err(e) => {
+                eprintln!("Game {}: {}", game + 1, e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    println!("Finished {} games: +{} -{} ={} (white wins, black wins, draws)",
+        games, tally[0], tally[1], tally[2]);
+}
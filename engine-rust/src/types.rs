@@ -1,4 +1,6 @@
-/// Klikschaak Engine - Type Definitions
+//! Klikschaak Engine - Type Definitions
+
+use serde::Serialize;
 
 // Colors
 pub const WHITE: u8 = 0;
@@ -80,6 +82,14 @@ pub fn square_rank(sq: u8) -> u8 {
     sq >> 3
 }
 
+/// King moves between two squares (Chebyshev distance)
+#[inline(always)]
+pub fn square_distance(a: u8, b: u8) -> i32 {
+    let files = (square_file(a) as i32 - square_file(b) as i32).abs();
+    let ranks = (square_rank(a) as i32 - square_rank(b) as i32).abs();
+    files.max(ranks)
+}
+
 #[inline(always)]
 pub fn make_square(file: u8, rank: u8) -> u8 {
     rank * 8 + file
@@ -117,7 +127,8 @@ pub const MT_CASTLE_K_KLIK: u8 = 8;
 pub const MT_CASTLE_Q_KLIK: u8 = 9;
 pub const MT_PROMOTION: u8 = 10;
 pub const MT_PROMOTION_CAPTURE: u8 = 11;
-pub const MT_PROMOTION_KLIK: u8 = 12;
+// A pawn may not klik onto the promotion rank (see README), so there is no
+// promotion-klik move type. 12 was MT_PROMOTION_KLIK and stays unused.
 
 pub fn move_type_name(mt: u8) -> &'static str {
     match mt {
@@ -133,7 +144,6 @@ pub fn move_type_name(mt: u8) -> &'static str {
         MT_CASTLE_Q_KLIK => "CASTLE_Q_KLIK",
         MT_PROMOTION => "PROMOTION",
         MT_PROMOTION_CAPTURE => "PROMOTION_CAPTURE",
-        MT_PROMOTION_KLIK => "PROMOTION_KLIK",
         _ => "UNKNOWN",
     }
 }
@@ -149,7 +159,7 @@ pub const CR_BLACK: u8 = CR_B_KINGSIDE | CR_B_QUEENSIDE;
 pub const CR_ALL: u8 = CR_WHITE | CR_BLACK;
 
 // Move representation
-#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Serialize)]
 pub struct Move {
     pub from_sq: u8,
     pub to_sq: u8,
@@ -179,6 +189,18 @@ impl Move {
         Move { from_sq, to_sq, move_type, unklik_index, promotion }
     }
 
+    /// Compact integer encoding of all fields; distinct moves get distinct keys
+    #[inline]
+    pub fn key(&self) -> u32 {
+        PackedMove::pack(*self).0
+    }
+
+    /// Inverse of `key`
+    #[inline]
+    pub fn from_key(key: u32) -> Self {
+        PackedMove(key).unpack()
+    }
+
     pub fn to_uci(&self) -> String {
         let mut s = format!("{}{}", square_name(self.from_sq), square_name(self.to_sq));
 
@@ -208,33 +230,80 @@ impl Move {
     }
 }
 
+/// A `Move` in one u32, for the transposition table and other dense storage.
+/// Bits 0-5 from, 6-11 to, 12-15 move type, 16-17 unklik index + 1, 18-20
+/// promotion piece type.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct PackedMove(pub u32);
+
+impl PackedMove {
+    /// Number of bits a packed move uses
+    pub const BITS: u32 = 21;
+
+    #[inline]
+    pub fn pack(mv: Move) -> Self {
+        PackedMove(mv.from_sq as u32
+            | (mv.to_sq as u32) << 6
+            | (mv.move_type as u32) << 12
+            | ((mv.unklik_index + 1) as u32) << 16
+            | (mv.promotion as u32) << 18)
+    }
+
+    #[inline]
+    pub fn unpack(self) -> Move {
+        let bits = self.0;
+        Move {
+            from_sq: (bits & 63) as u8,
+            to_sq: (bits >> 6 & 63) as u8,
+            move_type: (bits >> 12 & 15) as u8,
+            unklik_index: (bits >> 16 & 3) as i8 - 1,
+            promotion: (bits >> 18 & 7) as u8,
+        }
+    }
+}
+
+impl From<Move> for PackedMove {
+    fn from(mv: Move) -> Self {
+        PackedMove::pack(mv)
+    }
+}
+
 impl std::fmt::Display for Move {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "Move({})", self.to_uci())
     }
 }
 
-// Square stack (max 2 pieces)
+/// Most pieces one square can hold. Stacks, klik targets and the Zobrist
+/// keys follow it; the move rules still assume two (an unklik picks one of
+/// the two pieces, a combined move takes the whole stack).
+pub const MAX_STACK: usize = 2;
+
+// Square stack, bottom piece first
 #[derive(Clone, Copy, PartialEq, Eq)]
 pub struct SquareStack {
-    pub pieces: [u8; 2],
+    pub pieces: [u8; MAX_STACK],
     pub count: u8,
 }
 
 impl SquareStack {
     #[inline(always)]
     pub const fn empty() -> Self {
-        SquareStack { pieces: [NO_PIECE, NO_PIECE], count: 0 }
+        SquareStack { pieces: [NO_PIECE; MAX_STACK], count: 0 }
     }
 
     #[inline(always)]
     pub fn single(piece: u8) -> Self {
-        SquareStack { pieces: [piece, NO_PIECE], count: 1 }
+        let mut stack = Self::empty();
+        stack.add(piece);
+        stack
     }
 
     #[inline(always)]
     pub fn double(bottom: u8, top: u8) -> Self {
-        SquareStack { pieces: [bottom, top], count: 2 }
+        let mut stack = Self::single(bottom);
+        stack.add(top);
+        stack
     }
 
     #[inline(always)]
@@ -244,7 +313,13 @@ impl SquareStack {
 
     #[inline(always)]
     pub fn has_stack(&self) -> bool {
-        self.count == 2
+        self.count >= 2
+    }
+
+    /// No piece can klik onto this square.
+    #[inline(always)]
+    pub fn is_full(&self) -> bool {
+        self.count as usize >= MAX_STACK
     }
 
     #[inline(always)]
@@ -261,7 +336,7 @@ impl SquareStack {
 
     #[inline(always)]
     pub fn add(&mut self, piece: u8) {
-        if self.count < 2 {
+        if !self.is_full() {
             self.pieces[self.count as usize] = piece;
             self.count += 1;
         }
@@ -280,33 +355,25 @@ impl SquareStack {
     pub fn remove_at(&mut self, index: u8) -> u8 {
         if index >= self.count { return NO_PIECE; }
         let p = self.pieces[index as usize];
-        if index == 0 && self.count == 2 {
-            // Shift top piece down
-            self.pieces[0] = self.pieces[1];
-            self.pieces[1] = NO_PIECE;
-        } else {
-            self.pieces[index as usize] = NO_PIECE;
-        }
+        // Shift the pieces above it down
+        self.pieces.copy_within(index as usize + 1..self.count as usize, index as usize);
         self.count -= 1;
+        self.pieces[self.count as usize] = NO_PIECE;
         p
     }
 
     #[inline(always)]
     pub fn clear(&mut self) {
-        self.pieces = [NO_PIECE, NO_PIECE];
-        self.count = 0;
+        *self = Self::empty();
     }
 }
 
 impl std::fmt::Debug for SquareStack {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        if self.count == 0 {
-            write!(f, "[]")
-        } else if self.count == 1 {
-            write!(f, "[{}]", piece_char(self.pieces[0]))
-        } else {
-            write!(f, "[{}, {}]", piece_char(self.pieces[0]), piece_char(self.pieces[1]))
-        }
+        let pieces: Vec<String> = self.pieces[..self.count as usize].iter()
+            .map(|&p| piece_char(p).to_string())
+            .collect();
+        write!(f, "[{}]", pieces.join(", "))
     }
 }
 
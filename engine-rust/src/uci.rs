@@ -0,0 +1,185 @@
+//! Klikschaak Engine - UCI protocol driver (stdin/stdout)
+
+use std::io::BufRead;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::JoinHandle;
+
+use crate::board::Board;
+use crate::movegen::make_move;
+use crate::search::{SearchEngine, SearchInfo, compute_zobrist, mate_in, MAX_DEPTH};
+use crate::types::*;
+
+struct UciState {
+    board: Board,
+    // Hashes of the positions before `board`, for repetition detection
+    history: Vec<u64>,
+    // Owned by the search thread while a `go` is running
+    engine: Option<SearchEngine>,
+    worker: Option<JoinHandle<SearchEngine>>,
+    stop: Option<Arc<AtomicBool>>,
+}
+
+impl UciState {
+    fn new() -> Self {
+        let mut board = Board::startpos();
+        compute_zobrist(&mut board);
+        UciState { board, history: Vec::new(), engine: Some(SearchEngine::new()), worker: None, stop: None }
+    }
+
+    /// Block until the running search (if any) has reported its bestmove.
+    fn wait(&mut self) {
+        if let Some(worker) = self.worker.take() {
+            self.engine = Some(worker.join().expect("search thread panicked"));
+        }
+        self.stop = None;
+    }
+
+    fn stop(&mut self) {
+        if let Some(stop) = &self.stop {
+            stop.store(true, Ordering::Relaxed);
+        }
+        self.wait();
+    }
+
+    fn engine(&mut self) -> &mut SearchEngine {
+        self.wait();
+        self.engine.as_mut().expect("engine available after wait")
+    }
+
+    fn position(&mut self, args: &[&str]) {
+        self.wait();
+        let moves_at = args.iter().position(|&t| t == "moves").unwrap_or(args.len());
+        let mut board = match args.first() {
+            Some(&"startpos") => Board::startpos(),
+            Some(&"fen") => Board::from_fen(&args[1..moves_at].join(" ")),
+            _ => {
+                println!("info string expected 'startpos' or 'fen'");
+                return;
+            }
+        };
+        compute_zobrist(&mut board);
+
+        let mut history = Vec::new();
+        for &uci in args.iter().skip(moves_at + 1) {
+            match Move::from_uci(&mut board, uci) {
+                Some(mv) => {
+                    history.push(board.zobrist_hash);
+                    make_move(&mut board, mv);
+                }
+                None => {
+                    println!("info string illegal move {} in {}", uci, board.get_fen());
+                    break;
+                }
+            }
+        }
+        self.board = board;
+        self.history = history;
+    }
+
+    fn go(&mut self, args: &[&str]) {
+        self.wait();
+
+        let mut depth = MAX_DEPTH as u32;
+        let mut movetime: Option<u64> = None;
+        let mut nodes: Option<u64> = None;
+        let mut clock = [None::<u64>; 2];
+        let mut inc = [0u64; 2];
+        let mut movestogo = 30u64;
+
+        let mut i = 0;
+        while i < args.len() {
+            let value = args.get(i + 1).and_then(|v| v.parse::<u64>().ok());
+            match (args[i], value) {
+                ("depth", Some(v)) => depth = (v as u32).clamp(1, MAX_DEPTH as u32),
+                ("movetime", Some(v)) => movetime = Some(v),
+                ("nodes", Some(v)) => nodes = Some(v),
+                ("wtime", Some(v)) => clock[WHITE as usize] = Some(v),
+                ("btime", Some(v)) => clock[BLACK as usize] = Some(v),
+                ("winc", Some(v)) => inc[WHITE as usize] = v,
+                ("binc", Some(v)) => inc[BLACK as usize] = v,
+                ("movestogo", Some(v)) => movestogo = v.max(1),
+                _ => { i += 1; continue; }
+            }
+            i += 2;
+        }
+
+        // Clock-based budget when no explicit movetime is given
+        let side = self.board.turn as usize;
+        if movetime.is_none() {
+            if let Some(remaining) = clock[side] {
+                let budget = remaining / movestogo + inc[side] / 2;
+                movetime = Some(budget.min(remaining.saturating_sub(50)).max(1));
+            }
+        }
+
+        let mut engine = self.engine.take().expect("engine available after wait");
+        let turn = self.board.turn;
+        engine.set_info_callback(Box::new(move |info| print_info(info, turn)));
+        engine.set_game_history(&self.history);
+        self.stop = Some(engine.stop_handle());
+        let mut board = self.board.clone();
+        self.worker = Some(std::thread::spawn(move || {
+            let (best_move, info) = engine.search_with_limits(&mut board, depth, movetime, nodes);
+            match best_move {
+                Some(mv) => match info.pv.get(1) {
+                    Some(ponder) => println!("bestmove {} ponder {}", mv.to_uci(), ponder.to_uci()),
+                    None => println!("bestmove {}", mv.to_uci()),
+                },
+                None => println!("bestmove 0000"),
+            }
+            engine
+        }));
+    }
+}
+
+/// UCI wants the score from the side to move's point of view, in moves to mate when mating.
+fn print_info(info: &SearchInfo, turn: u8) {
+    let score = if turn == WHITE { info.score } else { -info.score };
+    let score_str = match mate_in(score) {
+        Some(moves) => format!("mate {}", moves),
+        None => format!("cp {}", score),
+    };
+    let pv: Vec<String> = info.pv.iter().map(|m| m.to_uci()).collect();
+    println!("info depth {} seldepth {} score {} nodes {} nps {} time {} pv {}",
+        info.depth, info.seldepth, score_str, info.nodes, info.nps, info.time_ms, pv.join(" "));
+}
+
+/// Run the UCI loop until `quit` or end of input. Moves use the engine's
+/// own notation: klik moves end in `k`, unklik moves in `u<idx>`/`U<idx>`.
+pub fn run_uci() {
+    let mut state = UciState::new();
+    let stdin = std::io::stdin();
+
+    for line in stdin.lock().lines() {
+        let Ok(line) = line else { break };
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        let Some((&command, args)) = tokens.split_first() else { continue };
+
+        match command {
+            "uci" => {
+                println!("id name Klikschaak {}", env!("CARGO_PKG_VERSION"));
+                println!("id author TJOffringa");
+                println!("uciok");
+            }
+            "isready" => println!("readyok"),
+            "ucinewgame" => {
+                state.engine().new_game();
+                state.board = Board::startpos();
+                state.history.clear();
+                compute_zobrist(&mut state.board);
+            }
+            "position" => state.position(args),
+            "go" => state.go(args),
+            "stop" => state.stop(),
+            "d" => {
+                state.wait();
+                println!("{}", state.board.display());
+            }
+            "quit" => break,
+            _ => println!("info string unknown command {}", command),
+        }
+    }
+
+    state.stop();
+}